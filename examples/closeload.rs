@@ -0,0 +1,90 @@
+use anyhow::Result;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::{env, net::IpAddr, thread};
+use toytcp::tcp::TCP;
+
+// 多数の接続を一斉にcloseして、全てのクローズが完了するかを確認する負荷テスト
+// echoserverに対して実行する
+// 使い方: closeload <addr> <port> <接続数> [タイムアウト秒]
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let addr: IpAddr = args[1].parse()?;
+    let port: u16 = args[2].parse()?;
+    let count: usize = args[3].parse()?;
+    let timeout = Duration::from_secs(match args.get(4) {
+        Some(timeout) => timeout.parse()?,
+        None => 60,
+    });
+
+    close_load(addr, port, count, timeout)?;
+
+    Ok(())
+}
+
+fn close_load(
+    remote_addr: IpAddr,
+    remote_port: u16,
+    count: usize,
+    timeout: Duration,
+) -> Result<()> {
+    let tcp = TCP::new();
+    let mut sock_ids = Vec::new();
+    for _ in 0..count {
+        sock_ids.push(tcp.connect(remote_addr, remote_port)?);
+    }
+    dbg!("connected", sock_ids.len());
+
+    // 全ての接続を別スレッドから同時にcloseする
+    // closeが返らない接続を検出できるように、完了の通知はチャネルで受け取る
+    let (sender, receiver) = mpsc::channel();
+    let start = Instant::now();
+    for sock_id in sock_ids.iter().copied() {
+        let cloned_tcp = tcp.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let result = cloned_tcp.close(sock_id);
+            sender.send((sock_id, result)).unwrap();
+        });
+    }
+    drop(sender);
+
+    let mut closed = Vec::new();
+    let mut failed = 0;
+    while closed.len() + failed < count {
+        let remain = match timeout.checked_sub(start.elapsed()) {
+            Some(remain) => remain,
+            None => break,
+        };
+        match receiver.recv_timeout(remain) {
+            Ok((sock_id, Ok(()))) => closed.push(sock_id),
+            Ok((sock_id, Err(error))) => {
+                dbg!("failed to close", sock_id, error);
+                failed += 1;
+            }
+            Err(_) => break,
+        }
+    }
+
+    println!(
+        "closed: {}, failed: {}, pending: {}, elapsed: {:?}",
+        closed.len(),
+        failed,
+        count - closed.len() - failed,
+        start.elapsed()
+    );
+    // タイムアウトまでにcloseが返らなかった接続は、クローズの完了を取りこぼしている
+    for sock_id in sock_ids.iter().filter(|sock_id| !closed.contains(sock_id)) {
+        dbg!("not closed", sock_id);
+    }
+
+    if closed.len() != count {
+        anyhow::bail!(
+            "{} of {} connections did not close",
+            count - closed.len(),
+            count
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use std::{env, fs, thread, time::Duration};
+use toytcp::tcp::TCP;
+
+// TCPの作成とdropを繰り返し、バックグラウンドのスレッドが残らないことを確認する
+// スレッド数は/proc/self/taskから数えるので、Linuxでのみ動作する
+// 使い方: dropload [回数]
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let count: usize = match args.get(1) {
+        Some(count) => count.parse()?,
+        None => 10,
+    };
+
+    drop_load(count)?;
+
+    Ok(())
+}
+
+fn drop_load(count: usize) -> Result<()> {
+    let before = thread_count()?;
+    for _ in 0..count {
+        let tcp = TCP::new();
+        // 受信スレッドがTCPを参照している最中にdropされる場合も試す
+        thread::sleep(Duration::from_millis(50));
+        drop(tcp);
+    }
+
+    // 最後の参照を受信スレッドやタイマスレッドが手放した場合、そのスレッドは少し遅れて終わる
+    thread::sleep(Duration::from_millis(500));
+    let after = thread_count()?;
+    println!("threads before: {}, after: {}", before, after);
+
+    if after > before {
+        anyhow::bail!("{} threads leaked", after - before);
+    }
+
+    Ok(())
+}
+
+fn thread_count() -> Result<usize> {
+    Ok(fs::read_dir("/proc/self/task")?.count())
+}
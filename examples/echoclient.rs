@@ -1,18 +1,19 @@
 use anyhow::Result;
-use std::{env, io, net::Ipv4Addr, str};
+use std::io::{self, Write};
+use std::{env, net::IpAddr};
 use toytcp::tcp::TCP;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
-    let addr: Ipv4Addr = args[1].parse()?;
+    let addr: IpAddr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
-    
+
     echo_client(addr, port)?;
 
     Ok(())
 }
 
-fn echo_client(remote_addr: Ipv4Addr, remote_port: u16) -> Result<()> {
+fn echo_client(remote_addr: IpAddr, remote_port: u16) -> Result<()> {
     let tcp = TCP::new();
     let sock_id = tcp.connect(remote_addr, remote_port)?;
 
@@ -22,14 +23,39 @@ fn echo_client(remote_addr: Ipv4Addr, remote_port: u16) -> Result<()> {
         std::process::exit(0);
     })?;
 
+    // 送信と受信を別スレッドで同時に行い、同じ接続を全二重で使う
+    // 入力を待たずに、サーバから返ってきたデータを随時表示する
+    let cloned_tcp = tcp.clone();
+    std::thread::spawn(move || {
+        // 1回のrecvで受け取れるのが文字の途中までのこともあるので、文字列にせずそのまま書き出す
+        let mut buffer = vec![0; 1500];
+        let mut stdout = io::stdout();
+        loop {
+            let n = match cloned_tcp.recv(sock_id, &mut buffer) {
+                Ok(0) => {
+                    println!("connection closed by server");
+                    std::process::exit(0);
+                }
+                Ok(n) => n,
+                Err(error) => {
+                    eprintln!("connection lost: {}", error);
+                    std::process::exit(1);
+                }
+            };
+            if stdout
+                .write_all(&buffer[..n])
+                .and_then(|_| stdout.flush())
+                .is_err()
+            {
+                std::process::exit(1);
+            }
+        }
+    });
+
     loop {
-        let mut input= String::new();
+        let mut input = String::new();
         io::stdin().read_line(&mut input)?;
 
         tcp.send(sock_id, input.as_bytes())?;
-
-        let mut buffer = vec![0; 1500];
-        let n = tcp.recv(sock_id, &mut buffer)?;
-        print!("> {}", str::from_utf8(&buffer[..n])?);
-    } 
-}
\ No newline at end of file
+    }
+}
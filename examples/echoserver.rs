@@ -1,17 +1,17 @@
 use anyhow::Result;
-use std::{env, net::Ipv4Addr, str};
+use std::{env, net::IpAddr, str};
 use toytcp::tcp::TCP;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
-    let addr: Ipv4Addr = args[1].parse()?;
+    let addr: IpAddr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
     echo_server(addr, port)?;
 
     Ok(())
 }
 
-fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
+fn echo_server(local_addr: IpAddr, local_port: u16) -> Result<()> {
     let tcp = TCP::new();
     let listening_socket = tcp.listen(local_addr, local_port)?;
     dbg!("listening...");
@@ -31,8 +31,10 @@ fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
                 }
 
                 print!("> {}", str::from_utf8(&buffer[..nbytes]).unwrap());
-                cloned_tcp.send(connected_socket, &buffer[..nbytes]).unwrap();
+                cloned_tcp
+                    .send(connected_socket, &buffer[..nbytes])
+                    .unwrap();
             }
         });
     }
-}
\ No newline at end of file
+}
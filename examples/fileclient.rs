@@ -1,10 +1,10 @@
 use anyhow::Result;
-use std::{env, fs, net::Ipv4Addr, str};
+use std::{env, fs, net::IpAddr, str};
 use toytcp::tcp::TCP;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
-    let addr: Ipv4Addr = args[1].parse()?;
+    let addr: IpAddr = args[1].parse()?;
     let port: u16 = args[2].parse()?;
     let filepath: &str = &args[3];
 
@@ -13,7 +13,7 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn file_client(addr: Ipv4Addr, port: u16, filepath: &str) -> Result<()> {
+fn file_client(addr: IpAddr, port: u16, filepath: &str) -> Result<()> {
     let tcp = TCP::new();
     let sock_id = tcp.connect(addr, port)?;
     let cloned_tcp = tcp.clone();
@@ -0,0 +1,61 @@
+use anyhow::Result;
+use std::{env, net::IpAddr, thread};
+use toytcp::tcp::TCP;
+
+// サーバ役とクライアント役のTCPインスタンスを同じプロセスで動かし、echoが通ることを確認する
+// addrには自ホストの(ループバック以外の)アドレスを指定する
+// 使い方: selfecho <addr> <port>
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let addr: IpAddr = args[1].parse()?;
+    let port: u16 = args[2].parse()?;
+
+    self_echo(addr, port)?;
+
+    Ok(())
+}
+
+fn self_echo(addr: IpAddr, port: u16) -> Result<()> {
+    let server = TCP::new();
+    let client = TCP::new();
+
+    let listening_socket = server.listen(addr, port)?;
+    let cloned_server = server.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        let connected_socket = cloned_server.accept(listening_socket)?;
+        let mut buffer = [0; 1024];
+        loop {
+            let nbytes = cloned_server.recv(connected_socket, &mut buffer)?;
+            if nbytes == 0 {
+                return cloned_server.close(connected_socket);
+            }
+            cloned_server.send(connected_socket, &buffer[..nbytes])?;
+        }
+    });
+
+    let sock_id = client.connect(addr, port)?;
+    let message = b"hello from the other instance";
+    client.send(sock_id, message)?;
+    let mut buffer = Vec::new();
+    while buffer.len() < message.len() {
+        let received = client.recv_all(sock_id)?;
+        if received.is_empty() {
+            break;
+        }
+        buffer.extend_from_slice(&received);
+    }
+    client.close(sock_id)?;
+
+    handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("server thread panicked"))??;
+    if buffer != message {
+        anyhow::bail!("echo mismatch: {:?}", buffer);
+    }
+    println!("echo succeeded");
+
+    client.shutdown()?;
+    server.shutdown()?;
+
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use crate::packet::TCP_HEADER_SIZE;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+const ICMP_HEADER_SIZE: usize = 8;
+const ICMP_DESTINATION_UNREACHABLE: u8 = 3;
+const ICMP_FRAGMENTATION_NEEDED: u8 = 4;
+const ICMPV6_PACKET_TOO_BIG: u8 = 2;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const PROTOCOL_TCP: u8 = 6;
+
+// DFを立てたセグメントが経路のMTUを超えたため、ルータが転送できずに破棄したという通知
+// アドレスとポートは破棄された(自分が送った)セグメントのもの
+#[derive(Debug, Clone, PartialEq)]
+pub struct TooBig {
+    pub local_addr: IpAddr,
+    pub remote_addr: IpAddr,
+    pub local_port: u16,
+    pub remote_port: u16,
+    pub seq: u32,
+    // 次のホップのMTU、RFC1191より前のルータは通知してこないので0になる
+    pub mtu: usize,
+}
+
+impl TooBig {
+    // 通知されたMTUに収まるMSS、MTUが通知されていなければNone
+    pub fn mss(&self) -> Option<usize> {
+        let ip_header_size = match self.local_addr {
+            IpAddr::V4(_) => IPV4_HEADER_SIZE,
+            IpAddr::V6(_) => IPV6_HEADER_SIZE,
+        };
+        self.mtu
+            .checked_sub(ip_header_size + TCP_HEADER_SIZE)
+            .filter(|mss| *mss > 0)
+    }
+}
+
+// IPv4のICMP(IPヘッダを除く)から、TCPセグメントに対するFragmentation Neededを取り出す(RFC1191)
+pub fn parse_v4(data: &[u8]) -> Option<TooBig> {
+    if data.len() < ICMP_HEADER_SIZE
+        || data[0] != ICMP_DESTINATION_UNREACHABLE
+        || data[1] != ICMP_FRAGMENTATION_NEEDED
+    {
+        return None;
+    }
+    let mtu = u16::from_be_bytes([data[6], data[7]]) as usize;
+
+    // ICMPの後ろには破棄されたパケットのIPヘッダと、ペイロードの先頭8バイトが続く
+    let ip = &data[ICMP_HEADER_SIZE..];
+    if ip.len() < IPV4_HEADER_SIZE || ip[0] >> 4 != 4 || ip[9] != PROTOCOL_TCP {
+        return None;
+    }
+    let header_len = (ip[0] & 0x0f) as usize * 4;
+    let local_addr = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let remote_addr = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+
+    parse_tcp(
+        local_addr.into(),
+        remote_addr.into(),
+        ip.get(header_len..)?,
+        mtu,
+    )
+}
+
+// ICMPv6から、TCPセグメントに対するPacket Too Bigを取り出す(RFC8201)
+// 破棄されたパケットに拡張ヘッダが付いている場合は扱わない
+pub fn parse_v6(data: &[u8]) -> Option<TooBig> {
+    if data.len() < ICMP_HEADER_SIZE || data[0] != ICMPV6_PACKET_TOO_BIG || data[1] != 0 {
+        return None;
+    }
+    let mtu = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+
+    let ip = &data[ICMP_HEADER_SIZE..];
+    if ip.len() < IPV6_HEADER_SIZE || ip[0] >> 4 != 6 || ip[6] != PROTOCOL_TCP {
+        return None;
+    }
+    let local_addr: [u8; 16] = ip[8..24].try_into().ok()?;
+    let remote_addr: [u8; 16] = ip[24..40].try_into().ok()?;
+
+    parse_tcp(
+        Ipv6Addr::from(local_addr).into(),
+        Ipv6Addr::from(remote_addr).into(),
+        &ip[IPV6_HEADER_SIZE..],
+        mtu,
+    )
+}
+
+// 破棄されたTCPセグメントのポートとseqを取り出す
+// ICMPに含まれるのは先頭の8バイトだけのことがあるので、それ以降は読まない
+fn parse_tcp(local_addr: IpAddr, remote_addr: IpAddr, tcp: &[u8], mtu: usize) -> Option<TooBig> {
+    if tcp.len() < 8 {
+        return None;
+    }
+
+    Some(TooBig {
+        local_addr,
+        remote_addr,
+        local_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+        remote_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+        seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        mtu,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 192.0.2.1:12345から192.0.2.2:80へ送ったseq=1のセグメントに対するFragmentation Needed
+    fn fragmentation_needed(mtu: u16) -> Vec<u8> {
+        let mut data = vec![
+            ICMP_DESTINATION_UNREACHABLE,
+            ICMP_FRAGMENTATION_NEEDED,
+            0,
+            0,
+            0,
+            0,
+        ];
+        data.extend_from_slice(&mtu.to_be_bytes());
+        data.extend_from_slice(&[
+            0x45,
+            0,
+            0,
+            60,
+            0,
+            0,
+            0x40,
+            0,
+            64,
+            PROTOCOL_TCP,
+            0,
+            0,
+            192,
+            0,
+            2,
+            1,
+            192,
+            0,
+            2,
+            2,
+        ]);
+        data.extend_from_slice(&[0x30, 0x39, 0, 80, 0, 0, 0, 1]);
+        data
+    }
+
+    // 2001:db8::1:12345から2001:db8::2:80へ送ったseq=1のセグメントに対するPacket Too Big
+    fn packet_too_big(mtu: u32) -> Vec<u8> {
+        let mut data = vec![ICMPV6_PACKET_TOO_BIG, 0, 0, 0];
+        data.extend_from_slice(&mtu.to_be_bytes());
+        data.extend_from_slice(&[0x60, 0, 0, 0, 0, 20, PROTOCOL_TCP, 64]);
+        data.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&[0x30, 0x39, 0, 80, 0, 0, 0, 1]);
+        data
+    }
+
+    #[test]
+    fn parses_fragmentation_needed() {
+        let too_big = parse_v4(&fragmentation_needed(1400)).unwrap();
+        assert_eq!(
+            too_big,
+            TooBig {
+                local_addr: Ipv4Addr::new(192, 0, 2, 1).into(),
+                remote_addr: Ipv4Addr::new(192, 0, 2, 2).into(),
+                local_port: 12345,
+                remote_port: 80,
+                seq: 1,
+                mtu: 1400,
+            }
+        );
+        assert_eq!(too_big.mss(), Some(1400 - 40));
+    }
+
+    #[test]
+    fn inner_ipv4_options_are_skipped() {
+        let mut data = fragmentation_needed(1400);
+        // IHLを6にして、IPヘッダの後ろに4バイトのオプションを挟む
+        data[ICMP_HEADER_SIZE] = 0x46;
+        let tcp_offset = ICMP_HEADER_SIZE + IPV4_HEADER_SIZE;
+        data.splice(tcp_offset..tcp_offset, [1, 1, 1, 0]);
+        assert_eq!(parse_v4(&data).unwrap().local_port, 12345);
+    }
+
+    #[test]
+    fn mtu_zero_has_no_mss() {
+        // RFC1191より前のルータはMTUを通知せず0になる
+        let too_big = parse_v4(&fragmentation_needed(0)).unwrap();
+        assert_eq!(too_big.mtu, 0);
+        assert_eq!(too_big.mss(), None);
+
+        // ヘッダしか収まらないMTUでもMSSは求められない
+        assert_eq!(parse_v4(&fragmentation_needed(40)).unwrap().mss(), None);
+    }
+
+    #[test]
+    fn truncated_or_unrelated_icmp_is_ignored() {
+        let data = fragmentation_needed(1400);
+        // 破棄されたパケットのIPヘッダが途中で切れている
+        assert_eq!(parse_v4(&data[..ICMP_HEADER_SIZE + 12]), None);
+        // TCPの先頭8バイトが揃っていない
+        assert_eq!(parse_v4(&data[..data.len() - 1]), None);
+
+        // Fragmentation Needed以外のDestination Unreachable
+        let mut port_unreachable = data.clone();
+        port_unreachable[1] = 3;
+        assert_eq!(parse_v4(&port_unreachable), None);
+
+        // TCP以外のパケットに対する通知
+        let mut udp = data;
+        udp[ICMP_HEADER_SIZE + 9] = 17;
+        assert_eq!(parse_v4(&udp), None);
+    }
+
+    #[test]
+    fn parses_packet_too_big() {
+        let too_big = parse_v6(&packet_too_big(1280)).unwrap();
+        assert_eq!(
+            too_big,
+            TooBig {
+                local_addr: "2001:db8::1".parse().unwrap(),
+                remote_addr: "2001:db8::2".parse().unwrap(),
+                local_port: 12345,
+                remote_port: 80,
+                seq: 1,
+                mtu: 1280,
+            }
+        );
+        // IPv6のヘッダは40バイトなので、同じMTUでもIPv4よりMSSが20バイト小さい
+        assert_eq!(too_big.mss(), Some(1280 - 60));
+
+        let data = packet_too_big(1280);
+        assert_eq!(parse_v6(&data[..ICMP_HEADER_SIZE + 30]), None);
+        // IPv4のICMPとして読んでも取り出さない
+        assert_eq!(parse_v4(&data), None);
+    }
+}
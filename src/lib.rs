@@ -1,4 +1,11 @@
+mod icmp;
 mod packet;
+mod ring_buffer;
+mod seq;
+mod snapshot;
 mod socket;
 pub mod tcp;
 mod tcpflags;
+#[cfg(test)]
+mod test_util;
+mod timer_wheel;
@@ -1,21 +1,94 @@
 use crate::tcpflags;
+use anyhow::Result;
+use log::debug;
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
 use pnet::util;
 
 use std::fmt::{self, Debug};
-use std::net::Ipv4Addr;
-const TCP_HEADER_SIZE: usize = 20;
+use std::net::IpAddr;
+pub const TCP_HEADER_SIZE: usize = 20;
+// data offsetは4bitの32bitワード数なので、オプションを含めたヘッダは最大60バイト
+const MAX_HEADER_SIZE: usize = 60;
+
+const OPTION_KIND_END_OF_OPTIONS: u8 = 0;
+const OPTION_KIND_NOP: u8 = 1;
+const OPTION_KIND_MSS: u8 = 2;
+const OPTION_KIND_WINDOW_SCALE: u8 = 3;
+const OPTION_KIND_SACK_PERMITTED: u8 = 4;
+const OPTION_KIND_SACK: u8 = 5;
+const OPTION_KIND_TIMESTAMP: u8 = 8;
 
 #[derive(Clone)]
 pub struct TCPPacket {
     buffer: Vec<u8>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum TcpOption {
+    EndOfOptions,
+    Nop,
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    Timestamp { tsval: u32, tsecr: u32 },
+    Sack(Vec<(u32, u32)>),
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+// ヘッダのオプション部分を走査するイテレータ
+// EOLを返したらそれ以降はパディングなので走査しない
+// 長さが不正なオプションを見つけたら、それ以降は走査しない
+pub struct TcpOptionsIter<'a> {
+    data: &'a [u8],
+}
+
 impl TCPPacket {
-    pub fn new(payload_len: usize) -> Self {
-        Self {
-            buffer: vec![0; TCP_HEADER_SIZE + payload_len],
+    // オプション付きのパケットを確保する
+    // オプションは4バイト境界までEOLで埋め、data offsetをオプションの後ろに設定する
+    pub fn with_options(options: &[TcpOption], payload_len: usize) -> Result<Self> {
+        let mut header = vec![0; TCP_HEADER_SIZE];
+        for option in options {
+            option.write(&mut header)?;
         }
+        // EOL(0)で埋める
+        header.resize(header.len().div_ceil(4) * 4, OPTION_KIND_END_OF_OPTIONS);
+        if header.len() > MAX_HEADER_SIZE {
+            anyhow::bail!(
+                "too long tcp options: {} bytes",
+                header.len() - TCP_HEADER_SIZE
+            );
+        }
+
+        let offset = header.len();
+        header.resize(offset + payload_len, 0);
+        let mut packet = Self { buffer: header };
+        packet.set_data_offset((offset / 4) as u32);
+        Ok(packet)
+    }
+
+    // オプションを載せたときの、パディングを含めたヘッダの長さ
+    pub fn header_len(options: &[TcpOption]) -> Result<usize> {
+        Ok(Self::with_options(options, 0)?.get_data_offset() as usize)
+    }
+
+    // ヘッダ長に満たないパケットや、data offsetがパケット長を超えるパケットは
+    // getterやpayload()でパニックするので受け付けない
+    pub fn from_bytes(buffer: Vec<u8>) -> Result<Self> {
+        if buffer.len() < TCP_HEADER_SIZE {
+            anyhow::bail!("too short tcp packet: {} bytes", buffer.len());
+        }
+
+        let packet = Self { buffer };
+        let offset = packet.get_data_offset() as usize;
+        if offset < TCP_HEADER_SIZE || packet.buffer.len() < offset {
+            anyhow::bail!(
+                "invalid data offset: {} (packet length: {})",
+                offset,
+                packet.buffer.len()
+            );
+        }
+
+        Ok(packet)
     }
 
     pub fn set_src(&mut self, port: u16) {
@@ -50,8 +123,21 @@ impl TCPPacket {
         self.buffer[16..18].copy_from_slice(&checksum.to_be_bytes());
     }
 
-    pub fn set_payload(&mut self, payload: &[u8]) {
-        self.buffer[TCP_HEADER_SIZE..(TCP_HEADER_SIZE + payload.len())].copy_from_slice(payload);
+    // payloadはヘッダの直後からパケットの末尾までにちょうど収まる必要がある
+    // 確保したバッファと長さが食い違う場合は、パニックや尻切れを避けるためにエラーにする
+    pub fn set_payload(&mut self, payload: &[u8]) -> Result<()> {
+        let offset = self.get_data_offset() as usize;
+        if offset > self.buffer.len() || self.buffer.len() - offset != payload.len() {
+            anyhow::bail!(
+                "payload length mismatch: {} (buffer length: {}, data offset: {})",
+                payload.len(),
+                self.buffer.len(),
+                offset
+            );
+        }
+
+        self.buffer[offset..].copy_from_slice(payload);
+        Ok(())
     }
 
     pub fn get_src(&self) -> u16 {
@@ -92,16 +178,54 @@ impl TCPPacket {
         u16::from_be_bytes([self.buffer[16], self.buffer[17]])
     }
 
-    pub fn is_correct_checksum(&self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> bool {
-        self.get_checksum()
-            == util::ipv4_checksum(
-                &self.packet(),
+    // 擬似ヘッダ(送信元・宛先アドレス、プロトコル番号、TCP長)を含めたチェックサムを計算する
+    // 8はチェックサムフィールド(16..18バイト目)の16bitワード単位の位置で、計算時はこのワードを飛ばす
+    // IPv6の擬似ヘッダはアドレスが128bitで、TCP長も32bitになる(RFC8200 8.1)
+    pub fn compute_checksum(&self, src_addr: IpAddr, dst_addr: IpAddr) -> u16 {
+        match (src_addr, dst_addr) {
+            (IpAddr::V4(src_addr), IpAddr::V4(dst_addr)) => util::ipv4_checksum(
+                self.packet(),
+                8,
+                &[],
+                &src_addr,
+                &dst_addr,
+                IpNextHeaderProtocols::Tcp,
+            ),
+            (IpAddr::V6(src_addr), IpAddr::V6(dst_addr)) => util::ipv6_checksum(
+                self.packet(),
                 8,
                 &[],
-                &local_addr,
-                &remote_addr,
+                &src_addr,
+                &dst_addr,
                 IpNextHeaderProtocols::Tcp,
-            )
+            ),
+            _ => panic!(
+                "address family mismatch: src={}, dst={}",
+                src_addr, dst_addr
+            ),
+        }
+    }
+
+    pub fn is_correct_checksum(&self, local_addr: IpAddr, remote_addr: IpAddr) -> bool {
+        if local_addr.is_ipv4() != remote_addr.is_ipv4() {
+            return false;
+        }
+        self.get_checksum() == self.compute_checksum(local_addr, remote_addr)
+    }
+
+    pub fn options(&self) -> TcpOptionsIter<'_> {
+        let offset = self.get_data_offset() as usize;
+        TcpOptionsIter {
+            data: &self.buffer[TCP_HEADER_SIZE..offset],
+        }
+    }
+
+    // タイムスタンプオプションの(TSval, TSecr)
+    pub fn get_timestamp(&self) -> Option<(u32, u32)> {
+        self.options().find_map(|option| match option {
+            TcpOption::Timestamp { tsval, tsecr } => Some((tsval, tsecr)),
+            _ => None,
+        })
     }
 
     pub fn get_data_offset(&self) -> u32 {
@@ -122,6 +246,115 @@ impl Packet for TCPPacket {
     }
 }
 
+impl<'a> Iterator for TcpOptionsIter<'a> {
+    type Item = TcpOption;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&kind, rest) = self.data.split_first()?;
+        match kind {
+            OPTION_KIND_END_OF_OPTIONS => {
+                self.data = &[];
+                Some(TcpOption::EndOfOptions)
+            }
+            // NOPはオプションを4バイト境界に揃えるための1バイトのパディング
+            OPTION_KIND_NOP => {
+                self.data = rest;
+                Some(TcpOption::Nop)
+            }
+            _ => {
+                // 長さはkindとlengthの2バイトを含むので、2未満だと走査が進まず無限ループになる
+                let len = rest.first().map_or(0, |&len| len as usize);
+                if len < 2 || self.data.len() < len {
+                    debug!("invalid tcp option length: kind={}, len={}", kind, len);
+                    self.data = &[];
+                    return None;
+                }
+
+                let data = &self.data[2..len];
+                self.data = &self.data[len..];
+                Some(TcpOption::parse(kind, data))
+            }
+        }
+    }
+}
+
+impl TcpOption {
+    // kindとlengthを含めたオプションのバイト列を書き込む
+    // lengthは1バイトなので、ヘッダに収まらない長さのオプションは書き込む前にエラーにする
+    fn write(&self, buffer: &mut Vec<u8>) -> Result<()> {
+        let (kind, data) = match self {
+            // EOLとNOPはlengthを持たない1バイトのオプション
+            TcpOption::EndOfOptions => {
+                buffer.push(OPTION_KIND_END_OF_OPTIONS);
+                return Ok(());
+            }
+            TcpOption::Nop => {
+                buffer.push(OPTION_KIND_NOP);
+                return Ok(());
+            }
+            TcpOption::Mss(mss) => (OPTION_KIND_MSS, mss.to_be_bytes().to_vec()),
+            TcpOption::WindowScale(shift) => (OPTION_KIND_WINDOW_SCALE, vec![*shift]),
+            TcpOption::SackPermitted => (OPTION_KIND_SACK_PERMITTED, Vec::new()),
+            TcpOption::Timestamp { tsval, tsecr } => {
+                let mut data = tsval.to_be_bytes().to_vec();
+                data.extend_from_slice(&tsecr.to_be_bytes());
+                (OPTION_KIND_TIMESTAMP, data)
+            }
+            TcpOption::Sack(blocks) => {
+                let mut data = Vec::with_capacity(blocks.len() * 8);
+                for (left, right) in blocks {
+                    data.extend_from_slice(&left.to_be_bytes());
+                    data.extend_from_slice(&right.to_be_bytes());
+                }
+                (OPTION_KIND_SACK, data)
+            }
+            TcpOption::Unknown { kind, data } => (*kind, data.clone()),
+        };
+
+        // EOLとNOPをUnknownとして書くと、lengthの位置から後続のオプションとして解釈されてしまう
+        if kind == OPTION_KIND_END_OF_OPTIONS || kind == OPTION_KIND_NOP {
+            anyhow::bail!("invalid tcp option kind for unknown option: {}", kind);
+        }
+        let len = data.len() + 2;
+        if len > MAX_HEADER_SIZE - TCP_HEADER_SIZE {
+            anyhow::bail!("too long tcp option: kind {}, {} bytes", kind, len);
+        }
+
+        buffer.push(kind);
+        buffer.push(len as u8);
+        buffer.extend_from_slice(&data);
+        Ok(())
+    }
+
+    // kindとlengthを除いたオプションの中身を解釈する
+    // 想定した長さと異なるものはUnknownとして扱う
+    fn parse(kind: u8, data: &[u8]) -> Self {
+        match (kind, data.len()) {
+            (OPTION_KIND_MSS, 2) => TcpOption::Mss(u16::from_be_bytes([data[0], data[1]])),
+            (OPTION_KIND_WINDOW_SCALE, 1) => TcpOption::WindowScale(data[0]),
+            (OPTION_KIND_SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (OPTION_KIND_TIMESTAMP, 8) => TcpOption::Timestamp {
+                tsval: u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+                tsecr: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            },
+            (OPTION_KIND_SACK, len) if len > 0 && len % 8 == 0 => TcpOption::Sack(
+                data.chunks(8)
+                    .map(|block| {
+                        (
+                            u32::from_be_bytes([block[0], block[1], block[2], block[3]]),
+                            u32::from_be_bytes([block[4], block[5], block[6], block[7]]),
+                        )
+                    })
+                    .collect(),
+            ),
+            _ => TcpOption::Unknown {
+                kind,
+                data: data.to_vec(),
+            },
+        }
+    }
+}
+
 impl Debug for TCPPacket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -130,19 +363,175 @@ impl Debug for TCPPacket {
             src: {}
             dst: {}
             flag: {}
+            options: {:?}
             payload_len: {}",
             self.get_src(),
             self.get_dst(),
             tcpflags::flag_to_string(self.get_flag()),
+            self.options()
+                .filter(|option| !matches!(option, TcpOption::EndOfOptions | TcpOption::Nop))
+                .collect::<Vec<_>>(),
             self.payload().len(),
         )
     }
 }
 
-impl<'a> From<TcpPacket<'a>> for TCPPacket {
-    fn from(packet: TcpPacket<'a>) -> Self {
-        Self {
-            buffer: packet.packet().to_vec(),
+impl<'a> TryFrom<TcpPacket<'a>> for TCPPacket {
+    type Error = anyhow::Error;
+
+    fn try_from(packet: TcpPacket<'a>) -> Result<Self> {
+        Self::from_bytes(packet.packet().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    // 送信元ポート12345、宛先ポート80、seq=1のSYNに3バイトのペイロードを載せたセグメント
+    // ペイロードが奇数長なので、末尾を0で埋めて計算できているかも確かめる
+    fn syn_with_payload() -> TCPPacket {
+        let mut packet = TCPPacket::with_options(&[], 3).unwrap();
+        packet.set_src(12345);
+        packet.set_dst(80);
+        packet.set_seq(1);
+        packet.set_flag(tcpflags::SYN);
+        packet.set_window_size(1024);
+        packet.set_payload(b"abc").unwrap();
+        packet
+    }
+
+    // オプション部分をバイト列で直接指定したパケット
+    fn with_raw_options(options: &[u8]) -> TCPPacket {
+        assert_eq!(options.len() % 4, 0);
+        let mut buffer = vec![0; TCP_HEADER_SIZE];
+        buffer.extend_from_slice(options);
+        buffer[12] = ((buffer.len() / 4) << 4) as u8;
+        TCPPacket::from_bytes(buffer).unwrap()
+    }
+
+    #[test]
+    fn with_options_round_trips_and_pads_with_eol() {
+        let options = [
+            TcpOption::Mss(1460),
+            TcpOption::WindowScale(7),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamp {
+                tsval: 0x01020304,
+                tsecr: 0x05060708,
+            },
+        ];
+        let mut packet = TCPPacket::with_options(&options, 3).unwrap();
+        packet.set_payload(b"abc").unwrap();
+
+        // オプションは4+3+2+10=19バイトなので、EOL1バイトで20バイトに揃える
+        assert_eq!(packet.get_data_offset(), 40);
+        assert_eq!(packet.packet()[39], OPTION_KIND_END_OF_OPTIONS);
+        assert_eq!(packet.payload(), b"abc");
+
+        let parsed = TCPPacket::from_bytes(packet.packet().to_vec()).unwrap();
+        let mut expected = options.to_vec();
+        expected.push(TcpOption::EndOfOptions);
+        assert_eq!(parsed.options().collect::<Vec<_>>(), expected);
+        assert_eq!(parsed.get_timestamp(), Some((0x01020304, 0x05060708)));
+    }
+
+    #[test]
+    fn with_options_pads_to_a_whole_word() {
+        // MSS(4)+WS(3)+SACK許可(2)=9バイトなので、EOLを3バイト足して12バイトにする
+        let options = [
+            TcpOption::Mss(536),
+            TcpOption::WindowScale(0),
+            TcpOption::SackPermitted,
+        ];
+        let packet = TCPPacket::with_options(&options, 0).unwrap();
+        assert_eq!(packet.get_data_offset() as usize, TCP_HEADER_SIZE + 12);
+        assert_eq!(&packet.packet()[TCP_HEADER_SIZE + 9..], [0, 0, 0]);
+        assert_eq!(
+            TCPPacket::header_len(&options).unwrap(),
+            TCP_HEADER_SIZE + 12
+        );
+    }
+
+    #[test]
+    fn options_iter_skips_nop_and_stops_at_eol() {
+        let packet = with_raw_options(&[
+            1, 1, 2, 4, 0x05, 0xb4, 1, 3, 3, 7, 0, 2, 4, 0x02, 0x18, 0, 0, 0, 0, 0,
+        ]);
+        // EOLより後ろはパディングなので、オプションのように見えても解釈しない
+        assert_eq!(
+            packet.options().collect::<Vec<_>>(),
+            [
+                TcpOption::Nop,
+                TcpOption::Nop,
+                TcpOption::Mss(1460),
+                TcpOption::Nop,
+                TcpOption::WindowScale(7),
+                TcpOption::EndOfOptions,
+            ]
+        );
+    }
+
+    #[test]
+    fn options_iter_stops_at_truncated_option() {
+        // タイムスタンプの長さ10に対して、残りは5バイトしかない
+        let packet = with_raw_options(&[1, 1, 1, 8, 10, 0, 0, 0]);
+        assert_eq!(
+            packet.options().collect::<Vec<_>>(),
+            [TcpOption::Nop, TcpOption::Nop, TcpOption::Nop]
+        );
+        assert_eq!(packet.get_timestamp(), None);
+    }
+
+    #[test]
+    fn options_iter_stops_at_option_shorter_than_its_header() {
+        // 長さ0や1のオプションは走査が進まないので、そこで打ち切る
+        for len in [0, 1] {
+            let packet = with_raw_options(&[1, 5, len, 2, 4, 0x05, 0xb4, 0]);
+            assert_eq!(packet.options().collect::<Vec<_>>(), [TcpOption::Nop]);
+        }
+    }
+
+    #[test]
+    fn checksum_matches_hand_computed_ipv4_vector() {
+        let src = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let dst = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut packet = syn_with_payload();
+        // 擬似ヘッダ c000 0201 c000 0202 0006 0017 とセグメントの16bitワードの1の補数和の補数
+        assert_eq!(packet.compute_checksum(src, dst), 0x32ef);
+
+        // 送信元と宛先を入れ替えても和は変わらないので、受信側からも同じ値で検証できる
+        packet.set_checksum(packet.compute_checksum(src, dst));
+        assert!(packet.is_correct_checksum(dst, src));
+    }
+
+    #[test]
+    fn checksum_matches_hand_computed_ipv6_vector() {
+        let src = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let dst = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2));
+        let mut packet = syn_with_payload();
+        // 擬似ヘッダは128bitのアドレス2つ、32bitのTCP長(0x17)、3バイトの0と次ヘッダ(6)
+        assert_eq!(packet.compute_checksum(src, dst), 0x5b7e);
+
+        packet.set_checksum(packet.compute_checksum(src, dst));
+        assert!(packet.is_correct_checksum(dst, src));
+        // アドレスファミリが混ざった組み合わせは検証に通さない
+        assert!(!packet.is_correct_checksum(dst, IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))));
+    }
+
+    #[test]
+    fn checksum_rejects_a_flipped_byte() {
+        let src = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let dst = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let mut packet = syn_with_payload();
+        packet.set_checksum(packet.compute_checksum(src, dst));
+
+        // ヘッダとペイロードのどのバイトが化けても検出できる
+        for i in 0..packet.packet().len() {
+            let mut corrupted = packet.clone();
+            corrupted.buffer[i] ^= 0x01;
+            assert!(!corrupted.is_correct_checksum(dst, src), "byte {}", i);
         }
     }
 }
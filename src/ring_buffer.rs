@@ -0,0 +1,134 @@
+// 受信バッファ用の固定長のリングバッファ
+// 先頭(アプリが次に読む位置)をheadで持ち、読み出しのたびにデータを詰め直さずにheadを進める
+// 位置は全てheadからの相対的なオフセットで指定する
+pub struct RingBuffer {
+    buffer: Vec<u8>,
+    head: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buffer: vec![0; capacity],
+            head: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // headからoffsetの位置にdataを書き込む
+    // 末尾を超える分はバッファの先頭へ折り返して書き込む
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        assert!(
+            offset + data.len() <= self.capacity(),
+            "write out of range: {} + {} > {}",
+            offset,
+            data.len(),
+            self.capacity()
+        );
+        if data.is_empty() {
+            return;
+        }
+
+        let start = (self.head + offset) % self.capacity();
+        let first = usize::min(data.len(), self.capacity() - start);
+        self.buffer[start..start + first].copy_from_slice(&data[..first]);
+        self.buffer[..data.len() - first].copy_from_slice(&data[first..]);
+    }
+
+    // headからoffsetの位置にあるデータをbufferの長さだけ読み出す
+    pub fn read(&self, offset: usize, buffer: &mut [u8]) {
+        assert!(
+            offset + buffer.len() <= self.capacity(),
+            "read out of range: {} + {} > {}",
+            offset,
+            buffer.len(),
+            self.capacity()
+        );
+        if buffer.is_empty() {
+            return;
+        }
+
+        let start = (self.head + offset) % self.capacity();
+        let first = usize::min(buffer.len(), self.capacity() - start);
+        let rest = buffer.len() - first;
+        buffer[..first].copy_from_slice(&self.buffer[start..start + first]);
+        buffer[first..].copy_from_slice(&self.buffer[..rest]);
+    }
+
+    // 先頭からsizeバイトを読み終えたものとしてheadを進める
+    pub fn consume(&mut self, size: usize) {
+        assert!(size <= self.capacity());
+        if self.capacity() > 0 {
+            self.head = (self.head + size) % self.capacity();
+        }
+    }
+
+    // headを先頭に並べ直したバッファ全体
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buffer = vec![0; self.capacity()];
+        self.read(0, &mut buffer);
+        buffer
+    }
+}
+
+impl From<Vec<u8>> for RingBuffer {
+    fn from(buffer: Vec<u8>) -> Self {
+        Self { buffer, head: 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_at_an_offset_from_head() {
+        let mut ring = RingBuffer::new(8);
+        ring.write(3, b"abc");
+        ring.write(0, b"xyz");
+
+        let mut buffer = [0; 6];
+        ring.read(0, &mut buffer);
+        assert_eq!(&buffer, b"xyzabc");
+        assert_eq!(ring.to_vec(), b"xyzabc\0\0");
+    }
+
+    #[test]
+    fn write_wraps_around_the_end() {
+        let mut ring = RingBuffer::new(8);
+        ring.consume(6);
+        ring.write(0, b"abcde");
+
+        // 末尾の2バイトと先頭の3バイトに分かれて入っている
+        assert_eq!(ring.buffer, b"cde\0\0\0ab");
+        let mut buffer = [0; 5];
+        ring.read(0, &mut buffer);
+        assert_eq!(&buffer, b"abcde");
+    }
+
+    #[test]
+    fn consume_and_read_across_the_end() {
+        let mut ring = RingBuffer::from(b"01234567".to_vec());
+        ring.consume(5);
+
+        let mut buffer = [0; 3];
+        ring.read(1, &mut buffer);
+        assert_eq!(&buffer, b"670");
+
+        // headが一周して先頭へ戻る
+        ring.consume(4);
+        ring.read(0, &mut buffer);
+        assert_eq!(&buffer, b"123");
+        assert_eq!(ring.to_vec(), b"12345670");
+    }
+
+    #[test]
+    #[should_panic(expected = "write out of range")]
+    fn write_beyond_capacity_panics() {
+        let mut ring = RingBuffer::new(4);
+        ring.write(2, b"abc");
+    }
+}
@@ -0,0 +1,59 @@
+// TCPのシーケンス番号は2^32で一周するので、大小は差の符号で判定する(RFC1982)
+// 比較できるのは差が2^31未満の場合のみで、ウィンドウの大きさからそれを超えることはない
+
+pub fn lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+pub fn leq(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) <= 0
+}
+
+pub fn gt(a: u32, b: u32) -> bool {
+    lt(b, a)
+}
+
+pub fn geq(a: u32, b: u32) -> bool {
+    leq(b, a)
+}
+
+pub fn max(a: u32, b: u32) -> u32 {
+    if lt(a, b) {
+        b
+    } else {
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_across_the_wrap() {
+        assert!(lt(0xffff_fff0, 0x10));
+        assert!(!lt(0x10, 0xffff_fff0));
+        assert!(gt(0x10, 0xffff_fff0));
+        assert!(leq(u32::MAX, 0));
+        assert!(geq(0, u32::MAX));
+        assert_eq!(max(0xffff_fff0, 0x10), 0x10);
+        assert_eq!(max(0x10, 0xffff_fff0), 0x10);
+    }
+
+    #[test]
+    fn equal_numbers_are_leq_but_not_lt() {
+        for x in [0, 1, 0x7fff_ffff, 0x8000_0000, u32::MAX] {
+            assert!(leq(x, x));
+            assert!(geq(x, x));
+            assert!(!lt(x, x));
+            assert!(!gt(x, x));
+        }
+    }
+
+    #[test]
+    fn differences_up_to_half_the_space_keep_their_order() {
+        let a = 0xffff_ff00;
+        assert!(lt(a, a.wrapping_add(0x7fff_ffff)));
+        assert!(gt(a.wrapping_add(0x7fff_ffff), a));
+    }
+}
@@ -0,0 +1,330 @@
+use crate::packet::TCPPacket;
+use crate::ring_buffer::RingBuffer;
+use crate::socket::{BufferSizes, RetransmissionQueueEntry, Socket, TcpStatus};
+use crate::tcp::CongestionState;
+use anyhow::{Context, Result};
+use pnet::packet::Packet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime};
+
+// 接続状態のスナップショットのフォーマットのバージョン
+const SNAPSHOT_VERSION: u8 = 13;
+
+// 接続状態(seq/ack/window/バッファ/再送キュー)をバイト列にする
+// 時刻に関する情報はプロセスやホストをまたぐと意味を持たないので保存しない
+pub fn serialize(socket: &Socket) -> Vec<u8> {
+    let mut writer = Writer::new();
+
+    writer.u8(SNAPSHOT_VERSION);
+    writer.addr(socket.local_addr);
+    writer.addr(socket.remote_addr);
+    writer.u16(socket.local_port);
+    writer.u16(socket.remote_port);
+    writer.u8(status_to_u8(&socket.status));
+
+    writer.u32(socket.send_param.unacked_seq);
+    writer.u32(socket.send_param.next);
+    writer.u32(socket.send_param.window);
+    writer.u8(socket.send_param.scale);
+    writer.u32(socket.send_param.initial_seq);
+
+    writer.u32(socket.recv_param.next);
+    writer.u32(socket.recv_param.window);
+    writer.u8(socket.recv_param.scale);
+    writer.u32(socket.recv_param.initial_seq);
+    writer.u32(socket.recv_param.out_of_order.len() as u32);
+    for block in socket.recv_param.out_of_order.iter() {
+        writer.u32(block.start);
+        writer.u32(block.end);
+    }
+    // 広告したウィンドウの右端、まだ広告していなければ0に続けて任意の値
+    writer.u8(socket.recv_param.advertised_edge.is_some() as u8);
+    writer.u32(socket.recv_param.advertised_edge.unwrap_or(0));
+
+    writer.u32(socket.recv_buffer.capacity() as u32);
+    writer.bytes(&socket.recv_buffer.to_vec());
+    writer.u32(socket.pending.len() as u32);
+    writer.bytes(&socket.pending);
+
+    writer.u32(socket.retransmission_queue.len() as u32);
+    for item in socket.retransmission_queue.iter() {
+        writer.u32(item.packet.packet().len() as u32);
+        writer.bytes(item.packet.packet());
+        writer.u32(item.expected_ack);
+        writer.u8(item.transmission_count);
+    }
+
+    writer.u64(socket.rto.get().as_millis() as u64);
+    // 相手のMSS、通知されていない場合は0
+    writer.u16(socket.peer_options.mss.unwrap_or(0));
+    writer.u8(socket.peer_options.sack_permitted as u8);
+    // タイムスタンプを折衝した場合は1、続けて相手へ返すタイムスタンプ
+    writer.u8(socket.peer_options.timestamp.is_some() as u8);
+    writer.u32(socket.ts_recent);
+    writer.u32(socket.send_param.cwnd);
+    writer.u32(socket.send_param.ssthresh);
+    writer.u8(socket.recovery as u8);
+
+    writer.buffer
+}
+
+// serializeで保存した接続状態からソケットを復元する
+// 送信用のraw socketは開き直す
+pub fn deserialize(data: &[u8]) -> Result<Socket> {
+    let mut reader = Reader::new(data);
+
+    let version = reader.u8()?;
+    if version != SNAPSHOT_VERSION {
+        anyhow::bail!("unsupported snapshot version: {}", version);
+    }
+
+    let local_addr = reader.addr()?;
+    let remote_addr = reader.addr()?;
+    if local_addr.is_ipv4() != remote_addr.is_ipv4() {
+        anyhow::bail!(
+            "address family mismatch: {} and {}",
+            local_addr,
+            remote_addr
+        );
+    }
+    let local_port = reader.u16()?;
+    let remote_port = reader.u16()?;
+    let status = status_from_u8(reader.u8()?)?;
+
+    let mut socket = Socket::new(
+        local_addr,
+        remote_addr,
+        local_port,
+        remote_port,
+        status,
+        BufferSizes::default(),
+    )?;
+
+    socket.send_param.unacked_seq = reader.u32()?;
+    socket.send_param.next = reader.u32()?;
+    socket.send_param.window = reader.u32()?;
+    socket.send_param.scale = reader.u8()?;
+    socket.send_param.initial_seq = reader.u32()?;
+
+    socket.recv_param.next = reader.u32()?;
+    socket.recv_param.window = reader.u32()?;
+    socket.recv_param.scale = reader.u8()?;
+    socket.recv_param.initial_seq = reader.u32()?;
+    let block_count = reader.u32()?;
+    for _ in 0..block_count {
+        let start = reader.u32()?;
+        let end = reader.u32()?;
+        socket.recv_param.out_of_order.push(start..end);
+    }
+    let has_advertised_edge = reader.u8()? != 0;
+    let advertised_edge = reader.u32()?;
+    socket.recv_param.advertised_edge = has_advertised_edge.then_some(advertised_edge);
+
+    let recv_buffer_len = reader.u32()? as usize;
+    socket.recv_buffer = RingBuffer::from(reader.bytes(recv_buffer_len)?.to_vec());
+    socket.options.recv_buffer_size = socket.recv_buffer.capacity();
+    if socket.recv_param.window as usize > socket.recv_buffer.capacity() {
+        anyhow::bail!("recv window exceeds recv buffer size");
+    }
+    let pending_len = reader.u32()? as usize;
+    socket.pending = reader.bytes(pending_len)?.to_vec();
+
+    let queue_len = reader.u32()?;
+    for _ in 0..queue_len {
+        let packet_len = reader.u32()? as usize;
+        let packet = TCPPacket::from_bytes(reader.bytes(packet_len)?.to_vec())?;
+        let expected_ack = reader.u32()?;
+        let transmission_count = reader.u8()?;
+
+        let entry = RetransmissionQueueEntry {
+            packet,
+            first_transmission_time: SystemTime::now(),
+            latest_transmission_time: SystemTime::now(),
+            expected_ack,
+            transmission_count,
+            rto: socket.rto.get(),
+            sacked: false,
+        };
+        if socket.overlaps_retransmission_queue(&entry.seq_range()) {
+            anyhow::bail!("retransmission queue entries overlap");
+        }
+        socket.retransmission_queue.push_back(entry);
+    }
+
+    socket.rto.set(Duration::from_millis(reader.u64()?));
+    for item in socket.retransmission_queue.iter_mut() {
+        item.rto = socket.rto.get();
+    }
+
+    // TimeWaitの経過時間は保存しないので、復元した時点から2MSLを測り直す
+    if socket.status == TcpStatus::TimeWait {
+        socket.time_wait_since = Some(SystemTime::now());
+    }
+
+    socket.peer_options.mss = match reader.u16()? {
+        0 => None,
+        mss => Some(mss),
+    };
+    socket.negotiate_mss();
+    socket.peer_options.sack_permitted = reader.u8()? != 0;
+    let timestamps = reader.u8()? != 0;
+    socket.ts_recent = reader.u32()?;
+    if timestamps {
+        socket.peer_options.timestamp = Some(socket.ts_recent);
+    }
+    socket.send_param.cwnd = reader.u32()?;
+    socket.send_param.ssthresh = reader.u32()?;
+    socket.recovery = reader.u8()? != 0;
+    socket.congestion_state = if socket.recovery {
+        CongestionState::FastRecovery
+    } else if socket.send_param.in_slow_start() {
+        CongestionState::SlowStart
+    } else {
+        CongestionState::CongestionAvoidance
+    };
+
+    // 確立時点の値は保存しないので、復元した時点の値を記録しておく
+    if !matches!(
+        socket.status,
+        TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcvd
+    ) {
+        socket.record_tcp_info();
+    }
+
+    if !reader.is_empty() {
+        anyhow::bail!("trailing bytes in snapshot");
+    }
+
+    Ok(socket)
+}
+
+fn status_to_u8(status: &TcpStatus) -> u8 {
+    match status {
+        TcpStatus::Listen => 0,
+        TcpStatus::SynSent => 1,
+        TcpStatus::SynRcvd => 2,
+        TcpStatus::Established => 3,
+        TcpStatus::FinWait1 => 4,
+        TcpStatus::FinWait2 => 5,
+        TcpStatus::TimeWait => 6,
+        TcpStatus::CloseWait => 7,
+        TcpStatus::LastAck => 8,
+        TcpStatus::Closing => 9,
+    }
+}
+
+fn status_from_u8(status: u8) -> Result<TcpStatus> {
+    let status = match status {
+        0 => TcpStatus::Listen,
+        1 => TcpStatus::SynSent,
+        2 => TcpStatus::SynRcvd,
+        3 => TcpStatus::Established,
+        4 => TcpStatus::FinWait1,
+        5 => TcpStatus::FinWait2,
+        6 => TcpStatus::TimeWait,
+        7 => TcpStatus::CloseWait,
+        8 => TcpStatus::LastAck,
+        9 => TcpStatus::Closing,
+        _ => anyhow::bail!("unknown tcp status: {}", status),
+    };
+
+    Ok(status)
+}
+
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self { buffer: Vec::new() }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.buffer.extend_from_slice(value);
+    }
+
+    // アドレスファミリ(4か6)に続けてアドレスを書き込む
+    fn addr(&mut self, value: IpAddr) {
+        match value {
+            IpAddr::V4(addr) => {
+                self.u8(4);
+                self.bytes(&addr.octets());
+            }
+            IpAddr::V6(addr) => {
+                self.u8(6);
+                self.bytes(&addr.octets());
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            anyhow::bail!("unexpected end of snapshot");
+        }
+
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.bytes(2)?.try_into().context("invalid u16")?;
+        Ok(u16::from_be_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.bytes(4)?.try_into().context("invalid u32")?;
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let bytes = self.bytes(8)?.try_into().context("invalid u64")?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn addr(&mut self) -> Result<IpAddr> {
+        match self.u8()? {
+            4 => Ok(Ipv4Addr::from(self.u32()?).into()),
+            6 => {
+                let octets: [u8; 16] = self.bytes(16)?.try_into().context("invalid ipv6 addr")?;
+                Ok(Ipv6Addr::from(octets).into())
+            }
+            family => anyhow::bail!("unknown address family: {}", family),
+        }
+    }
+}
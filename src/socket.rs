@@ -1,24 +1,57 @@
-use crate::packet::TCPPacket;
+use crate::packet::{TCPPacket, TcpOption, TCP_HEADER_SIZE};
+use crate::ring_buffer::RingBuffer;
+use crate::seq;
+use crate::tcp::{CongestionState, TcpInfo, MSS};
 use crate::tcpflags;
+use crate::timer_wheel::TimerWheel;
 use anyhow::{Context, Result};
+use log::{debug, Level, LevelFilter, Record};
 use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
 use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
-use pnet::util;
+use std::cmp;
 use std::collections::VecDeque;
-use std::fmt::{self, Display};
-use std::net::{IpAddr, Ipv4Addr};
+use std::fmt::{self, Display, Write};
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::time::SystemTime;
 
 const SOCKET_BUFFER_SIZE: usize = 4380;
-const INIT_RTO: Duration = Duration::from_secs(3);
+const MIN_RTO: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_RTO: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_OUT_OF_ORDER_BLOCKS: usize = 16;
+// window scaleのシフト量の上限(RFC7323)
+const MAX_WINDOW_SCALE: u8 = 14;
+// ACKに載せるSACKブロックの数の上限
+// タイムスタンプ(10バイト)と合わせてもオプション領域の40バイトに収まる数にする
+const MAX_SACK_BLOCKS: usize = 3;
+// 受信バッファの使用率がこれ(%)を超えたら、広告するウィンドウを絞り始める
+const RECV_HIGH_WATER_PERCENT: u64 = 75;
+// window scalingで広告できるウィンドウの上限
+pub const MAX_WINDOW: usize = (u16::MAX as usize) << MAX_WINDOW_SCALE;
+
+// セグメントを送り出す口
+// 通常はraw socketで、テストではネットワークを使わずに送ったセグメントを受け取るものに差し替える
+pub trait SegmentSender: Send + Sync {
+    fn send_to(&mut self, packet: &TCPPacket, addr: IpAddr) -> io::Result<usize>;
+}
+
+impl SegmentSender for TransportSender {
+    fn send_to(&mut self, packet: &TCPPacket, addr: IpAddr) -> io::Result<usize> {
+        TransportSender::send_to(self, packet.clone(), addr)
+    }
+}
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
-pub struct SockID(pub Ipv4Addr, pub Ipv4Addr, pub u16, pub u16);
+pub struct SockID(pub IpAddr, pub IpAddr, pub u16, pub u16);
 
 pub struct Socket {
-    pub local_addr: Ipv4Addr,
-    pub remote_addr: Ipv4Addr,
+    pub local_addr: IpAddr,
+    pub remote_addr: IpAddr,
     pub local_port: u16,
     pub remote_port: u16,
 
@@ -26,49 +59,110 @@ pub struct Socket {
     pub recv_param: RecvParam,
     pub status: TcpStatus,
 
-    pub sender: TransportSender,
+    pub sender: Box<dyn SegmentSender>,
     pub connected_connection_queue: VecDeque<SockID>, // 接続済みソケットを保持するキュー、リスニングソケットのみ使用
     pub listening_socket: Option<SockID>, // 生成元のリスニングソケット、接続済みソケットのみ使用
+    pub draining: bool, // 新規接続の受理を停止しているか、リスニングソケットのみ使用
+    pub aborted: bool,  // 再送を諦めて接続が使えなくなったか
 
     // 再送用データの保管キュー
     pub retransmission_queue: VecDeque<RetransmissionQueueEntry>,
 
     // 受信用のバッファ
     // パケットの到着順は送信順とは限らないため、一旦バッファに格納してseq順に並び替える必要がある
-    pub recv_buffer: Vec<u8>,
+    // アプリが読むたびにデータを詰め直さないように、リングバッファで持つ
+    pub recv_buffer: RingBuffer,
+    // Nagleアルゴリズムで送信を保留している、MSS未満の未送信データ
+    pub pending: Vec<u8>,
 
     pub last_time_window_probe: Option<SystemTime>,
+    // TimeWaitに遷移した時刻、2MSL経過したらtimerスレッドがソケットを削除する
+    pub time_wait_since: Option<SystemTime>,
+    pub last_time_ack_received: SystemTime,
+    // 相手から最後にセグメントを受信した時刻、keep-aliveの判定に使う
+    pub last_activity: SystemTime,
+    // 応答の無いまま送ったkeep-aliveのprobeの数
+    pub keepalive_probes: u32,
+    pub dup_ack_count: u8,
+    // 遅延させているACKがある場合、ACKすべきデータを最初に受信した時刻
+    pub pending_ack_since: Option<SystemTime>,
+    // fast retransmitの後、新しいデータがACKされるまでのfast recovery中か
+    pub recovery: bool,
 
-    pub retransmission_timeout: Duration,
-
-    pub sent_times: VecDeque<SentTime>,
+    // 外れ値判定用の直近のRTTの履歴
+    pub turn_around_times: VecDeque<Duration>,
 
     pub rto: RTO,
+
+    pub stats: SocketStats,
+
+    // アプリから設定できるオプション、acceptで生まれる接続はリスニングソケットの設定を引き継ぐ
+    pub options: SocketOptions,
+
+    // 相手がSYNで通知してきたオプション
+    pub peer_options: PeerOptions,
+
+    // 相手から受け取った最新のタイムスタンプ、ACKのtsecrで相手へ返す(RFC7323のTS.Recent)
+    pub ts_recent: u32,
+
+    // 接続確立時点のパラメータ、確立するまではNone
+    pub tcp_info: Option<TcpInfo>,
+
+    // 接続単位のログレベル、グローバルなログレベルより詳細なログをこの接続だけ出力できる
+    pub log_level: LevelFilter,
+
+    // 輻輳制御のフェーズ、接続開始時はスロースタート
+    pub congestion_state: CongestionState,
+
+    // 再送やprobeの期限を登録するタイミングホイールと、登録済みの直近の期限
+    pub timer_wheel: Option<Arc<Mutex<TimerWheel>>>,
+    // スタック全体の統計情報、RST送信用の一時的なソケットなどでは数えない
+    pub global_counters: Option<Arc<GlobalCounters>>,
+    pub next_timer: Option<SystemTime>,
 }
 
 #[derive(Clone, Debug)]
 pub struct SendParam {
     pub unacked_seq: u32,
     pub next: u32,
-    pub window: u16,
+    // 相手の広告ウィンドウにwindow scaleを適用した実効ウィンドウ
+    pub window: u32,
+    // 相手の広告ウィンドウに適用するシフト量、window scalingを折衝していなければ0
+    pub scale: u8,
     pub initial_seq: u32,
+    // 送信するセグメントのペイロード長の上限、SYNで相手のMSSを受け取ったら折衝した値にする
+    // 経路のMTUがより小さいと分かった場合はさらに小さくする
+    pub mss: usize,
+    // 輻輳ウィンドウ、送信中のデータはwindowとcwndの小さい方に収める
+    pub cwnd: u32,
+    // cwndがこれ未満の間はスロースタートで増やす
+    pub ssthresh: u32,
 }
 
 #[derive(Clone, Debug)]
 pub struct RecvParam {
     pub next: u32,
-    pub window: u16,
+    pub window: u32,
+    // 自分の広告ウィンドウに適用するシフト量、window scalingを折衝していなければ0
+    pub scale: u8,
     pub initial_seq: u32,
-    pub tail: u32,
+    // 順序が入れ替わって届き、受信バッファに保持しているデータの範囲(seqの昇順)
+    // 歯抜けが複数あってもブロックごとに持つので、nextはnextと連続したブロックの分だけ進める
+    pub out_of_order: Vec<Range<u32>>,
+    // 相手へ最後に広告したウィンドウの右端(ACK番号+ウィンドウ)、まだ広告していなければNone
+    pub advertised_edge: Option<u32>,
 }
 
 #[derive(Clone, Debug)]
 pub struct RetransmissionQueueEntry {
     pub packet: TCPPacket,
+    pub first_transmission_time: SystemTime,
     pub latest_transmission_time: SystemTime,
     pub expected_ack: u32,
     pub transmission_count: u8,
     pub rto: Duration,
+    // 相手がSACKで受信済みと通知してきたセグメントは、タイムアウトしても再送しない
+    pub sacked: bool,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -79,57 +173,149 @@ pub enum TcpStatus {
     Established,
     FinWait1,
     FinWait2,
+    Closing,
     TimeWait,
     CloseWait,
     LastAck,
 }
 
-pub struct SentTime {
-    pub sent_time: SystemTime,
-    pub expected_ack: u32,
+// 接続の統計情報
+// sent_segmentsとretransmitted_segmentsは再送対象となるセグメント(データ、SYN、FIN)のみ数える
+#[derive(Clone, Debug)]
+pub struct SocketStats {
+    pub sent_segments: u64,
+    pub retransmitted_segments: u64,
+    pub acked_bytes: u64,
+    pub since: SystemTime,
+}
+
+// スタック全体で共有するパケットの統計情報
+// 各ソケットから更新するのでアトミックに数える
+#[derive(Debug, Default)]
+pub struct GlobalCounters {
+    pub sent_packets: AtomicU64,
+    pub sent_bytes: AtomicU64,
+    pub received_packets: AtomicU64,
+    pub received_bytes: AtomicU64,
+    pub retransmitted_packets: AtomicU64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SocketOptions {
+    pub recv_buffer_size: usize,
+    // ACKを待っている(再送のために保持している)データの上限、これを超える分はsendが待機する
+    pub send_buffer_size: usize,
+    pub round_window_to_mss: bool,
+    pub pacing: bool,
+    pub max_rto: Duration,
+    // 設定されている場合は、再送を回数ではなく初回送信からの経過時間で打ち切る
+    pub retransmit_deadline: Option<Duration>,
+    // 保持する順序逆転ブロックの数の上限
+    pub max_out_of_order_blocks: usize,
+    // sendの呼び出し単位をメッセージとして区切って送受信する
+    pub message_mode: bool,
+    // Nagleアルゴリズムを無効にして、小さなデータも即座に送る
+    pub nodelay: bool,
+    pub keepalive: Option<KeepAlive>,
+    // 受信バッファがhigh-waterを超えたら、ゼロウィンドウの前に徐々に小さいウィンドウを広告する
+    pub soft_window: bool,
+}
+
+// 通信の無い接続で相手の生存を確認するkeep-aliveの設定
+#[derive(Clone, Copy, Debug)]
+pub struct KeepAlive {
+    // probeを送り始めるまでの無通信の時間
+    pub idle: Duration,
+    // probeを送る間隔
+    pub interval: Duration,
+    // 応答が無いまま送るprobeの数、これを超えたら接続を諦める
+    pub count: u32,
+}
+
+// 接続ごとの送信・受信バッファのサイズ
+// 送信バッファのサイズはACKを待っているデータの上限で、相手のウィンドウが広くてもそれ以上は送らない
+// window scalingで広告できるMAX_WINDOWまで指定できる
+// 相手がwindow scalingに対応していない場合、64KBを超える分は広告されない
+#[derive(Clone, Copy, Debug)]
+pub struct BufferSizes {
+    pub send: usize,
+    pub recv: usize,
+}
+
+// 相手がSYNで通知してきたオプションの値
+#[derive(Clone, Debug, Default)]
+pub struct PeerOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub timestamp: Option<u32>,
 }
 
 pub struct RTO {
     rto: Duration,
     srtt: Option<Duration>,
     rttvar: Option<Duration>,
+    max: Duration,
 }
 
 impl Socket {
     pub fn new(
-        local_addr: Ipv4Addr,
-        remote_addr: Ipv4Addr,
+        local_addr: IpAddr,
+        remote_addr: IpAddr,
+        local_port: u16,
+        remote_port: u16,
+        status: TcpStatus,
+        buffer_sizes: BufferSizes,
+    ) -> Result<Self> {
+        let sender = open_sender(local_addr)?;
+        Self::with_sender(
+            local_addr,
+            remote_addr,
+            local_port,
+            remote_port,
+            status,
+            buffer_sizes,
+            Box::new(sender),
+        )
+    }
+
+    // 送信先を指定してソケットを作る
+    pub fn with_sender(
+        local_addr: IpAddr,
+        remote_addr: IpAddr,
         local_port: u16,
         remote_port: u16,
         status: TcpStatus,
+        buffer_sizes: BufferSizes,
+        sender: Box<dyn SegmentSender>,
     ) -> Result<Self> {
-        // トランスポート層のTCPに投げるように設定？
-        let (sender, _) = transport::transport_channel(
-            65535,
-            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
-        )?;
+        buffer_sizes.validate()?;
 
         let send_param = SendParam {
             unacked_seq: 0,
             initial_seq: 0,
             next: 0,
-            window: SOCKET_BUFFER_SIZE as u16,
+            window: buffer_sizes.send as u32,
+            scale: 0,
+            mss: local_mss(local_addr),
+            cwnd: initial_cwnd(local_mss(local_addr)),
+            ssthresh: u32::MAX,
         };
 
         let recv_param = RecvParam {
             initial_seq: 0,
             next: 0,
-            window: SOCKET_BUFFER_SIZE as u16,
-            tail: 0,
+            window: buffer_sizes.recv as u32,
+            scale: 0,
+            out_of_order: Vec::new(),
+            advertised_edge: None,
         };
 
         let connected_connection_queue = VecDeque::new();
         let listening_socket = None;
         let retransmission_queue = VecDeque::new();
-        let recv_buffer = vec![0; SOCKET_BUFFER_SIZE];
+        let recv_buffer = RingBuffer::new(buffer_sizes.recv);
         let window_probe_duration = None;
-        let retransmission_timeout = INIT_RTO;
-        let sent_times = VecDeque::new();
         let rto = RTO::new();
 
         Ok(Self {
@@ -145,14 +331,41 @@ impl Socket {
             sender,
             connected_connection_queue,
             listening_socket,
+            draining: false,
+            aborted: false,
             retransmission_queue,
             recv_buffer,
+            pending: Vec::new(),
 
             last_time_window_probe: window_probe_duration,
-            retransmission_timeout,
+            last_time_ack_received: SystemTime::now(),
+            last_activity: SystemTime::now(),
+            keepalive_probes: 0,
+            time_wait_since: None,
+            dup_ack_count: 0,
+            pending_ack_since: None,
+            recovery: false,
 
-            sent_times,
+            turn_around_times: VecDeque::new(),
             rto,
+
+            stats: SocketStats::new(),
+
+            options: SocketOptions {
+                recv_buffer_size: buffer_sizes.recv,
+                send_buffer_size: buffer_sizes.send,
+                ..SocketOptions::default()
+            },
+            peer_options: PeerOptions::default(),
+            ts_recent: 0,
+            tcp_info: None,
+
+            log_level: LevelFilter::Off,
+            congestion_state: CongestionState::SlowStart,
+
+            timer_wheel: None,
+            global_counters: None,
+            next_timer: None,
         })
     }
 
@@ -163,38 +376,743 @@ impl Socket {
         flag: u8,
         payload: &[u8],
     ) -> Result<usize> {
-        let mut tcp_packet = TCPPacket::new(payload.len());
+        let options = self.segment_options(flag);
+        self.send_tcp_packet_with_options(seq, ack, flag, &options, payload)
+    }
+
+    // 折衝済みのオプションのうち、SYN以外のセグメントに載せるもの
+    // SYNは自分で折衝用のオプションを載せるので対象外
+    // RSTはタイムスタンプに関わらず受理されるべきなので何も載せない(RFC7323)
+    fn segment_options(&self, flag: u8) -> Vec<TcpOption> {
+        let mut options = Vec::new();
+        if flag & (tcpflags::SYN | tcpflags::RST) > 0 {
+            return options;
+        }
+
+        if self.peer_options.timestamp.is_some() {
+            options.push(TcpOption::Timestamp {
+                tsval: timestamp_now(),
+                tsecr: self.ts_recent,
+            });
+        }
+
+        // SACKが有効な接続では、ACKに保持している順序逆転ブロックを載せる
+        if self.peer_options.sack_permitted
+            && flag & tcpflags::ACK > 0
+            && !self.recv_param.out_of_order.is_empty()
+        {
+            let blocks = self
+                .recv_param
+                .out_of_order
+                .iter()
+                .take(MAX_SACK_BLOCKS)
+                .map(|block| (block.start, block.end))
+                .collect();
+            options.push(TcpOption::Sack(blocks));
+        }
+
+        options
+    }
+
+    // PAWS(RFC7323): 受理済みのものより古いタイムスタンプを持つセグメントは、
+    // seqが一周して古い接続のセグメントと区別できないので破棄する
+    // 受理する場合は、次に相手へ返すタイムスタンプを更新する
+    pub fn check_timestamp(&mut self, packet: &TCPPacket) -> bool {
+        if self.peer_options.timestamp.is_none() {
+            return true;
+        }
+
+        let tsval = match packet.get_timestamp() {
+            Some((tsval, _)) => tsval,
+            None => return true,
+        };
+
+        if seq::lt(tsval, self.ts_recent) {
+            return false;
+        }
+
+        // 遅れて届いたセグメントのタイムスタンプで更新しないよう、ACK済みの範囲から始まるものだけ反映する
+        if seq::leq(packet.get_seq(), self.recv_param.next) {
+            self.ts_recent = tsval;
+        }
+
+        true
+    }
+
+    // ACKに載ったタイムスタンプのエコーから測ったRTT
+    // 再送は送り直す時刻のTSvalを載せるので、再送したセグメントに対するACKでも
+    // どの送信へのACKか区別でき、Karnのアルゴリズムで捨てずに測れる
+    // 再送キューからACKされたセグメントを削除する前に呼ぶ
+    pub fn timestamp_rtt(&self, packet: &TCPPacket) -> Option<Duration> {
+        self.peer_options.timestamp?;
+
+        let (_, tsecr) = packet.get_timestamp()?;
+        // tsecrが0の場合は、相手がまだエコーするタイムスタンプを持っていない
+        if tsecr == 0 {
+            return None;
+        }
+
+        // 相手は受信ウィンドウの左端を進めたセグメントのTSvalをエコーするので、
+        // 正しいエコーはACKされた最も古いセグメントを最後に送った時刻から現在までに収まる
+        // それより古いエコー(遅れて届いたACKや、再送する前の送信へのACKなど)や
+        // 未来の時刻は、RTTの測定に使わない
+        let now = timestamp_now();
+        let oldest = self
+            .retransmission_queue
+            .front()
+            .and_then(|item| item.packet.get_timestamp());
+        if seq::gt(tsecr, now) || matches!(oldest, Some((tsval, _)) if seq::lt(tsecr, tsval)) {
+            self.log(
+                Level::Debug,
+                format_args!("invalid timestamp echo: tsecr={}, now={}", tsecr, now),
+            );
+            return None;
+        }
+
+        Some(Duration::from_millis(now.wrapping_sub(tsecr) as u64))
+    }
+
+    // SACKで通知された範囲に収まる再送キューのセグメントに印を付ける
+    pub fn mark_sacked(&mut self, packet: &TCPPacket) {
+        if !self.peer_options.sack_permitted {
+            return;
+        }
+
+        for option in packet.options() {
+            let blocks = match option {
+                TcpOption::Sack(blocks) => blocks,
+                _ => continue,
+            };
+
+            for (left, right) in blocks {
+                for item in self.retransmission_queue.iter_mut() {
+                    if seq::geq(item.packet.get_seq(), left) && seq::leq(item.expected_ack, right) {
+                        item.sacked = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // SYNなどオプションを付けて送信する
+    pub fn send_tcp_packet_with_options(
+        &mut self,
+        seq: u32,
+        ack: u32,
+        flag: u8,
+        options: &[TcpOption],
+        payload: &[u8],
+    ) -> Result<usize> {
+        let tcp_packet = self.build_packet(seq, ack, flag, options, payload)?;
+
+        // RSTは再送しない
+        let retransmittable = tcp_packet.get_flag() & tcpflags::RST == 0
+            && (!payload.is_empty() || tcp_packet.get_flag() != tcpflags::ACK);
+        // 再送キューに積むものだけ、パケットを複製してエントリを作る
+        let entry = if retransmittable {
+            let entry = RetransmissionQueueEntry::new(tcp_packet.clone(), self.rto.get());
+            // 送信済みの範囲を再び新規セグメントとして送ると、再送キューに同じデータが二重に積まれる
+            // 再送はキューのセグメントをそのまま送り直すので、ここを通るのは新規の範囲だけのはず
+            if self.overlaps_retransmission_queue(&entry.seq_range()) {
+                anyhow::bail!(
+                    "segment overlaps retransmission queue: {:?}",
+                    entry.seq_range()
+                );
+            }
+            Some(entry)
+        } else {
+            None
+        };
+
+        let sent_size = self
+            .sender
+            .send_to(&tcp_packet, self.remote_addr)
+            .context(format!("failed to send: \n{:?}", tcp_packet))?;
+        self.record_advertised_edge(&tcp_packet);
+
+        self.trace_sent("sent", &tcp_packet);
+        if let Some(ref counters) = self.global_counters {
+            counters.count_sent(payload.len());
+        }
+        // ACKを載せたセグメントを送れば、遅延させていたACKも返したことになる
+        if flag & tcpflags::ACK > 0 {
+            self.pending_ack_since = None;
+        }
+
+        if let Some(entry) = entry {
+            self.stats.sent_segments += 1;
+            self.retransmission_queue.push_back(entry);
+            self.schedule_timer(SystemTime::now() + self.rto.get());
+        }
+
+        Ok(sent_size)
+    }
+
+    fn build_packet(
+        &self,
+        seq: u32,
+        ack: u32,
+        flag: u8,
+        options: &[TcpOption],
+        payload: &[u8],
+    ) -> Result<TCPPacket> {
+        let mut tcp_packet = TCPPacket::with_options(options, payload.len())?;
         tcp_packet.set_src(self.local_port);
         tcp_packet.set_dst(self.remote_port);
         tcp_packet.set_seq(seq);
         tcp_packet.set_ack(ack);
-        tcp_packet.set_data_offset(5);
         tcp_packet.set_flag(flag);
-        tcp_packet.set_window_size(self.recv_param.window);
-        tcp_packet.set_payload(payload);
-        tcp_packet.set_checksum(util::ipv4_checksum(
-            &tcp_packet.packet(),
-            8,
-            &[],
-            &self.local_addr,
-            &self.remote_addr,
-            IpNextHeaderProtocols::Tcp,
-        ));
-
-        // tcp_packet.clone()のcloneは必要？
-        let sent_size = self
-            .sender
-            .send_to(tcp_packet.clone(), IpAddr::V4(self.remote_addr))
-            .context(format!("failed to send: \n{:?}", tcp_packet))?;
+        debug_assert!(self.recv_param.window as usize <= self.recv_buffer.capacity());
+        tcp_packet.set_window_size(self.window_field(flag));
+        tcp_packet.set_payload(payload)?;
+        tcp_packet.set_checksum(tcp_packet.compute_checksum(self.local_addr, self.remote_addr));
+
+        Ok(tcp_packet)
+    }
+
+    // 再送キューのセグメントを送り直す
+    // seqとペイロードはそのままに、ACKとウィンドウとオプションは現在の値でパケットを作り直す
+    // 再送の間に受信したデータのACKや、開いたウィンドウも相手に伝わる
+    pub fn retransmit(&mut self, item: &mut RetransmissionQueueEntry) -> Result<()> {
+        // キューに積んだ時点のACK番号は古くなっているので、最新のrecv_param.nextに付け替える
+        // FinWait中にFINを再送する場合も、FIN送信後に受信したデータへのACKが載る
+        let flag = item.packet.get_flag();
+        let queued_ack = item.packet.get_ack();
+        let ack = if flag & tcpflags::ACK > 0 {
+            self.pending_ack_since = None;
+            self.recv_param.next
+        } else {
+            queued_ack
+        };
+
+        if flag & tcpflags::SYN > 0 {
+            // SYNのオプションは折衝のためのものなので、送ったときのものをそのまま使う
+            item.packet.set_ack(ack);
+            item.packet.set_window_size(self.window_field(flag));
+            item.packet.set_checksum(
+                item.packet
+                    .compute_checksum(self.local_addr, self.remote_addr),
+            );
+        } else {
+            // タイムスタンプは再送する時刻のものを、SACKは現在保持しているブロックを載せる
+            // SACKブロックが増えてヘッダが伸び、相手のMSSに収まらなくなる場合はSACKを載せない
+            let mut options = self.segment_options(flag);
+            let payload_len = item.packet.payload().len();
+            if TCPPacket::header_len(&options)? - TCP_HEADER_SIZE + payload_len
+                > self.send_param.mss
+            {
+                options.retain(|option| !matches!(option, TcpOption::Sack(_)));
+            }
+            let packet = self.build_packet(
+                item.packet.get_seq(),
+                ack,
+                flag,
+                &options,
+                item.packet.payload(),
+            )?;
+            item.packet = packet;
+        }
 
-        dbg!("sent", &tcp_packet);
+        if flag & tcpflags::FIN > 0 && queued_ack != ack {
+            self.log(
+                Level::Debug,
+                format_args!(
+                    "fin retransmitted with updated ack: {} -> {}",
+                    queued_ack.wrapping_sub(self.recv_param.initial_seq),
+                    ack.wrapping_sub(self.recv_param.initial_seq)
+                ),
+            );
+        }
 
-        if !payload.is_empty() || tcp_packet.get_flag() != tcpflags::ACK {
-            self.retransmission_queue
-                .push_back(RetransmissionQueueEntry::new(tcp_packet, self.rto.get()));
+        self.sender
+            .send_to(&item.packet, self.remote_addr)
+            .context("failed to retransmit")?;
+        self.record_advertised_edge(&item.packet);
+        self.trace_sent("retransmitted", &item.packet);
+        item.transmission_count = item.transmission_count.saturating_add(1);
+        item.latest_transmission_time = SystemTime::now();
+        self.stats.sent_segments += 1;
+        self.stats.retransmitted_segments += 1;
+        if let Some(ref counters) = self.global_counters {
+            counters.count_retransmitted(item.packet.payload().len());
         }
 
-        Ok(sent_size)
+        Ok(())
+    }
+
+    // 再送キューにrangeと重なるseqの範囲を持つセグメントがあるか
+    pub fn overlaps_retransmission_queue(&self, range: &Range<u32>) -> bool {
+        self.retransmission_queue.iter().any(|item| {
+            let queued = item.seq_range();
+            seq::lt(range.start, queued.end) && seq::lt(queued.start, range.end)
+        })
+    }
+
+    // 次のkeep-aliveのprobeを送る時刻
+    // 未ACKのデータがある間は再送で相手の生存を確認できるので、probeは送らない
+    pub fn keepalive_deadline(&self) -> Option<SystemTime> {
+        let keepalive = self.options.keepalive?;
+        if self.aborted
+            || !matches!(self.status, TcpStatus::Established | TcpStatus::CloseWait)
+            || self.send_param.used() > 0
+        {
+            return None;
+        }
+
+        Some(self.last_activity + keepalive.idle + keepalive.interval * self.keepalive_probes)
+    }
+
+    // timerスレッドがdeadlineにこのソケットを処理するように登録する
+    // 既に登録済みの期限の方が早ければ何もしない
+    pub fn schedule_timer(&mut self, deadline: SystemTime) {
+        if matches!(self.next_timer, Some(next) if next <= deadline) {
+            return;
+        }
+
+        if let Some(ref timer_wheel) = self.timer_wheel {
+            timer_wheel
+                .lock()
+                .unwrap()
+                .schedule(self.get_sock_id(), deadline);
+            self.next_timer = Some(deadline);
+        }
+    }
+
+    // オプションを反映する
+    // 受信バッファを確保し直すので、データを受信する前にのみ呼ぶこと
+    pub fn apply_options(&mut self, options: SocketOptions) {
+        if self.recv_buffer.capacity() != options.recv_buffer_size {
+            self.recv_buffer = RingBuffer::new(options.recv_buffer_size);
+            self.recv_param.window = options.recv_buffer_size as u32;
+        }
+
+        self.rto.set_max(options.max_rto);
+        self.options = options;
+    }
+
+    // 再送キューやバッファが保持しているメモリを解放する
+    // リセットやabortでソケットを破棄するときに呼ぶ
+    pub fn release_buffers(&mut self) {
+        self.retransmission_queue = VecDeque::new();
+        self.turn_around_times = VecDeque::new();
+        self.connected_connection_queue = VecDeque::new();
+        self.recv_buffer = RingBuffer::new(0);
+        self.pending = Vec::new();
+        self.recv_param.window = 0;
+        self.next_timer = None;
+    }
+
+    // pacingが有効な場合の、セグメントを送出する間隔
+    // 1RTTで送れる量(相手のウィンドウと輻輳ウィンドウの小さい方)を送り切るレートになるよう、
+    // MSSごとに均等に間隔を空ける
+    // RTTをまだ計測できていないときはpacingしない
+    pub fn pacing_interval(&self) -> Option<Duration> {
+        if !self.options.pacing {
+            return None;
+        }
+
+        let srtt = self.rto.srtt()?;
+        let mss = self.send_mss();
+        let window = cmp::min(self.send_param.window, self.send_param.cwnd) as usize;
+        let window = cmp::max(window, mss);
+        Some(srtt.mul_f64(mss as f64 / window as f64))
+    }
+
+    // 送信するセグメントのペイロード長の上限
+    // MSSはオプションを含まないので、データと一緒に載せるオプションの分だけ小さくする(RFC6691)
+    // DFを立てて送るので、超えるとフラグメントされずに破棄される
+    pub fn send_mss(&self) -> usize {
+        let header_len =
+            TCPPacket::header_len(&self.segment_options(tcpflags::ACK)).unwrap_or(TCP_HEADER_SIZE);
+        self.send_param
+            .mss
+            .saturating_sub(header_len - TCP_HEADER_SIZE)
+    }
+
+    // 経路のMTUに収まるように送信するセグメントを小さくする(RFC1191, RFC8201)
+    // 送信済みで未ACKのセグメントもこの大きさに分割し直し、再送で届くようにする
+    // 下限より小さくはせず、MSSが小さくなった場合はtrueを返す
+    pub fn lower_path_mss(&mut self, mss: usize) -> bool {
+        let mss = cmp::max(mss, min_mss(self.local_addr));
+        if mss >= self.send_param.mss {
+            return false;
+        }
+
+        self.send_param.mss = mss;
+        self.resegment_retransmission_queue();
+        true
+    }
+
+    // 再送キューのうちsend_mssを超えるセグメントを分割する
+    // 分割したセグメントは元のセグメントの送信時刻と送信回数を引き継ぐ
+    fn resegment_retransmission_queue(&mut self) {
+        let mss = self.send_mss();
+        for item in mem::take(&mut self.retransmission_queue) {
+            if item.packet.payload().len() <= mss || item.packet.get_flag() & tcpflags::SYN > 0 {
+                self.retransmission_queue.push_back(item);
+                continue;
+            }
+
+            match self.split_segment(&item, mss) {
+                Ok(items) => self.retransmission_queue.extend(items),
+                Err(error) => {
+                    self.log(
+                        Level::Warn,
+                        format_args!("failed to resegment: {:?}", error),
+                    );
+                    self.retransmission_queue.push_back(item);
+                }
+            }
+        }
+    }
+
+    fn split_segment(
+        &self,
+        item: &RetransmissionQueueEntry,
+        mss: usize,
+    ) -> Result<Vec<RetransmissionQueueEntry>> {
+        let flag = item.packet.get_flag();
+        let chunks: Vec<&[u8]> = item.packet.payload().chunks(mss).collect();
+        let mut seq = item.packet.get_seq();
+        let mut items = Vec::with_capacity(chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            // PSHとFINは元のセグメントの末尾を含むものにだけ立てる
+            let chunk_flag = if i + 1 == chunks.len() {
+                flag
+            } else {
+                flag & !(tcpflags::PSH | tcpflags::FIN)
+            };
+            let packet = self.build_packet(
+                seq,
+                item.packet.get_ack(),
+                chunk_flag,
+                &self.segment_options(chunk_flag),
+                chunk,
+            )?;
+            items.push(RetransmissionQueueEntry {
+                first_transmission_time: item.first_transmission_time,
+                latest_transmission_time: item.latest_transmission_time,
+                transmission_count: item.transmission_count,
+                sacked: item.sacked,
+                ..RetransmissionQueueEntry::new(packet, item.rto)
+            });
+            seq = seq.wrapping_add(chunk.len() as u32);
+        }
+
+        Ok(items)
+    }
+
+    // SYNとSYNACKで自分から広告するオプション
+    // 実装していない機能を広告しないよう、各機能の実装に合わせて追加する
+    // window scaleは能動オープン側が常に広告し、受動オープン側は相手が広告してきた場合のみ返す
+    pub fn syn_options(&self) -> Vec<TcpOption> {
+        let mut options = vec![TcpOption::Mss(local_mss(self.local_addr) as u16)];
+        if self.status == TcpStatus::SynSent || self.peer_options.window_scale.is_some() {
+            options.push(TcpOption::WindowScale(self.local_window_scale()));
+        }
+        if self.status == TcpStatus::SynSent || self.peer_options.sack_permitted {
+            options.push(TcpOption::SackPermitted);
+        }
+        if self.status == TcpStatus::SynSent || self.peer_options.timestamp.is_some() {
+            options.push(TcpOption::Timestamp {
+                tsval: timestamp_now(),
+                tsecr: self.ts_recent,
+            });
+        }
+
+        options
+    }
+
+    // 相手がSYNでタイムスタンプを送ってきた場合は、以降のセグメントでエコーする
+    pub fn negotiate_timestamp(&mut self) {
+        if let Some(tsval) = self.peer_options.timestamp {
+            self.ts_recent = tsval;
+        }
+    }
+
+    // 受信バッファ全体をウィンドウとして広告できる最小のシフト量
+    pub fn local_window_scale(&self) -> u8 {
+        let mut scale = 0;
+        while (self.recv_buffer.capacity() >> scale) > u16::MAX as usize && scale < MAX_WINDOW_SCALE
+        {
+            scale += 1;
+        }
+
+        scale
+    }
+
+    // 双方がSYNでwindow scaleを広告した場合のみ、ウィンドウをスケールする
+    pub fn negotiate_window_scale(&mut self) {
+        match self.peer_options.window_scale {
+            Some(scale) => {
+                if scale > MAX_WINDOW_SCALE {
+                    self.log(
+                        Level::Debug,
+                        format_args!("too large window scale: {}", scale),
+                    );
+                }
+                self.send_param.scale = cmp::min(scale, MAX_WINDOW_SCALE);
+                self.recv_param.scale = self.local_window_scale();
+            }
+            None => {
+                self.send_param.scale = 0;
+                self.recv_param.scale = 0;
+            }
+        }
+    }
+
+    // 相手がSYNでMSSを通知してきた場合は、自分のMSSと小さい方に合わせる
+    // 通知されなかった場合は自分のMSSのまま
+    // 初期の輻輳ウィンドウもMSSに合わせて決め直す
+    pub fn negotiate_mss(&mut self) {
+        let mss = local_mss(self.local_addr);
+        self.send_param.mss = match self.peer_options.mss {
+            Some(peer_mss) if peer_mss > 0 => cmp::min(mss, peer_mss as usize),
+            _ => mss,
+        };
+        self.send_param.cwnd = initial_cwnd(self.send_param.mss);
+    }
+
+    // 接続確立時点のパラメータを記録する
+    pub fn record_tcp_info(&mut self) {
+        self.tcp_info = Some(TcpInfo {
+            remote_addr: self.remote_addr,
+            remote_port: self.remote_port,
+            mss: self.send_mss(),
+            rto: self.rto.get(),
+            send_window: self.send_param.window,
+            recv_window: self.recv_param.window,
+            established_at: SystemTime::now(),
+        });
+    }
+
+    // 相手に広告する受信ウィンドウ
+    // 丸めが有効な場合は、相手が送ってくるセグメントの大きさである自分のMSSの倍数に切り下げる
+    // ウィンドウがMSS未満のときに0へ切り下げると送信が止まってしまうので、そのまま広告する
+    // 丸めや絞り込みで、以前に広告した右端より手前を広告することはしない(RFC1122 4.2.2.16)
+    pub fn advertised_window(&self) -> u32 {
+        let mss = local_mss(self.local_addr) as u32;
+        let window = self.soft_limited_window();
+        let window = if self.options.round_window_to_mss && window >= mss {
+            window - window % mss
+        } else {
+            window
+        };
+        cmp::max(window, self.advertised_floor())
+    }
+
+    // 以前に広告した右端までのウィンドウ
+    // 右端はアプリが読み出したときにしか進まないので、実際の空きを超えることは無いが、念のため空きで抑える
+    fn advertised_floor(&self) -> u32 {
+        match self.recv_param.advertised_edge {
+            Some(edge) if seq::gt(edge, self.recv_param.next) => cmp::min(
+                edge.wrapping_sub(self.recv_param.next),
+                self.recv_param.window,
+            ),
+            _ => 0,
+        }
+    }
+
+    // 送ったセグメントで広告したウィンドウの右端を記録する
+    fn record_advertised_edge(&mut self, packet: &TCPPacket) {
+        let flag = packet.get_flag();
+        if flag & tcpflags::ACK == 0 {
+            return;
+        }
+
+        let window = if flag & tcpflags::SYN > 0 {
+            packet.get_window_size() as u32
+        } else {
+            (packet.get_window_size() as u32) << self.recv_param.scale
+        };
+        let edge = packet.get_ack().wrapping_add(window);
+        if self
+            .recv_param
+            .advertised_edge
+            .is_none_or(|prev| seq::gt(edge, prev))
+        {
+            self.recv_param.advertised_edge = Some(edge);
+        }
+    }
+
+    // 受信バッファの使用量がhigh-waterを超えている間は、アプリが読み出しても右端を空きの分まで進めない
+    // 右端はnext+空きから、high-waterを超えて使っている分だけ手前に置くので、使用量に対して線形に絞られる
+    // 一度広告した右端は戻さない(advertised_floor)ので、絞る効果は読み出しで右端を進めるときに現れる
+    // 読み出しの遅いアプリでは、送信側に見えるウィンドウがゼロになる前から小さくなり、送信側は減速できる
+    // 小さなウィンドウの広告(SWS)を避けるため、MSS(空きがそれ未満なら空き)より小さくはしない
+    // 受理するかどうかは実際の空き(recv_param.window)で判定するので、絞る前の広告を信じて送られたデータも失わない
+    fn soft_limited_window(&self) -> u32 {
+        let window = self.recv_param.window;
+        if !self.options.soft_window {
+            return window;
+        }
+
+        let capacity = self.recv_buffer.capacity() as u64;
+        let used = capacity - window as u64;
+        let over = used.saturating_sub(capacity * RECV_HIGH_WATER_PERCENT / 100);
+        let limited = (window as u64).saturating_sub(over) as u32;
+        cmp::max(limited, cmp::min(window, local_mss(self.local_addr) as u32))
+    }
+
+    // セグメントのウィンドウフィールドに書く値
+    // SYNを含むセグメントのウィンドウはスケールしない(RFC7323)
+    // スケールで切り捨てた分は広告しないだけなので、受信バッファを超えることはない
+    fn window_field(&self, flag: u8) -> u16 {
+        let window = if flag & tcpflags::SYN > 0 {
+            self.advertised_window()
+        } else {
+            self.advertised_window() >> self.recv_param.scale
+        };
+        cmp::min(window, u16::MAX as u32) as u16
+    }
+
+    // 送信したセグメントを、seqは自分の初期seq、ackは相手の初期seqからの相対値でトレースする
+    pub fn trace_sent(&self, event: &str, packet: &TCPPacket) {
+        self.trace_segment(
+            event,
+            packet,
+            self.send_param.initial_seq,
+            self.recv_param.initial_seq,
+        );
+    }
+
+    // 受信したセグメントを、seqは相手の初期seq、ackは自分の初期seqからの相対値でトレースする
+    pub fn trace_received(&self, packet: &TCPPacket) {
+        self.trace_segment(
+            "received",
+            packet,
+            self.recv_param.initial_seq,
+            self.send_param.initial_seq,
+        );
+    }
+
+    // 絶対値のseqは桁が大きく、順序逆転やギャップを追うときに読みづらいので、
+    // 送受信とも同じフォーマットで初期seqからの相対値を出力する
+    // SYNは初期seqを記録する前にトレースすることがあるので、SYN自身のseqを基準にして0と表示する
+    fn trace_segment(&self, event: &str, packet: &TCPPacket, seq_base: u32, ack_base: u32) {
+        let flag = packet.get_flag();
+        let seq_base = if flag & tcpflags::SYN > 0 {
+            packet.get_seq()
+        } else {
+            seq_base
+        };
+        let ack = if flag & tcpflags::ACK > 0 {
+            packet.get_ack().wrapping_sub(ack_base).to_string()
+        } else {
+            "-".to_string()
+        };
+
+        self.log(
+            Level::Trace,
+            format_args!(
+                "{} seq={} ack={} len={} win={} [{}]",
+                event,
+                packet.get_seq().wrapping_sub(seq_base),
+                ack,
+                packet.payload().len(),
+                packet.get_window_size(),
+                tcpflags::flag_to_string(flag).trim_end(),
+            ),
+        );
+    }
+
+    // 接続単位のログレベルを考慮してログを出力する
+    pub fn log(&self, level: Level, args: fmt::Arguments) {
+        log_connection(self.get_sock_id(), self.log_level, level, args);
+    }
+
+    // 受信バッファにあるアプリ未読のin-orderデータのバイト数
+    // 広告ウィンドウは常に受信バッファの空きと一致していなければならない
+    pub fn received_size(&self) -> usize {
+        debug_assert!(
+            self.recv_param.window as usize <= self.recv_buffer.capacity(),
+            "advertised window {} exceeds recv buffer size {}",
+            self.recv_param.window,
+            self.recv_buffer.capacity()
+        );
+        self.recv_buffer.capacity() - self.recv_param.window as usize
+    }
+
+    // 相手の受信ウィンドウ、輻輳ウィンドウ、送信バッファの空きの全てに収まる、まだ送信できる量
+    // 輻輳ウィンドウはネットワーク上にあるバイト数と比べ、それ以外は未ACKのバイト数と比べる
+    pub fn send_capacity(&self) -> u32 {
+        let used = self.send_param.used();
+        let window_room = self.send_param.window.saturating_sub(used);
+        let cwnd_room = self.send_param.cwnd.saturating_sub(self.inflight());
+        let buffer_room = (self.options.send_buffer_size as u32).saturating_sub(used);
+        cmp::min(window_room, cmp::min(cwnd_room, buffer_room))
+    }
+
+    // ネットワーク上にある未ACKのバイト数
+    // SACK済みのセグメントは相手に届いてネットワークから抜けているので差し引く
+    pub fn inflight(&self) -> u32 {
+        let sacked: u32 = self
+            .retransmission_queue
+            .iter()
+            .filter(|item| item.sacked)
+            .map(|item| item.expected_ack.wrapping_sub(item.packet.get_seq()))
+            .sum();
+        self.send_param.used().saturating_sub(sacked)
+    }
+
+    // 送受信のシーケンス空間と再送キューの各セグメントの範囲をテキストで図示する
+    // seqはinitial_seqからの相対値で表示する
+    pub fn seq_diagram(&self) -> String {
+        let send = &self.send_param;
+        let recv = &self.recv_param;
+        let send_rel = |seq: u32| seq.wrapping_sub(send.initial_seq);
+        let recv_rel = |seq: u32| seq.wrapping_sub(recv.initial_seq);
+
+        let mut diagram = String::new();
+        writeln!(diagram, "{:?} {}", self.get_sock_id(), self.status).unwrap();
+
+        writeln!(
+            diagram,
+            "send: acked [0, {}) | in flight [{}, {}) | sendable [{}, {}) | window {} | inflight {}",
+            send_rel(send.unacked_seq),
+            send_rel(send.unacked_seq),
+            send_rel(send.next),
+            send_rel(send.next),
+            send_rel(send.unacked_seq) + send.window,
+            send.window,
+            self.inflight(),
+        )
+        .unwrap();
+        for item in self.retransmission_queue.iter() {
+            writeln!(
+                diagram,
+                "  queued [{}, {}) {}x{}",
+                send_rel(item.packet.get_seq()),
+                send_rel(item.expected_ack),
+                tcpflags::flag_to_string(item.packet.get_flag()).trim_end(),
+                item.transmission_count,
+            )
+            .unwrap();
+        }
+
+        write!(
+            diagram,
+            "recv: received [0, {}) | window {}",
+            recv_rel(recv.next),
+            recv.window,
+        )
+        .unwrap();
+        // 順序逆転ブロックの手前にはギャップがある
+        let mut gap_start = recv.next;
+        for block in recv.out_of_order.iter() {
+            write!(
+                diagram,
+                "\n  gap [{}, {}) | out of order [{}, {})",
+                recv_rel(gap_start),
+                recv_rel(block.start),
+                recv_rel(block.start),
+                recv_rel(block.end),
+            )
+            .unwrap();
+            gap_start = block.end;
+        }
+
+        diagram
     }
 
     pub fn get_sock_id(&self) -> SockID {
@@ -207,13 +1125,297 @@ impl Socket {
     }
 }
 
+impl RecvParam {
+    // 順序が入れ替わって届いたデータの範囲を記録する
+    // 重なる範囲や隣接する範囲は1つのブロックにまとめる
+    // 1バイトずつ歯抜けに送りつけられてもブロックが増え続けないように、
+    // 上限を超えたら最も小さいブロックを破棄する。破棄した範囲は未受信として相手に再送させる
+    pub fn insert_out_of_order(&mut self, range: Range<u32>, max_blocks: usize) {
+        self.out_of_order.push(range);
+        let next = self.next;
+        self.out_of_order
+            .sort_by_key(|block| block.start.wrapping_sub(next));
+
+        let mut merged: Vec<Range<u32>> = Vec::with_capacity(self.out_of_order.len());
+        for block in self.out_of_order.drain(..) {
+            match merged.last_mut() {
+                Some(last) if seq::leq(block.start, last.end) => {
+                    last.end = seq::max(last.end, block.end)
+                }
+                _ => merged.push(block),
+            }
+        }
+
+        while merged.len() > max_blocks {
+            let smallest = (0..merged.len())
+                .min_by_key(|&i| merged[i].end.wrapping_sub(merged[i].start))
+                .unwrap();
+            debug!("drop out-of-order block: {:?}", merged[smallest]);
+            merged.remove(smallest);
+        }
+
+        self.out_of_order = merged;
+    }
+
+    // nextと連続した順序逆転ブロックを取り込んでnextを進める
+    pub fn merge_out_of_order(&mut self) {
+        while let Some(i) = self
+            .out_of_order
+            .iter()
+            .position(|block| seq::leq(block.start, self.next))
+        {
+            let block = self.out_of_order.remove(i);
+            self.next = seq::max(self.next, block.end);
+        }
+    }
+}
+
 impl SendParam {
     pub fn used(&self) -> u32 {
-        self.next - self.unacked_seq
+        self.next.wrapping_sub(self.unacked_seq)
     }
 
+    // 相手の受信ウィンドウと輻輳ウィンドウの両方に収まる、まだ送信できる量
     pub fn remain(&self) -> u32 {
-        u32::from(self.window).saturating_sub(self.used())
+        cmp::min(self.window, self.cwnd).saturating_sub(self.used())
+    }
+
+    // 新しいデータがACKされたときにcwndを増やす
+    // スロースタート中はACKごとに1MSS増やすので、1RTTごとにおよそ倍になる
+    // ssthresh以上では輻輳回避としてACKごとにMSS*MSS/cwnd増やし、1RTTでおよそ1MSSずつ増やす
+    pub fn grow_cwnd(&mut self) {
+        let mss = self.mss as u32;
+        if self.in_slow_start() {
+            self.cwnd = self.cwnd.saturating_add(mss);
+        } else {
+            // cwndが大きいと増分が0に切り捨てられて増えなくなるので、最低1バイトは増やす
+            let increment = cmp::max(1, mss * mss / cmp::max(self.cwnd, 1));
+            self.cwnd = self.cwnd.saturating_add(increment);
+        }
+    }
+
+    // fast recoveryに入る(RFC5681 3.2)
+    // 重複ACKを返した3セグメントは相手に届いてネットワークから抜けているので、その分cwndを膨らませる
+    pub fn enter_fast_recovery(&mut self) {
+        let mss = self.mss as u32;
+        self.ssthresh = cmp::max(self.cwnd / 2, 2 * mss);
+        self.cwnd = self.ssthresh.saturating_add(3 * mss);
+    }
+
+    // fast recovery中の重複ACKごとに、届いたセグメントの分だけcwndを膨らませる
+    pub fn inflate_cwnd(&mut self) {
+        self.cwnd = self.cwnd.saturating_add(self.mss as u32);
+    }
+
+    // 欠けていたセグメントがACKされたら、膨らませた分を戻してfast recoveryを抜ける
+    pub fn exit_fast_recovery(&mut self) {
+        self.cwnd = self.ssthresh;
+    }
+
+    pub fn in_slow_start(&self) -> bool {
+        self.cwnd < self.ssthresh
+    }
+
+    // 再送タイムアウトしたら、ssthreshを半分にしてcwndを1MSSからやり直す(RFC5681)
+    pub fn collapse_cwnd(&mut self) {
+        self.ssthresh = cmp::max(self.cwnd / 2, 2 * self.mss as u32);
+        self.cwnd = self.mss as u32;
+    }
+
+    // セグメントのウィンドウフィールドの値を実効ウィンドウにする
+    // SYNを含むセグメントのウィンドウはスケールされないので、この関数を通さない(RFC7323)
+    pub fn scaled_window(&self, window_size: u16) -> u32 {
+        u32::from(window_size) << self.scale
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            recv_buffer_size: SOCKET_BUFFER_SIZE,
+            send_buffer_size: SOCKET_BUFFER_SIZE,
+            round_window_to_mss: false,
+            pacing: false,
+            max_rto: DEFAULT_MAX_RTO,
+            retransmit_deadline: None,
+            max_out_of_order_blocks: DEFAULT_MAX_OUT_OF_ORDER_BLOCKS,
+            message_mode: false,
+            nodelay: false,
+            keepalive: None,
+            soft_window: false,
+        }
+    }
+}
+
+// 自分のMSS
+// IPv6のヘッダはIPv4より20バイト大きいので、同じMTUに収まるようにその分小さくする
+fn local_mss(addr: IpAddr) -> usize {
+    match addr {
+        IpAddr::V4(_) => MSS,
+        IpAddr::V6(_) => MSS - 20,
+    }
+}
+
+// 経路のMTUに合わせて小さくするMSSの下限
+// IPv4はRFC1122のデフォルトのMSS、IPv6は最小MTUの1280バイトに収まる大きさ
+fn min_mss(addr: IpAddr) -> usize {
+    match addr {
+        IpAddr::V4(_) => 536,
+        IpAddr::V6(_) => 1220,
+    }
+}
+
+// TCPセグメントを送信するraw socketを開く
+// IPヘッダはカーネルが付けるので、アドレスファミリに合わせてチャネルを選ぶ
+pub fn open_sender(addr: IpAddr) -> Result<TransportSender> {
+    let protocol = match addr {
+        IpAddr::V4(_) => TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp),
+        IpAddr::V6(_) => TransportProtocol::Ipv6(IpNextHeaderProtocols::Tcp),
+    };
+    let (sender, _) = transport::transport_channel(65535, TransportChannelType::Layer4(protocol))?;
+    set_dont_fragment(&sender, addr)?;
+
+    Ok(sender)
+}
+
+// 経路上でフラグメントされないように、DFを立てて送信する
+// セグメントの大きさはこちらで経路のMTUに合わせるので、カーネルが覚えている経路のMTUは使わない
+fn set_dont_fragment(sender: &TransportSender, addr: IpAddr) -> Result<()> {
+    let (level, name, value) = match addr {
+        IpAddr::V4(_) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        ),
+        IpAddr::V6(_) => (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        ),
+    };
+    let result = unsafe {
+        libc::setsockopt(
+            sender.socket.fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error()).context("failed to set dont fragment");
+    }
+
+    Ok(())
+}
+
+// 接続のログを出力する、接続に関するログは全てここを通す
+// 接続のログレベル(log_level)かグローバルなlog::max_level()のどちらかが許せば出力する
+// log!マクロはmax_levelで止まってしまうので、ロガーへ直接渡してmax_levelの判定だけを置き換える
+// ロガー自身のフィルタ(env_loggerならRUST_LOG)は通常どおり適用されるので、接続単位で詳細なログを出すには
+// ロガーのフィルタを緩めておき、全体のログレベルはlog::set_max_levelで絞る
+pub fn log_connection(sock_id: SockID, log_level: LevelFilter, level: Level, args: fmt::Arguments) {
+    if !connection_log_enabled(log_level, level) {
+        return;
+    }
+
+    log::logger().log(
+        &Record::builder()
+            .args(format_args!("{:?}: {}", sock_id, args))
+            .level(level)
+            .target(module_path!())
+            .build(),
+    );
+}
+
+fn connection_log_enabled(log_level: LevelFilter, level: Level) -> bool {
+    level <= log_level || level <= log::max_level()
+}
+
+// 初期の輻輳ウィンドウ(RFC5681)
+fn initial_cwnd(mss: usize) -> u32 {
+    let mss = mss as u32;
+    cmp::min(4 * mss, cmp::max(2 * mss, 4380))
+}
+
+// タイムスタンプオプションに載せるミリ秒単位の時刻
+// 32bitで一周するので、比較にはseqと同じ折り返しを考慮した関数を使う
+pub fn timestamp_now() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u32
+}
+
+impl GlobalCounters {
+    // ペイロードのバイト数を数える
+    pub fn count_sent(&self, payload_len: usize) {
+        self.sent_packets.fetch_add(1, Ordering::Relaxed);
+        self.sent_bytes
+            .fetch_add(payload_len as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_received(&self, payload_len: usize) {
+        self.received_packets.fetch_add(1, Ordering::Relaxed);
+        self.received_bytes
+            .fetch_add(payload_len as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_retransmitted(&self, payload_len: usize) {
+        self.count_sent(payload_len);
+        self.retransmitted_packets.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl BufferSizes {
+    pub fn validate(&self) -> Result<()> {
+        for size in [self.send, self.recv] {
+            if size == 0 || size > MAX_WINDOW {
+                anyhow::bail!("invalid buffer size: {}", size);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for BufferSizes {
+    fn default() -> Self {
+        Self {
+            send: SOCKET_BUFFER_SIZE,
+            recv: SOCKET_BUFFER_SIZE,
+        }
+    }
+}
+
+impl PeerOptions {
+    pub fn from_packet(packet: &TCPPacket) -> Self {
+        let mut peer_options = Self::default();
+        for option in packet.options() {
+            match option {
+                TcpOption::Mss(mss) => peer_options.mss = Some(mss),
+                TcpOption::WindowScale(shift) => peer_options.window_scale = Some(shift),
+                TcpOption::SackPermitted => peer_options.sack_permitted = true,
+                TcpOption::Timestamp { tsval, .. } => peer_options.timestamp = Some(tsval),
+                TcpOption::EndOfOptions
+                | TcpOption::Nop
+                | TcpOption::Sack(_)
+                | TcpOption::Unknown { .. } => {}
+            }
+        }
+
+        peer_options
+    }
+}
+
+impl SocketStats {
+    pub fn new() -> Self {
+        Self {
+            sent_segments: 0,
+            retransmitted_segments: 0,
+            acked_bytes: 0,
+            since: SystemTime::now(),
+        }
     }
 }
 
@@ -223,6 +1425,7 @@ impl RTO {
             rto: Duration::from_secs(1),
             srtt: None,
             rttvar: None,
+            max: DEFAULT_MAX_RTO,
         }
     }
 
@@ -231,10 +1434,18 @@ impl RTO {
     }
 
     pub fn set(&mut self, rto: Duration) {
-        self.rto = Duration::min(
-            Duration::max(Duration::from_secs(1), rto),
-            Duration::from_secs(60),
-        );
+        self.rto = Duration::min(Duration::max(MIN_RTO, rto), self.max);
+    }
+
+    // RTOの上限を設定する
+    // RFC6298は上限を実装依存としているので、環境に合わせて変えられるようにする
+    pub fn set_max(&mut self, max: Duration) {
+        self.max = max;
+        self.set(self.rto);
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
     }
 
     pub fn backoff(&mut self) -> Duration {
@@ -268,15 +1479,30 @@ impl RTO {
 }
 
 impl RetransmissionQueueEntry {
+    // このセグメントが消費するseqの範囲(SYNとFINの分も含む)
+    pub fn seq_range(&self) -> Range<u32> {
+        self.packet.get_seq()..self.expected_ack
+    }
+
     fn new(packet: TCPPacket, rto: Duration) -> Self {
-        let expected_ack = packet.get_seq() + packet.payload().len() as u32;
+        // SYNとFINはそれぞれシーケンス番号を1つ消費する
+        let mut expected_ack = packet.get_seq().wrapping_add(packet.payload().len() as u32);
+        if packet.get_flag() & tcpflags::SYN > 0 {
+            expected_ack = expected_ack.wrapping_add(1);
+        }
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            expected_ack = expected_ack.wrapping_add(1);
+        }
 
+        let now = SystemTime::now();
         Self {
             packet,
-            latest_transmission_time: SystemTime::now(),
+            first_transmission_time: now,
+            latest_transmission_time: now,
             expected_ack,
             transmission_count: 1,
             rto,
+            sacked: false,
         }
     }
 }
@@ -290,6 +1516,7 @@ impl Display for TcpStatus {
             TcpStatus::Established => "ESTABLISHED",
             TcpStatus::FinWait1 => "FINWAIT1",
             TcpStatus::FinWait2 => "FINWAIT2",
+            TcpStatus::Closing => "CLOSING",
             TcpStatus::TimeWait => "TIMEWAIT",
             TcpStatus::CloseWait => "CLOSEWAIT",
             TcpStatus::LastAck => "LASTACK",
@@ -298,3 +1525,266 @@ impl Display for TcpStatus {
         write!(f, "{}", msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn socket_with_recv_buffer(local_addr: IpAddr, remote_addr: IpAddr, recv: usize) -> Socket {
+        established_socket(
+            (local_addr, unique_port()),
+            (remote_addr, unique_port()),
+            0,
+            0,
+            BufferSizes { send: recv, recv },
+            Box::new(RecordingSender::default()),
+        )
+    }
+
+    // 相手のウィンドウに十分な空きがある、確立直後の送信側のパラメータ
+    fn send_param(mss: usize) -> SendParam {
+        SendParam {
+            unacked_seq: 0,
+            initial_seq: 0,
+            next: 0,
+            window: MAX_WINDOW as u32,
+            scale: 0,
+            mss,
+            cwnd: initial_cwnd(mss),
+            ssthresh: u32::MAX,
+        }
+    }
+
+    // cwnd分のセグメントが1つずつACKされる1RTTを模擬する
+    fn ack_one_round(param: &mut SendParam) {
+        for _ in 0..param.cwnd / param.mss as u32 {
+            param.grow_cwnd();
+        }
+    }
+
+    fn recv_param(next: u32) -> RecvParam {
+        RecvParam {
+            next,
+            window: SOCKET_BUFFER_SIZE as u32,
+            scale: 0,
+            initial_seq: next,
+            out_of_order: Vec::new(),
+            advertised_edge: None,
+        }
+    }
+
+    // 受信処理と同じく、nextから始まるものは取り込み、それ以外は順序逆転ブロックとして記録する
+    fn receive(param: &mut RecvParam, range: Range<u32>) {
+        if range.start == param.next {
+            param.next = range.end;
+            param.merge_out_of_order();
+        } else {
+            param.insert_out_of_order(range, DEFAULT_MAX_OUT_OF_ORDER_BLOCKS);
+        }
+    }
+
+    // 順序逆転ブロックを(start, end)の組で並べたもの
+    fn blocks(param: &RecvParam) -> Vec<(u32, u32)> {
+        param
+            .out_of_order
+            .iter()
+            .map(|block| (block.start, block.end))
+            .collect()
+    }
+
+    #[test]
+    fn out_of_order_segments_are_merged_as_gaps_fill() {
+        // seqの一周をまたぐ位置から、100バイトずつの5セグメント
+        let base = u32::MAX - 150;
+        let segment = |k: u32| base.wrapping_add((k - 1) * 100)..base.wrapping_add(k * 100);
+        let at = |offset: u32| base.wrapping_add(offset);
+        let mut param = recv_param(base);
+
+        let steps = [
+            (1, at(100), vec![]),
+            (3, at(100), vec![(at(200), at(300))]),
+            (2, at(300), vec![]),
+            (5, at(300), vec![(at(400), at(500))]),
+            (4, at(500), vec![]),
+        ];
+        for (k, next, expected) in steps {
+            receive(&mut param, segment(k));
+            assert_eq!(param.next, next, "after segment {}", k);
+            assert_eq!(blocks(&param), expected, "after segment {}", k);
+        }
+    }
+
+    #[test]
+    fn overlapping_and_adjacent_blocks_are_coalesced() {
+        let mut param = recv_param(0);
+        param.insert_out_of_order(300..400, 4);
+        param.insert_out_of_order(100..200, 4);
+        assert_eq!(blocks(&param), [(100, 200), (300, 400)]);
+
+        // 隣接するブロックや重なるブロックは1つにまとめる
+        param.insert_out_of_order(200..250, 4);
+        param.insert_out_of_order(240..310, 4);
+        assert_eq!(blocks(&param), [(100, 400)]);
+
+        // 上限を超えたら最も小さいブロックを捨てる
+        param.insert_out_of_order(500..510, 2);
+        param.insert_out_of_order(600..700, 2);
+        assert_eq!(blocks(&param), [(100, 400), (600, 700)]);
+
+        // nextに重なるブロックも取り込む
+        param.next = 150;
+        param.merge_out_of_order();
+        assert_eq!(param.next, 400);
+        assert_eq!(blocks(&param), [(600, 700)]);
+    }
+
+    #[test]
+    fn cwnd_doubles_every_round_in_slow_start() {
+        let mut param = send_param(1000);
+        assert_eq!(param.cwnd, 4000);
+
+        for expected in [8000, 16000, 32000] {
+            ack_one_round(&mut param);
+            assert_eq!(param.cwnd, expected);
+            assert!(param.in_slow_start());
+        }
+
+        // タイムアウトしたら半分をssthreshに覚えて、1MSSからやり直す
+        param.collapse_cwnd();
+        assert_eq!((param.cwnd, param.ssthresh), (1000, 16000));
+        assert!(param.in_slow_start());
+    }
+
+    #[test]
+    fn cwnd_grows_by_about_one_mss_per_round_above_ssthresh() {
+        let mut param = send_param(1000);
+        param.ssthresh = 8000;
+        // スロースタートでssthreshを超えるまでは倍々に増える
+        ack_one_round(&mut param);
+        assert_eq!(param.cwnd, 8000);
+        assert!(!param.in_slow_start());
+
+        // 輻輳回避では1RTTでおよそ1MSSずつ、線形に増える
+        // ACKの数はcwnd/MSSの切り捨てなので、1MSSより少し小さくなる
+        for _ in 0..5 {
+            let before = param.cwnd;
+            ack_one_round(&mut param);
+            let increase = param.cwnd - before;
+            assert!((800..=1000).contains(&increase), "{}", increase);
+        }
+        assert!(param.cwnd < 2 * 8000);
+    }
+
+    #[test]
+    fn segment_overlapping_the_retransmission_queue_is_not_sent() {
+        let sender = RecordingSender::default();
+        let mut socket = established_socket(
+            (LOCAL_ADDR, unique_port()),
+            (REMOTE_ADDR, unique_port()),
+            0,
+            0,
+            BufferSizes::default(),
+            Box::new(sender.clone()),
+        );
+        socket
+            .send_tcp_packet(1, 1, tcpflags::ACK, &[1; 100])
+            .unwrap();
+
+        // 送信済みの範囲に一部でも重なるセグメントは、送らずにエラーにする
+        for seq in [1, 50, 100] {
+            assert!(socket
+                .send_tcp_packet(seq, 1, tcpflags::ACK, &[2; 10])
+                .is_err());
+        }
+        // 直後から始まるセグメントや、再送キューに積まないACKは送れる
+        socket
+            .send_tcp_packet(101, 1, tcpflags::ACK, &[3; 10])
+            .unwrap();
+        socket.send_tcp_packet(1, 1, tcpflags::ACK, &[]).unwrap();
+
+        let ranges: Vec<_> = socket
+            .retransmission_queue
+            .iter()
+            .map(|item| item.seq_range())
+            .collect();
+        assert_eq!(ranges, [1..101, 101..111]);
+        assert_eq!(sender.take().len(), 3);
+    }
+
+    #[test]
+    fn pacing_spreads_the_smaller_of_window_and_cwnd_over_srtt() {
+        let mut socket = socket_with_recv_buffer(LOCAL_ADDR, REMOTE_ADDR, 4380);
+        assert_eq!(socket.pacing_interval(), None);
+
+        socket.options.pacing = true;
+        // RTTを計測するまではpacingしない
+        assert_eq!(socket.pacing_interval(), None);
+
+        socket.rto.srtt = Some(Duration::from_millis(100));
+        let mss = socket.send_mss() as u32;
+        socket.send_param.window = 10 * mss;
+        socket.send_param.cwnd = 2 * mss;
+        assert_eq!(socket.pacing_interval(), Some(Duration::from_millis(50)));
+
+        socket.send_param.cwnd = 20 * mss;
+        assert_eq!(socket.pacing_interval(), Some(Duration::from_millis(10)));
+
+        // MSSより小さいウィンドウでも、1RTTに1セグメントより遅くはしない
+        socket.send_param.window = 0;
+        assert_eq!(socket.pacing_interval(), Some(Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn rounds_advertised_window_to_local_mss() {
+        let mut socket = socket_with_recv_buffer(LOCAL_ADDR, REMOTE_ADDR, 5000);
+        socket.options.round_window_to_mss = true;
+        assert_eq!(socket.advertised_window(), 3 * 1460);
+
+        // IPv6はヘッダが20バイト大きい分MSSも小さい
+        let local_v6 = "2001:db8::1".parse().unwrap();
+        let remote_v6 = "2001:db8::2".parse().unwrap();
+        let mut socket = socket_with_recv_buffer(local_v6, remote_v6, 5000);
+        socket.options.round_window_to_mss = true;
+        assert_eq!(socket.advertised_window(), 3 * 1440);
+
+        // MSS未満は0にせずそのまま広告する
+        socket.recv_param.window = 1000;
+        assert_eq!(socket.advertised_window(), 1000);
+    }
+
+    #[test]
+    fn rounding_never_moves_the_right_edge_backwards() {
+        let mut socket = socket_with_recv_buffer(LOCAL_ADDR, REMOTE_ADDR, 5000);
+        socket.options.round_window_to_mss = true;
+        socket
+            .send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )
+            .unwrap();
+        let edge = socket.recv_param.next.wrapping_add(3 * 1460);
+        assert_eq!(socket.recv_param.advertised_edge, Some(edge));
+
+        // 1000バイト受信すると空きは4000で、丸めると2920になるが、右端は前回の広告より手前にしない
+        socket.recv_param.next = socket.recv_param.next.wrapping_add(1000);
+        socket.recv_param.window -= 1000;
+        assert_eq!(socket.advertised_window(), 3 * 1460 - 1000);
+
+        // アプリが読み出して空きが増えれば、右端を進めて丸める
+        socket.recv_param.window = 5000;
+        assert_eq!(socket.advertised_window(), 3 * 1460);
+    }
+
+    #[test]
+    fn connection_log_level_lifts_the_global_max_level() {
+        // ロガーを設定していないテストではlog::max_level()はOffのまま
+        assert_eq!(log::max_level(), LevelFilter::Off);
+        assert!(connection_log_enabled(LevelFilter::Trace, Level::Trace));
+        assert!(connection_log_enabled(LevelFilter::Debug, Level::Warn));
+        assert!(!connection_log_enabled(LevelFilter::Info, Level::Debug));
+        assert!(!connection_log_enabled(LevelFilter::Off, Level::Error));
+    }
+}
@@ -1,35 +1,87 @@
-use crate::packet::TCPPacket;
-use crate::socket::{RetransmissionQueueEntry, SentTime, SockID, Socket, TcpStatus, RTO};
+use crate::icmp::{self, TooBig};
+use crate::packet::{TCPPacket, TCP_HEADER_SIZE};
+use crate::seq;
+use crate::snapshot;
+use crate::socket::{
+    log_connection, open_sender, BufferSizes, GlobalCounters, KeepAlive, PeerOptions,
+    SegmentSender, SockID, Socket, SocketStats, TcpStatus, MAX_WINDOW,
+};
 use crate::tcpflags;
+use crate::timer_wheel::TimerWheel;
 use anyhow::{Context, Result};
+use log::{debug, error, trace, warn, Level, LevelFilter};
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
-use pnet::transport::{self, TransportChannelType};
+use pnet::transport::{self, TransportChannelType, TransportProtocol};
 use rand::{rngs::ThreadRng, Rng};
-use std::collections::{HashMap, VecDeque};
-use std::net::{IpAddr, Ipv4Addr};
-use std::process::Command;
-use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
-use std::time::{Duration, SystemTime};
-use std::{cmp, ops::Range, str, thread};
-
-const UNDETERMINED_IP_ADDR: std::net::Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+use std::collections::hash_map::{DefaultHasher, Entry};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error;
+use std::fmt::{self, Display};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{cmp, mem, ops::Range, thread};
+
 const UNDETERMINED_PORT: u16 = 0;
 const MAX_TRANSMISSION: u8 = 5;
-const MSS: usize = 1460;
+// プロセス内の全てのTCPインスタンスが使っているローカルポートと、その持ち主のインスタンスID
+// raw socketは同じホスト宛ての全パケットを全てのインスタンスへ配るので、
+// ポートの持ち主のインスタンスだけがセグメントを処理する
+static PORT_OWNERS: Mutex<BTreeMap<u16, u64>> = Mutex::new(BTreeMap::new());
+static NEXT_INSTANCE_ID: AtomicU64 = AtomicU64::new(0);
+pub(crate) const MSS: usize = 1460;
 const PORT_RANGE: Range<u16> = 40000..60000;
+const SEND_BATCH_SIZE: usize = 16;
+const ACCEPT_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+const EVENT_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+// 削除されたソケットのイベントを残しておく時間
+// 待機中のスレッドはEVENT_RECHECK_INTERVALごとに確認するので、その間に受け取れる
+const EVENT_RETENTION: Duration = Duration::from_secs(1);
+const TIMER_TICK: Duration = Duration::from_millis(100);
+const TIMER_WHEEL_SLOTS: usize = 512;
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(100);
 const WINDOW_PROBE_DURATION: Duration = Duration::from_millis(5000);
+// 遅延ACKでACKを遅らせる最大の時間
+const DELAYED_ACK_TIMEOUT: Duration = Duration::from_millis(200);
+// セグメントがネットワーク上に残りうる最大の時間
+const MSL: Duration = Duration::from_secs(30);
 const TURN_AROUND_TIMES_MAXLEN: usize = 16;
 const RTO_MARGIN: f32 = 3.0;
+const RTT_OUTLIER_MIN_SAMPLES: usize = 4;
+// fast retransmitを行う重複ACKの回数
+const DUP_ACK_THRESHOLD: u8 = 3;
+// 同じセグメントをこの回数送っても届かない場合、MTUブラックホールを疑ってセグメントを小さくする
+const BLACKHOLE_DETECTION_COUNT: u8 = 2;
+// メッセージモードでメッセージの前に付ける長さヘッダのサイズ
+const MESSAGE_HEADER_SIZE: usize = 4;
+// メッセージモードで送受信できるメッセージの最大長
+// 相手から届いた長さヘッダをそのまま信じて巨大なバッファを確保しないようにする
+const MAX_MESSAGE_SIZE: usize = 1 << 24;
 
 pub struct TCP {
     sockets: RwLock<HashMap<SockID, Socket>>,
-    event_condvar: (Mutex<Option<TCPEvent>>, Condvar),
-}
-
-#[derive(Debug, Clone, PartialEq)]
-struct TCPEvent {
-    sock_id: SockID,
-    kind: TCPEventKind,
+    // ソケットごとの未処理のイベントのキュー
+    events: (Mutex<HashMap<SockID, EventQueue>>, Condvar),
+    // ACKなどで送信ウィンドウが更新されるたびにインクリメントされる世代番号
+    window_condvar: (Mutex<u64>, Condvar),
+    timer_wheel: Arc<Mutex<TimerWheel>>,
+    // スタック全体のパケットの統計情報
+    counters: Arc<GlobalCounters>,
+    // 受信スレッドとタイマスレッドを動かし続けるか、shutdownで落とす
+    running: AtomicBool,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+    // 新しく作るソケットのバッファサイズ
+    buffer_sizes: BufferSizes,
+    // 初期シーケンス番号の生成に使う秘密鍵、スタックの起動時に一度だけ生成する
+    isn_secret: u128,
+    // PORT_OWNERSでポートの持ち主を識別するためのID
+    instance_id: u64,
+    // 対応する接続が無いセグメントへRSTを返す送信先
+    // アドレスファミリごとに1つ、undetermined_addrのアドレスをキーにして、初めて使うときに開く
+    reset_senders: Mutex<HashMap<IpAddr, Box<dyn SegmentSender>>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -38,37 +90,252 @@ pub enum TCPEventKind {
     Acked,
     DataArrived,
     ConnectionClosed,
+    ConnectionReset,
+    ConnectionAborted,
+}
+
+// 接続確立後に折衝されたパラメータ
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionParams {
+    pub mss: usize,
+    pub send_window: u32,
+    pub recv_window: u32,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+    pub timestamps: bool,
+}
+
+// 接続確立時点のパラメータ(LinuxのTCP_INFO相当)
+// 確立後に変化する値も、確立した瞬間の値のまま保持する
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpInfo {
+    pub remote_addr: IpAddr,
+    pub remote_port: u16,
+    pub mss: usize,
+    pub rto: Duration,
+    pub send_window: u32,
+    pub recv_window: u32,
+    pub established_at: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TCPError {
+    // ドレイン中のlistenソケットのキューが空になった
+    Drained,
+    // 相手からRSTを受信して接続が破棄された
+    ConnectionReset,
+    // 再送の上限に達して接続を諦めた
+    ConnectionAborted,
+    // 接続が確立していないソケットに対してデータの送受信を行おうとした
+    NotConnected,
+    // 存在しない(削除済みの)ソケットを指定した
+    InvalidSocket(SockID),
+    // ポートが同じプロセス内の別のTCPインスタンスで使われている
+    AddrInUse(u16),
+    // shutdownでスタックが停止した
+    Shutdown,
+}
+
+// 輻輳制御のフェーズ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionState {
+    SlowStart,
+    CongestionAvoidance,
+    FastRecovery,
+}
+
+// 受信したACK番号の分類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AckResult {
+    // unacked_seqが進んだ
+    Advanced,
+    // unacked_seqと同じで進展が無い、重複ACKの候補
+    Duplicate,
+    // unacked_seqより前を指す古いACK
+    Old,
+    // まだ送信していないseqに対するACK
+    Unsent,
+}
+
+// ソケットごとの未処理のイベント
+// 最後にイベントが発行された時刻は、削除済みのソケットのイベントを掃除する時期の判断に使う
+struct EventQueue {
+    kinds: VecDeque<TCPEventKind>,
+    published_at: SystemTime,
+}
+
+// スタック全体のパケットの統計情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalStats {
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+    pub received_packets: u64,
+    pub received_bytes: u64,
+    pub retransmitted_packets: u64,
+}
+
+// 接続品質の推定値
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkQuality {
+    // 送信したセグメントのうち再送したセグメントの割合
+    pub loss_rate: f64,
+    // ACKされたバイト数から求めた実効スループット(bytes/sec)
+    pub throughput: f64,
 }
 
 impl TCP {
     pub fn new() -> Arc<Self> {
+        Self::spawn(BufferSizes::default())
+    }
+
+    // 送信・受信バッファのサイズを指定して作成する
+    // 低レイテンシなら小さく、高スループットなら大きくするなど用途に合わせて選べる
+    pub fn with_buffer_sizes(
+        send_buffer_size: usize,
+        recv_buffer_size: usize,
+    ) -> Result<Arc<Self>> {
+        let buffer_sizes = BufferSizes {
+            send: send_buffer_size,
+            recv: recv_buffer_size,
+        };
+        buffer_sizes.validate()?;
+
+        Ok(Self::spawn(buffer_sizes))
+    }
+
+    fn spawn(buffer_sizes: BufferSizes) -> Arc<Self> {
         let sockets = RwLock::new(HashMap::new());
         let tcp = Arc::new(Self {
             sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
+            events: (Mutex::new(HashMap::new()), Condvar::new()),
+            window_condvar: (Mutex::new(0), Condvar::new()),
+            timer_wheel: Arc::new(Mutex::new(TimerWheel::new(TIMER_TICK, TIMER_WHEEL_SLOTS))),
+            counters: Arc::new(GlobalCounters::default()),
+            running: AtomicBool::new(true),
+            threads: Mutex::new(Vec::new()),
+            buffer_sizes,
+            isn_secret: rand::thread_rng().gen(),
+            instance_id: NEXT_INSTANCE_ID.fetch_add(1, Ordering::Relaxed),
+            reset_senders: Mutex::new(HashMap::new()),
         });
 
-        let cloned_tcp = tcp.clone();
+        // スレッドがArcを持ち続けるとTCPがdropされなくなるので、Weakで参照する
+        let weak_tcp = Arc::downgrade(&tcp);
         // 別スレッドで受信ハンドラの処理を行うようにする
         // スリーウェイハンドシェイクのSYNACKの処理もここで行う
-        std::thread::spawn(move || {
-            cloned_tcp.receive_handler().unwrap();
+        let receiver = std::thread::spawn(move || {
+            if let Err(error) = Self::receive_handler(weak_tcp) {
+                error!("recv thread stopped: {:?}", error);
+            }
         });
 
-        let cloned_tcp = tcp.clone();
+        // IPv6のセグメントは別のチャネルで受信する
+        // IPv6が使えない環境ではチャネルを開けないので、IPv4だけで動作を続ける
+        let weak_tcp = Arc::downgrade(&tcp);
+        let receiver_v6 = std::thread::spawn(move || {
+            if let Err(error) = Self::receive_handler_v6(weak_tcp) {
+                warn!("ipv6 is unavailable: {:?}", error);
+            }
+        });
+
+        // 経路のMTUを超えたセグメントに対するICMPを受信する
+        // ICMPを受信できなくても、MTUブラックホールの検出でセグメントを小さくできる
+        let weak_tcp = Arc::downgrade(&tcp);
+        let icmp_receiver = std::thread::spawn(move || {
+            if let Err(error) = Self::receive_icmp_handler(weak_tcp) {
+                warn!("icmp is unavailable: {:?}", error);
+            }
+        });
+        let weak_tcp = Arc::downgrade(&tcp);
+        let icmp_receiver_v6 = std::thread::spawn(move || {
+            if let Err(error) = Self::receive_icmp_handler_v6(weak_tcp) {
+                warn!("icmpv6 is unavailable: {:?}", error);
+            }
+        });
+
+        let weak_tcp = Arc::downgrade(&tcp);
         //再送管理用のタイマスレッド
-        std::thread::spawn(move || {
-            cloned_tcp.timer();
+        let timer = std::thread::spawn(move || {
+            Self::timer(weak_tcp);
         });
 
+        tcp.threads.lock().unwrap().extend([
+            receiver,
+            receiver_v6,
+            icmp_receiver,
+            icmp_receiver_v6,
+            timer,
+        ]);
+
         tcp
     }
 
+    // RFC6528に従って初期シーケンス番号を選ぶ
+    // ISN = M + F(4-tuple, secret)、Mは4μsごとに1増えるクロック
+    // 同じ4-tupleでは時間とともに増えるので前の接続のseqと重なりにくく、
+    // secretを知らない第三者からは他の接続のISNを観測しても予測できない
+    fn initial_seq(&self, socket: &Socket) -> u32 {
+        let mut hasher = DefaultHasher::new();
+        self.isn_secret.hash(&mut hasher);
+        socket.local_addr.hash(&mut hasher);
+        socket.local_port.hash(&mut hasher);
+        socket.remote_addr.hash(&mut hasher);
+        socket.remote_port.hash(&mut hasher);
+        let offset = hasher.finish() as u32;
+
+        let clock = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros()
+            / 4;
+
+        (clock as u32).wrapping_add(offset)
+    }
+
+    // 受信スレッドとタイマスレッドを停止し、全てのソケットを破棄する
+    // TCPがdropされたときも呼ばれる
+    // 相手への通知は行わないので、相手側の接続はタイムアウトで終了する
+    // connect・recv・closeなどで待機中のスレッドは起こされ、Shutdownエラーになる
+    pub fn shutdown(&self) -> Result<()> {
+        if !self.running.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let threads = mem::take(&mut *self.threads.lock().unwrap());
+        for thread in threads {
+            // 受信・タイマスレッドが最後の参照を手放してdropした場合は、そのスレッド自身でここに来る
+            // 自分自身はjoinできないので、このまま抜けて終了させる
+            if thread.thread().id() == thread::current().id() {
+                continue;
+            }
+            thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("tcp thread panicked"))?;
+        }
+
+        let mut table = self.sockets.write().unwrap();
+        let sock_ids: Vec<_> = table.keys().copied().collect();
+        for sock_id in sock_ids {
+            remove_socket(&mut table, sock_id);
+        }
+        drop(table);
+
+        // イベント待ちのスレッドを起こす
+        // 待機側は停止フラグの確認とwaitをロックを握ったまま行うので、ロックを取ってから通知する
+        let (lock, cvar) = &self.events;
+        let events = lock.lock().unwrap();
+        cvar.notify_all();
+        drop(events);
+        self.notify_window_update();
+
+        Ok(())
+    }
+
+    // 同じプロセス内の他のインスタンスとも重ならないポートを選ぶ
     fn select_unused_port(&self, rng: &mut ThreadRng) -> Result<u16> {
         for _ in 0..(PORT_RANGE.end - PORT_RANGE.start) {
             let local_port = rng.gen_range(PORT_RANGE);
-            let table = self.sockets.read().unwrap();
-            if table.keys().all(|k| local_port != k.2) {
+            if !PORT_OWNERS.lock().unwrap().contains_key(&local_port) {
                 return Ok(local_port);
             }
         }
@@ -76,7 +343,78 @@ impl TCP {
         anyhow::bail!("no available port found.");
     }
 
-    pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockID> {
+    // ポートをこのインスタンスの持ち物として登録する
+    // 既にこのインスタンスが持っているポートなら何もしない
+    fn claim_port(&self, port: u16) -> Result<()> {
+        let mut owners = PORT_OWNERS.lock().unwrap();
+        match owners.get(&port) {
+            Some(&owner) if owner != self.instance_id => Err(TCPError::AddrInUse(port).into()),
+            _ => {
+                owners.insert(port, self.instance_id);
+                Ok(())
+            }
+        }
+    }
+
+    // このインスタンス宛てのセグメントか
+    fn owns_port(&self, port: u16) -> bool {
+        PORT_OWNERS.lock().unwrap().get(&port) == Some(&self.instance_id)
+    }
+
+    // addrにはIpv4AddrとIpv6Addrのどちらも渡せる
+    pub fn connect(&self, addr: impl Into<IpAddr>, port: u16) -> Result<SockID> {
+        let sock_id = self.send_syn(addr.into(), port)?;
+
+        // コネクションが確立されるまで待機
+        // 接続先から帰ってきたSYNACKの処理などはnew()で作成した受信ハンドラのスレッドで実施する。
+        // 受信ハンドラ内でConnectionCompletedが送信されるので、それまで待機することになる。
+        self.wait_connected(sock_id, None)?;
+        Ok(sock_id)
+    }
+
+    // connectと同様だが、timeout以内に接続が確立しなければ諦めてエラーを返す
+    // 応答の無いホストに対してconnectが永久に返らなくなるのを避けられる
+    pub fn connect_timeout(
+        &self,
+        addr: impl Into<IpAddr>,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<SockID> {
+        let sock_id = self.send_syn(addr.into(), port)?;
+        self.wait_connected(sock_id, Some(SystemTime::now() + timeout))?;
+        Ok(sock_id)
+    }
+
+    // SYNを送ったソケットの接続が、deadlineまでに確立するまで待機する
+    // 確立しなかった場合、呼び出し元はソケットIDを受け取れずcloseできないので、
+    // ソケットをテーブルから削除してエラーを返す
+    fn wait_connected(&self, sock_id: SockID, deadline: Option<SystemTime>) -> Result<()> {
+        let completed = self.wait_event_until(sock_id, TCPEventKind::ConnectionCompleted, deadline);
+        let mut table = self.sockets.write().unwrap();
+        match completed {
+            Ok(true) => return Ok(()),
+            Ok(false) => {}
+            Err(error) => {
+                remove_socket(&mut table, sock_id);
+                return Err(error);
+            }
+        }
+
+        // タイムアウトとイベントの発行がすれ違った場合は、確立済みの接続をそのまま返す
+        let established = table
+            .get(&sock_id)
+            .is_some_and(|socket| socket.status != TcpStatus::SynSent);
+        if established {
+            return Ok(());
+        }
+
+        // 半開きのソケットを削除して、再送キューに残ったSYNも破棄する
+        remove_socket(&mut table, sock_id);
+        anyhow::bail!("connection timed out: {}:{}", sock_id.1, sock_id.3);
+    }
+
+    // SYN_SENTのソケットを作ってSYNを送信する
+    fn send_syn(&self, addr: IpAddr, port: u16) -> Result<SockID> {
         let mut rng = rand::thread_rng();
         let mut socket = Socket::new(
             get_source_addr_to(addr)?,
@@ -84,37 +422,48 @@ impl TCP {
             self.select_unused_port(&mut rng)?,
             port,
             TcpStatus::SynSent,
+            self.buffer_sizes,
         )?;
+        self.attach(&mut socket);
 
-        socket.send_param.initial_seq = rng.gen_range(1..1 << 31);
-        socket.send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[])?;
+        // SYNACKを受け取る前にポートを登録しておかないと、他のインスタンスに処理されてしまう
+        let mut table = self.sockets.write().unwrap();
+        self.claim_port(socket.local_port)?;
+        socket.send_param.initial_seq = self.initial_seq(&socket);
+        let options = socket.syn_options();
+        if let Err(error) = socket.send_tcp_packet_with_options(
+            socket.send_param.initial_seq,
+            0,
+            tcpflags::SYN,
+            &options,
+            &[],
+        ) {
+            release_port_if_unused(&table, socket.local_port);
+            return Err(error);
+        }
         socket.send_param.unacked_seq = socket.send_param.initial_seq;
-        socket.send_param.next = socket.send_param.initial_seq + 1;
+        socket.send_param.next = socket.send_param.initial_seq.wrapping_add(1);
 
-        let mut table = self.sockets.write().unwrap();
         let sock_id = socket.get_sock_id();
         table.insert(sock_id, socket);
 
-        drop(table);
-
-        // コネクションが確立されるまで待機
-        // 接続先から帰ってきたSYNACKの処理などはnew()で作成した受信ハンドラのスレッドで実施する。
-        // 受信ハンドラ内でConnectionCompletedが送信されるので、それまで待機することになる。
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
-
         Ok(sock_id)
     }
 
-    pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
+    // local_addrのアドレスファミリの接続だけを受け付ける
+    pub fn listen(&self, local_addr: impl Into<IpAddr>, local_port: u16) -> Result<SockID> {
+        let local_addr = local_addr.into();
         let socket = Socket::new(
             local_addr,
-            UNDETERMINED_IP_ADDR,
+            undetermined_addr(local_addr),
             local_port,
             UNDETERMINED_PORT,
             TcpStatus::Listen,
+            self.buffer_sizes,
         )?;
 
         let mut lock = self.sockets.write().unwrap();
+        self.claim_port(local_port)?;
         let sock_id = socket.get_sock_id();
         lock.insert(sock_id, socket);
 
@@ -122,648 +471,4532 @@ impl TCP {
     }
 
     pub fn accept(&self, sock_id: SockID) -> Result<SockID> {
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
+        loop {
+            let mut table = self.sockets.write().unwrap();
+            let socket = table
+                .get_mut(&sock_id)
+                .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+            if let Some(connected_socket) = socket.connected_connection_queue.pop_front() {
+                return Ok(connected_socket);
+            }
 
+            // ドレイン中はキューに残った接続を返しきったら終了を通知する
+            if socket.draining {
+                return Err(TCPError::Drained.into());
+            }
+
+            // 複数のスレッドがacceptしている場合、1つの接続完了イベントは1スレッドしか受け取れない。
+            // イベントを受け取れなかったスレッドも定期的にキューを確認して残りの接続を受け取る
+            drop(table);
+            self.wait_event_timeout(
+                sock_id,
+                TCPEventKind::ConnectionCompleted,
+                ACCEPT_RECHECK_INTERVAL,
+            )?;
+        }
+    }
+
+    // listenソケットで新規接続の受理を止める
+    // 既に受理済みの接続はacceptで取り出せ、closeも通常通り行える
+    pub fn drain(&self, sock_id: SockID) -> Result<()> {
         let mut table = self.sockets.write().unwrap();
-        Ok(table
+        let socket = table
             .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?
-            .connected_connection_queue
-            .pop_front()
-            .context("no connected socket")?)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+        if socket.status != TcpStatus::Listen {
+            anyhow::bail!("not a listening socket: {:?}", sock_id);
+        }
+
+        socket.draining = true;
+        drop(table);
+
+        // accept待ちのスレッドを起こしてドレイン状態を確認させる
+        self.publish_event(sock_id, TCPEventKind::ConnectionCompleted);
+
+        Ok(())
     }
 
     pub fn send(&self, sock_id: SockID, buffer: &[u8]) -> Result<()> {
+        self.send_segments(sock_id, buffer, false)
+    }
+
+    // 送信待機をせずにbufferを即座に送る
+    // bufferの最後のセグメントにはPSHフラグを立て、受信側にもすぐアプリへ渡すよう促す
+    pub fn send_push(&self, sock_id: SockID, buffer: &[u8]) -> Result<()> {
+        self.send_segments(sock_id, buffer, true)
+    }
+
+    fn send_segments(&self, sock_id: SockID, buffer: &[u8], push: bool) -> Result<()> {
+        // メッセージモードでは長さヘッダを付けて、受信側がsendの単位で取り出せるようにする
+        // メッセージの末尾にはPSHを立てて、受信側がすぐに取り出せるようにする
+        let message_mode = self
+            .sockets
+            .read()
+            .unwrap()
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .message_mode;
+        let framed;
+        let (buffer, push) = if message_mode && !buffer.is_empty() {
+            framed = frame_message(buffer)?;
+            (&framed[..], true)
+        } else {
+            (buffer, push)
+        };
+
+        // Nagleで保留していたデータは、新しいデータより先に送る
+        let pending = mem::take(
+            &mut self
+                .sockets
+                .write()
+                .unwrap()
+                .get_mut(&sock_id)
+                .ok_or(TCPError::InvalidSocket(sock_id))?
+                .pending,
+        );
+        let joined;
+        let buffer = if pending.is_empty() {
+            buffer
+        } else {
+            joined = [&pending[..], buffer].concat();
+            &joined[..]
+        };
+
         let mut cursor = 0;
         while cursor < buffer.len() {
             let mut table = self.sockets.write().unwrap();
-            let mut socket = table
+            let socket = table
                 .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))?;
-            let send_size = cmp::min(
-                MSS,
-                cmp::min(socket.send_param.remain() as usize, buffer.len() - cursor),
-            );
-
-            if send_size == 0 || socket.last_time_window_probe.is_some() {
+                .ok_or(TCPError::InvalidSocket(sock_id))?;
+            if socket.aborted {
+                return Err(TCPError::ConnectionAborted.into());
+            }
+            if !is_connected(&socket.status) {
+                return Err(TCPError::NotConnected.into());
+            }
+            if socket.last_time_window_probe.is_some() {
+                let generation = self.window_generation();
                 drop(table);
-                thread::sleep(Duration::from_millis(1));
+                self.wait_window_update(generation);
                 continue;
             }
 
-            dbg!("current window size", socket.send_param.window);
+            socket.log(
+                Level::Trace,
+                format_args!("current window size: {}", socket.send_param.window),
+            );
 
-            socket.sent_times.push_back(SentTime {
-                sent_time: SystemTime::now(),
-                expected_ack: socket.send_param.next + send_size as u32,
-            });
+            // ウィンドウに空きがある分はロックを握ったまままとめてセグメント化して送信する
+            // ロックを長時間握らないように1回にまとめるセグメント数には上限を設ける
+            // pacingが有効な場合は1セグメントずつ間隔を空けて送る
+            let mut batched = 0;
+            let mut pacing_interval = None;
+            while cursor < buffer.len() && batched < SEND_BATCH_SIZE {
+                // 相手が受け取れるセグメントより大きく分割しないように、相手のMSSで上限を設ける
+                let send_size = cmp::min(
+                    socket.send_mss(),
+                    cmp::min(socket.send_capacity() as usize, buffer.len() - cursor),
+                );
+
+                // Nagle(RFC896): 未ACKのデータがある間はMSS未満のセグメントを送らずに保留し、
+                // 後続のsendのデータとまとめる
+                // 保留したデータは未ACKのデータが無くなったときかcloseの前に送る
+                if !push
+                    && !socket.options.nodelay
+                    && buffer.len() - cursor < socket.send_mss()
+                    && socket.send_param.used() > 0
+                {
+                    socket.log(
+                        Level::Trace,
+                        format_args!("nagle: hold small segment: {}", buffer.len() - cursor),
+                    );
+                    socket.pending.extend_from_slice(&buffer[cursor..]);
+                    cursor = buffer.len();
+                    break;
+                }
 
-            dbg!(socket.send_param.next - socket.send_param.initial_seq);
+                if send_size == 0 {
+                    break;
+                }
 
-            // RFC793によるとデータを送るときはACKが必要っぽい
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &buffer[cursor..cursor + send_size],
-            )?;
+                // RFC793によるとデータを送るときはACKが必要っぽい
+                let mut flag = tcpflags::ACK;
+                if push && cursor + send_size == buffer.len() {
+                    flag |= tcpflags::PSH;
+                }
+
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    flag,
+                    &buffer[cursor..cursor + send_size],
+                )?;
+
+                cursor += send_size;
+                socket.send_param.next = socket.send_param.next.wrapping_add(send_size as u32);
+                // 相手が広告したウィンドウを超えて送っていないこと
+                debug_assert!(socket.send_param.used() <= socket.send_param.window);
+                batched += 1;
+
+                pacing_interval = socket.pacing_interval();
+                if pacing_interval.is_some() {
+                    break;
+                }
+            }
 
-            cursor += send_size;
-            socket.send_param.next += send_size as u32;
-            dbg!(socket.send_param.next - socket.send_param.initial_seq);
+            if let Some(interval) = pacing_interval {
+                drop(table);
+                thread::sleep(interval);
+                continue;
+            }
 
-            // 1msだけtableのロックを解除して受信スレッドが扱えるようにする。
+            // 最大1msだけtableのロックを解除して受信スレッドが扱えるようにする。
             // 受信スレッドの処理によってwindowの空きを増やすのが狙い
+            // ACKでwindowが空いた場合は1msを待たずに送信を再開する
+            let generation = self.window_generation();
             drop(table);
-            thread::sleep(Duration::from_millis(1));
+            self.wait_window_update(generation);
         }
 
         Ok(())
     }
 
-    pub fn recv(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
+    // リスニングソケットの受信バッファのサイズを設定する
+    // acceptで生まれる全ての接続に反映される
+    pub fn set_recv_buffer_size(&self, sock_id: SockID, size: usize) -> Result<()> {
+        if size == 0 || size > MAX_WINDOW {
+            anyhow::bail!("invalid recv buffer size: {}", size);
+        }
+
         let mut table = self.sockets.write().unwrap();
-        let mut socket = table
+        let socket = table
             .get_mut(&sock_id)
-            .context(format!("no such socket {:?}", sock_id))?;
-        let mut received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
-        while received_size == 0 {
-            // すでにFINを受信している場合は待機せずスキップ
-            if matches!(
-                socket.status,
-                TcpStatus::CloseWait | TcpStatus::LastAck | TcpStatus::TimeWait
-            ) {
-                break;
-            }
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
 
-            drop(table);
-            dbg!("waiting incoming data");
-            self.wait_event(sock_id, TCPEventKind::DataArrived);
-            table = self.sockets.write().unwrap();
-            socket = table
-                .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))?;
-            received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
+        // データ受信後にバッファを確保し直すことはできないので、リスニングソケットにのみ設定できる
+        if socket.status != TcpStatus::Listen {
+            anyhow::bail!("not a listening socket: {:?}", sock_id);
         }
 
-        let copy_size = cmp::min(buffer.len(), received_size);
-        buffer[..copy_size].copy_from_slice(&socket.recv_buffer[..copy_size]);
-        socket.recv_buffer.copy_within(copy_size.., 0);
-        socket.recv_param.window += copy_size as u16;
+        let mut options = socket.options.clone();
+        options.recv_buffer_size = size;
+        socket.apply_options(options);
 
-        Ok(copy_size)
+        Ok(())
     }
 
-    pub fn close(&self, sock_id: SockID) -> Result<()> {
+    // 指定した接続だけ個別のログレベルでパケットの送受信や状態遷移のログを出力する
+    // log::max_level()より詳細なログもこの接続だけ出力するが、ロガー自身のフィルタは越えられない
+    // (詳しくはsocket::log_connection)
+    pub fn set_log_level(&self, sock_id: SockID, level: LevelFilter) -> Result<()> {
         let mut table = self.sockets.write().unwrap();
-        let mut socket = table
+        table
             .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?;
-        socket.send_tcp_packet(
-            socket.send_param.next,
-            socket.recv_param.next,
-            tcpflags::FIN | tcpflags::ACK,
-            &[],
-        )?;
-
-        socket.send_param.next += 1;
-
-        match socket.status {
-            TcpStatus::Established => {
-                socket.status = TcpStatus::FinWait1;
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
-            }
-            TcpStatus::CloseWait => {
-                socket.status = TcpStatus::LastAck;
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
-            }
-            TcpStatus::Listen => {
-                table.remove(&sock_id);
-            }
-            _ => {
-                // 上記以外の場合、何もしない
-            }
-        }
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .log_level = level;
 
         Ok(())
     }
 
-    fn receive_handler(&self) -> Result<()> {
-        dbg!("begin recv thread");
+    // 広告する受信ウィンドウをMSSの倍数に切り下げるか設定する
+    // 相手がフルサイズのセグメントを送れるようになる
+    pub fn set_round_window_to_mss(&self, sock_id: SockID, enabled: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .round_window_to_mss = enabled;
 
-        let (_, mut receiver) = transport::transport_channel(
-            65535,
-            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
-        )
-        .unwrap();
+        Ok(())
+    }
 
-        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
-        loop {
-            let (packet, remote_addr) = match packet_iter.next() {
-                Ok((p, r)) => (p, r),
-                Err(_) => continue,
-            };
+    // 受信バッファがhigh-waterを超えたときに、広告するウィンドウを徐々に絞るか設定する
+    // 急にゼロウィンドウを広告してprobe待ちになるのを避け、送信側に滑らかに減速させられる
+    pub fn set_soft_window(&self, sock_id: SockID, enabled: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .soft_window = enabled;
 
-            let local_addr = packet.get_destination();
-            let tcp_packet = match TcpPacket::new(packet.payload()) {
-                Some(p) => p,
-                None => continue,
-            };
+        Ok(())
+    }
 
-            let packet = TCPPacket::from(tcp_packet);
-            let remote_addr = match remote_addr {
-                IpAddr::V4(addr) => addr,
-                _ => continue,
-            };
+    // 再送を打ち切るまでの、初回送信からの経過時間を設定する
+    // 設定しない場合はMAX_TRANSMISSION回の送信で打ち切る
+    // RTOが大きい高遅延リンクでも、十分に粘ってから諦められる
+    pub fn set_retransmit_deadline(&self, sock_id: SockID, deadline: Duration) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .retransmit_deadline = Some(deadline);
 
-            let mut table = self.sockets.write().unwrap();
-            let socket = match table.get_mut(&SockID(
-                local_addr,
-                remote_addr,
-                packet.get_dst(),
-                packet.get_src(),
-            )) {
-                Some(socket) => socket,
-                None => match table.get_mut(&SockID(
-                    local_addr,
-                    UNDETERMINED_IP_ADDR,
-                    packet.get_dst(),
-                    UNDETERMINED_PORT,
-                )) {
-                    Some(socket) => socket,
-                    None => continue,
-                },
-            };
+        Ok(())
+    }
 
-            if !packet.is_correct_checksum(local_addr, remote_addr) {
-                dbg!("invalid checksum");
-                continue;
-            }
+    // 保持する順序逆転ブロックの数の上限を設定する
+    // 上限を超えた分は破棄して相手に再送させるので、歯抜けのセグメントでメモリを食い潰されない
+    pub fn set_max_out_of_order_blocks(&self, sock_id: SockID, max_blocks: usize) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .max_out_of_order_blocks = max_blocks;
 
-            let sock_id = socket.get_sock_id();
-            if let Err(error) = match socket.status {
-                TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
-                TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
-                TcpStatus::SynSent => self.synsent_handler(socket, &packet),
-                TcpStatus::Established => self.established_handler(socket, &packet),
-                TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
-                TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
-                _ => {
-                    dbg!("not implemented state");
-                    Ok(())
-                }
-            } {
-                dbg!(error);
-            }
-        }
+        Ok(())
     }
 
-    fn listen_handler(
-        &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
-        listening_socket_id: SockID,
-        packet: &TCPPacket,
-        remote_addr: Ipv4Addr,
-    ) -> Result<()> {
-        dbg!("listen handler");
-
-        if packet.get_flag() & tcpflags::ACK > 0 {
-            // 本来はSYNが来るはずなのでRSTを送るが、今回はRSTは排除するのでOk(())にしている
-            return Ok(());
+    // RTOの上限を設定する
+    // リスニングソケットに設定した場合はacceptで生まれる全ての接続に反映される
+    pub fn set_max_rto(&self, sock_id: SockID, max: Duration) -> Result<()> {
+        if max.is_zero() {
+            anyhow::bail!("invalid max rto: {:?}", max);
         }
 
-        let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+        socket.options.max_rto = max;
+        socket.rto.set_max(max);
 
-        if packet.get_flag() & tcpflags::SYN > 0 {
-            let mut connection_socket = Socket::new(
+        Ok(())
+    }
+
+    // sendの呼び出し単位をメッセージとして扱うメッセージモードを設定する
+    // 有効にすると送信データの前に長さヘッダを付けるので、通信する両方の接続で有効にし、
+    // 受信はrecvではなくrecv_messageで行う
+    // 空のsendはストリームの場合と同じく何も送らないので、空のメッセージは届かない
+    // リスニングソケットに設定した場合はacceptで生まれる全ての接続に反映される
+    pub fn set_message_mode(&self, sock_id: SockID, enabled: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .message_mode = enabled;
+
+        Ok(())
+    }
+
+    // keep-aliveを設定する
+    // idleの間相手から何も届かなければintervalごとにprobeを送り、
+    // count回送っても応答が無ければ接続を諦めてConnectionAbortedを通知する
+    pub fn set_keepalive(
+        &self,
+        sock_id: SockID,
+        idle: Duration,
+        interval: Duration,
+        count: u32,
+    ) -> Result<()> {
+        if idle.is_zero() || interval.is_zero() {
+            anyhow::bail!("invalid keepalive interval: {:?}, {:?}", idle, interval);
+        }
+
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+        socket.options.keepalive = Some(KeepAlive {
+            idle,
+            interval,
+            count,
+        });
+        socket.keepalive_probes = 0;
+        if let Some(deadline) = socket.keepalive_deadline() {
+            socket.schedule_timer(deadline);
+        }
+
+        Ok(())
+    }
+
+    // Nagleアルゴリズムを無効にするか設定する
+    // 無効にすると、未ACKのデータがあってもsendのデータを即座に送る
+    // 有効な間に保留していたデータは、無効にした時点で送る
+    pub fn set_nodelay(&self, sock_id: SockID, nodelay: bool) -> Result<()> {
+        self.sockets
+            .write()
+            .unwrap()
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .nodelay = nodelay;
+
+        if nodelay {
+            self.flush_pending(sock_id)?;
+        }
+
+        Ok(())
+    }
+
+    // セグメントの送出間隔を空けるpacingを設定する
+    // バーストによる経路上のキュー溢れを避ける
+    pub fn set_pacing(&self, sock_id: SockID, enabled: bool) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .pacing = enabled;
+
+        Ok(())
+    }
+
+    pub fn recv(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
+        let mut table = self.sockets.write().unwrap();
+        let mut socket = table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+        if !is_connected(&socket.status) {
+            return Err(TCPError::NotConnected.into());
+        }
+        let mut received_size = socket.received_size();
+        while received_size == 0 {
+            if socket.aborted {
+                return Err(TCPError::ConnectionAborted.into());
+            }
+
+            // すでにFINを受信している場合は待機せずスキップ
+            if matches!(
+                socket.status,
+                TcpStatus::CloseWait
+                    | TcpStatus::LastAck
+                    | TcpStatus::Closing
+                    | TcpStatus::TimeWait
+            ) {
+                break;
+            }
+
+            socket.log(Level::Trace, format_args!("waiting incoming data"));
+            drop(table);
+            self.wait_event(sock_id, TCPEventKind::DataArrived)?;
+            table = self.sockets.write().unwrap();
+            socket = table
+                .get_mut(&sock_id)
+                .ok_or(TCPError::InvalidSocket(sock_id))?;
+            received_size = socket.received_size();
+        }
+
+        let copy_size = cmp::min(buffer.len(), received_size);
+        socket.recv_buffer.read(0, &mut buffer[..copy_size]);
+        socket.recv_buffer.consume(copy_size);
+        // ウィンドウ0を広告していた場合、相手はprobeの間隔(数秒)ごとにしか送信を再開できないので、
+        // ウィンドウが開いたらすぐにウィンドウ更新のACKを送る
+        let was_closed = socket.advertised_window() >> socket.recv_param.scale == 0;
+        socket.recv_param.window += copy_size as u32;
+        let reopened = was_closed && socket.advertised_window() >> socket.recv_param.scale > 0;
+        // FINを受信済みなら相手はもう送ってこないので、ウィンドウを伝える必要は無い
+        if reopened
+            && matches!(
+                socket.status,
+                TcpStatus::Established | TcpStatus::FinWait1 | TcpStatus::FinWait2
+            )
+        {
+            socket.log(
+                Level::Debug,
+                format_args!("window reopened: {}", socket.recv_param.window),
+            );
+            if let Err(error) = socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            ) {
+                socket.log(
+                    Level::Warn,
+                    format_args!("failed to send window update: {:?}", error),
+                );
+            }
+        }
+
+        Ok(copy_size)
+    }
+
+    // Nagleで保留しているデータを、ACKを待たずに送る
+    fn flush_pending(&self, sock_id: SockID) -> Result<()> {
+        self.send_segments(sock_id, &[], true)
+    }
+
+    // メッセージモードで、相手が1回のsendで送ったデータを1メッセージとして取り出す
+    // メッセージが全て届くまで待機し、メッセージの境界でFINを受信していれば空のVecを返す
+    pub fn recv_message(&self, sock_id: SockID) -> Result<Vec<u8>> {
+        let message_mode = self
+            .sockets
+            .read()
+            .unwrap()
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .options
+            .message_mode;
+        if !message_mode {
+            anyhow::bail!("not in message mode: {:?}", sock_id);
+        }
+
+        let mut header = [0; MESSAGE_HEADER_SIZE];
+        let size = self.recv(sock_id, &mut header)?;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        self.recv_exact(sock_id, &mut header[size..])?;
+
+        let message_size = u32::from_be_bytes(header) as usize;
+        if message_size > MAX_MESSAGE_SIZE {
+            anyhow::bail!("message too large: {}", message_size);
+        }
+
+        let mut message = vec![0; message_size];
+        self.recv_exact(sock_id, &mut message)?;
+
+        Ok(message)
+    }
+
+    // bufferが埋まるまでrecvを繰り返す
+    fn recv_exact(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let size = self.recv(sock_id, &mut buffer[filled..])?;
+            if size == 0 {
+                anyhow::bail!(
+                    "connection closed in the middle of a message: {:?}",
+                    sock_id
+                );
+            }
+            filled += size;
+        }
+
+        Ok(())
+    }
+
+    // 受信バッファにある受信済みのデータを全て取り出す
+    // recvと同様にデータが届くまで待機し、FIN受信後にデータが無ければ空のVecを返す
+    pub fn recv_all(&self, sock_id: SockID) -> Result<Vec<u8>> {
+        let buffer_size = self
+            .sockets
+            .read()
+            .unwrap()
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .recv_buffer
+            .capacity();
+
+        let mut buffer = vec![0; buffer_size];
+        let size = self.recv(sock_id, &mut buffer)?;
+        buffer.truncate(size);
+
+        Ok(buffer)
+    }
+
+    pub fn close(&self, sock_id: SockID) -> Result<()> {
+        // Nagleで保留しているデータをFINより先に送る
+        // 再送を諦めた接続などで送れない場合も、以下でソケットの後始末をする
+        let flushed = self.flush_pending(sock_id);
+
+        let mut table = self.sockets.write().unwrap();
+        let mut socket = table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+        if let Err(error) = flushed {
+            socket.log(
+                Level::Warn,
+                format_args!("failed to flush pending data: {:?}", error),
+            );
+        }
+        // 再送を諦めた接続はFINも届かないので、そのまま破棄する
+        if socket.aborted {
+            socket.log(Level::Debug, format_args!("aborted & removed"));
+            remove_socket(&mut table, sock_id);
+            return Ok(());
+        }
+        match socket.status {
+            // リスニングソケットは相手がいないので、FINを送らずに破棄する
+            TcpStatus::Listen => {
+                remove_socket(&mut table, sock_id);
+                return Ok(());
+            }
+            TcpStatus::SynSent | TcpStatus::SynRcvd => return Err(TCPError::NotConnected.into()),
+            _ => {}
+        }
+
+        // FINもシーケンス番号を1つ消費するので、相手のウィンドウに空きが無いと受理されない
+        // ウィンドウが開くまでFINの送信を遅らせる
+        // ゼロウィンドウの間はtimerスレッドのprobeへのACKでウィンドウが開くのを待つ
+        while matches!(socket.status, TcpStatus::Established | TcpStatus::CloseWait)
+            && socket.send_param.remain() == 0
+        {
+            socket.log(
+                Level::Debug,
+                format_args!("delay fin until window opens: {}", socket.send_param.window),
+            );
+            let generation = self.window_generation();
+            drop(table);
+            self.wait_window_update(generation);
+            table = self.sockets.write().unwrap();
+            socket = table
+                .get_mut(&sock_id)
+                .ok_or(TCPError::InvalidSocket(sock_id))?;
+            if socket.aborted {
+                remove_socket(&mut table, sock_id);
+                return Err(TCPError::ConnectionAborted.into());
+            }
+        }
+
+        // ここで載せるACK番号はFINを送る時点のもの
+        // FinWait1の間に相手からデータが届いた場合、FINの再送時にSocket::retransmitが付け替える
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            tcpflags::FIN | tcpflags::ACK,
+            &[],
+        )?;
+
+        socket.send_param.next = socket.send_param.next.wrapping_add(1);
+
+        match socket.status {
+            TcpStatus::Established => {
+                socket.status = TcpStatus::FinWait1;
+                drop(table);
+                self.wait_event(sock_id, TCPEventKind::ConnectionClosed)?;
+                let mut table = self.sockets.write().unwrap();
+                // TimeWaitに遷移した場合は、遅れて届くセグメントに備えて2MSLの間ソケットを残す
+                // 2MSL経過後にtimerスレッドが削除する
+                match table.get(&sock_id) {
+                    Some(socket) if socket.status == TcpStatus::TimeWait => {
+                        socket.log(Level::Debug, format_args!("closed & waiting 2msl"));
+                    }
+                    _ => {
+                        if let Some(socket) = remove_socket(&mut table, sock_id) {
+                            socket.log(Level::Debug, format_args!("closed & removed"));
+                        }
+                    }
+                }
+            }
+            TcpStatus::CloseWait => {
+                socket.status = TcpStatus::LastAck;
+                drop(table);
+                self.wait_event(sock_id, TCPEventKind::ConnectionClosed)?;
+                let mut table = self.sockets.write().unwrap();
+                if let Some(socket) = remove_socket(&mut table, sock_id) {
+                    socket.log(Level::Debug, format_args!("closed & removed"));
+                }
+            }
+            _ => {
+                // 上記以外の場合、何もしない
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn connection_params(&self, sock_id: SockID) -> Result<ConnectionParams> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+        if matches!(
+            socket.status,
+            TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcvd
+        ) {
+            anyhow::bail!("connection is not established: {}", socket.status);
+        }
+
+        Ok(ConnectionParams {
+            mss: socket.send_mss(),
+            send_window: socket.send_param.window,
+            recv_window: socket.recv_param.window,
+            window_scale: socket
+                .peer_options
+                .window_scale
+                .map(|_| socket.send_param.scale),
+            sack_permitted: socket.peer_options.sack_permitted,
+            timestamps: socket.peer_options.timestamp.is_some(),
+        })
+    }
+
+    // 接続確立時点の折衝MSS・RTO・ウィンドウ・相手アドレスを取得する
+    // connect/acceptの直後に呼べば、接続の初期パラメータをログに残したり検証したりできる
+    pub fn tcp_info(&self, sock_id: SockID) -> Result<TcpInfo> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+        socket
+            .tcp_info
+            .clone()
+            .context(format!("connection is not established: {}", socket.status))
+    }
+
+    // デバッグ用に接続のシーケンス空間を図示する
+    pub fn seq_diagram(&self, sock_id: SockID) -> Result<String> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+        Ok(socket.seq_diagram())
+    }
+
+    // 相手がFINを送って送信を終えているか
+    // 相手の送信が終わっていても、こちらからはcloseするまで送信できる(ハーフクローズ)
+    // 受信バッファに未読のデータが残っている場合があるので、recvが0を返すまで読み切ってよい
+    pub fn is_peer_closed(&self, sock_id: SockID) -> Result<bool> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+        Ok(matches!(
+            socket.status,
+            TcpStatus::CloseWait | TcpStatus::LastAck | TcpStatus::Closing | TcpStatus::TimeWait
+        ))
+    }
+
+    // 現在の輻輳制御のフェーズを取得する
+    pub fn congestion_state(&self, sock_id: SockID) -> Result<CongestionState> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+        Ok(socket.congestion_state)
+    }
+
+    pub fn link_quality(&self, sock_id: SockID) -> Result<LinkQuality> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+        let stats = &socket.stats;
+
+        let loss_rate = if stats.sent_segments == 0 {
+            0.0
+        } else {
+            stats.retransmitted_segments as f64 / stats.sent_segments as f64
+        };
+
+        let elapsed = stats.since.elapsed().unwrap_or_default().as_secs_f64();
+        let throughput = if elapsed == 0.0 {
+            0.0
+        } else {
+            stats.acked_bytes as f64 / elapsed
+        };
+
+        Ok(LinkQuality {
+            loss_rate,
+            throughput,
+        })
+    }
+
+    // 接続の統計情報をゼロクリアし、この時点からの統計を取り直す
+    // 定期的に呼び出せば区間ごとのスループットやロス率を計測できる
+    pub fn reset_stats(&self, sock_id: SockID) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        table
+            .get_mut(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?
+            .stats = SocketStats::new();
+
+        Ok(())
+    }
+
+    // スタック全体のパケットの送受信数・バイト数・再送数を取得する
+    // バイト数はTCPヘッダを含まないペイロードの長さ
+    pub fn global_stats(&self) -> GlobalStats {
+        let counters = &self.counters;
+        GlobalStats {
+            sent_packets: counters.sent_packets.load(Ordering::Relaxed),
+            sent_bytes: counters.sent_bytes.load(Ordering::Relaxed),
+            received_packets: counters.received_packets.load(Ordering::Relaxed),
+            received_bytes: counters.received_bytes.load(Ordering::Relaxed),
+            retransmitted_packets: counters.retransmitted_packets.load(Ordering::Relaxed),
+        }
+    }
+
+    // ソケットをこのスタックのタイミングホイールと統計情報に結びつける
+    fn attach(&self, socket: &mut Socket) {
+        socket.timer_wheel = Some(self.timer_wheel.clone());
+        socket.global_counters = Some(self.counters.clone());
+    }
+
+    // 対応する接続が無いセグメントに対してRSTを返す
+    // 受信スレッドで届いたセグメントごとに呼ばれるので、raw socketはインスタンスで使い回す
+    fn send_reset(
+        &self,
+        local_addr: IpAddr,
+        remote_addr: IpAddr,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        let Some(reset) = reset_segment(local_addr, remote_addr, packet) else {
+            return Ok(());
+        };
+
+        let mut senders = self.reset_senders.lock().unwrap();
+        let sender = match senders.entry(undetermined_addr(local_addr)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Box::new(open_sender(local_addr)?)),
+        };
+        sender
+            .send_to(&reset, remote_addr)
+            .with_context(|| format!("failed to send reset to {}", remote_addr))?;
+        trace!(
+            "sent reset to {}:{}: {}",
+            remote_addr,
+            reset.get_dst(),
+            tcpflags::flag_to_string(reset.get_flag())
+        );
+
+        Ok(())
+    }
+
+    // 接続をフリーズして、その状態をバイト列として取り出す
+    // フリーズした接続はテーブルから外れ、restore_connectionで復元するまでパケットを処理しない
+    pub fn serialize_connection(&self, sock_id: SockID) -> Result<Vec<u8>> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .ok_or(TCPError::InvalidSocket(sock_id))?;
+
+        if socket.status == TcpStatus::Listen {
+            anyhow::bail!("cannot serialize listening socket: {:?}", sock_id);
+        }
+
+        let data = snapshot::serialize(socket);
+        socket.log(Level::Debug, format_args!("frozen"));
+        remove_socket(&mut table, sock_id);
+
+        Ok(data)
+    }
+
+    // serialize_connectionで取り出した状態から接続を再開する
+    pub fn restore_connection(&self, data: &[u8]) -> Result<SockID> {
+        let mut socket = snapshot::deserialize(data)?;
+        let sock_id = socket.get_sock_id();
+        self.attach(&mut socket);
+        if let Some(deadline) = next_timer_deadline(&socket) {
+            socket.schedule_timer(deadline);
+        }
+
+        let mut table = self.sockets.write().unwrap();
+        if table.contains_key(&sock_id) {
+            anyhow::bail!("socket already exists: {:?}", sock_id);
+        }
+        self.claim_port(sock_id.2)?;
+        socket.log(Level::Debug, format_args!("restored"));
+        table.insert(sock_id, socket);
+
+        Ok(sock_id)
+    }
+
+    // バックグラウンドのスレッドから、動作中のTCPへの参照を取る
+    // TCPがdropされたかshutdownされていればNoneを返し、スレッドを終了させる
+    fn upgrade_running(weak: &Weak<Self>) -> Option<Arc<Self>> {
+        weak.upgrade()
+            .filter(|tcp| tcp.running.load(Ordering::Acquire))
+    }
+
+    // 受信を待っている間はTCPへの参照を持たないので、TCPがdropされるとスレッドも終了する
+    fn receive_handler(weak: Weak<Self>) -> Result<()> {
+        debug!("begin recv thread");
+
+        let (_, mut receiver) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
+        )
+        .context("failed to open recv channel")?;
+
+        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
+        // shutdownで停止できるように、タイムアウト付きで受信する
+        while Self::upgrade_running(&weak).is_some() {
+            let (packet, remote_addr) = match packet_iter.next_with_timeout(RECV_POLL_INTERVAL) {
+                Ok(Some((p, r))) => (p, r),
+                Ok(None) | Err(_) => continue,
+            };
+
+            let local_addr = IpAddr::V4(packet.get_destination());
+            if packet.payload().len() < TCP_HEADER_SIZE {
+                trace!("too short tcp packet: {}", packet.payload().len());
+                continue;
+            }
+
+            let tcp_packet = match TcpPacket::new(packet.payload()) {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let packet = match TCPPacket::try_from(tcp_packet) {
+                Ok(p) => p,
+                Err(error) => {
+                    debug!("malformed tcp packet from {}: {:?}", remote_addr, error);
+                    continue;
+                }
+            };
+            if !remote_addr.is_ipv4() {
+                continue;
+            }
+
+            match Self::upgrade_running(&weak) {
+                Some(tcp) => tcp.handle_segment(local_addr, remote_addr, packet),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    // IPv6のセグメントを受信する
+    // IPv6のraw socketはIPヘッダを渡さないので宛先アドレスが分からず、
+    // 相手のアドレスとポートが一致するソケットのローカルアドレスを宛先とみなす
+    fn receive_handler_v6(weak: Weak<Self>) -> Result<()> {
+        debug!("begin recv v6 thread");
+
+        let (_, mut receiver) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv6(IpNextHeaderProtocols::Tcp)),
+        )?;
+
+        let mut packet_iter = transport::tcp_packet_iter(&mut receiver);
+        while Self::upgrade_running(&weak).is_some() {
+            let (tcp_packet, remote_addr) = match packet_iter.next_with_timeout(RECV_POLL_INTERVAL)
+            {
+                Ok(Some((p, r))) => (p, r),
+                Ok(None) | Err(_) => continue,
+            };
+            if !remote_addr.is_ipv6() {
+                continue;
+            }
+
+            let packet = match TCPPacket::try_from(tcp_packet) {
+                Ok(p) => p,
+                Err(error) => {
+                    debug!("malformed tcp packet from {}: {:?}", remote_addr, error);
+                    continue;
+                }
+            };
+            let tcp = match Self::upgrade_running(&weak) {
+                Some(tcp) => tcp,
+                None => break,
+            };
+            let local_addr = match tcp.local_addr_v6(remote_addr, &packet) {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            tcp.handle_segment(local_addr, remote_addr, packet);
+        }
+
+        Ok(())
+    }
+
+    // Fragmentation Needed(RFC1191)を受信する
+    // Layer4のチャネルはIPヘッダを取り除いてICMPの部分を渡す
+    fn receive_icmp_handler(weak: Weak<Self>) -> Result<()> {
+        debug!("begin icmp recv thread");
+
+        let (_, mut receiver) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Icmp)),
+        )
+        .context("failed to open icmp recv channel")?;
+
+        let mut packet_iter = transport::icmp_packet_iter(&mut receiver);
+        while Self::upgrade_running(&weak).is_some() {
+            let packet = match packet_iter.next_with_timeout(RECV_POLL_INTERVAL) {
+                Ok(Some((p, _))) => p,
+                Ok(None) | Err(_) => continue,
+            };
+            let too_big = match icmp::parse_v4(packet.packet()) {
+                Some(too_big) => too_big,
+                None => continue,
+            };
+
+            match Self::upgrade_running(&weak) {
+                Some(tcp) => tcp.handle_too_big(too_big),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    // Packet Too Big(RFC8201)を受信する
+    fn receive_icmp_handler_v6(weak: Weak<Self>) -> Result<()> {
+        debug!("begin icmpv6 recv thread");
+
+        let (_, mut receiver) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv6(IpNextHeaderProtocols::Icmpv6)),
+        )?;
+
+        let mut packet_iter = transport::icmpv6_packet_iter(&mut receiver);
+        while Self::upgrade_running(&weak).is_some() {
+            let packet = match packet_iter.next_with_timeout(RECV_POLL_INTERVAL) {
+                Ok(Some((p, _))) => p,
+                Ok(None) | Err(_) => continue,
+            };
+            let too_big = match icmp::parse_v6(packet.packet()) {
+                Some(too_big) => too_big,
+                None => continue,
+            };
+
+            match Self::upgrade_running(&weak) {
+                Some(tcp) => tcp.handle_too_big(too_big),
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    // 経路のMTUが通知されたら接続のセグメントを小さくし、破棄されたセグメントをすぐに再送する
+    // ICMPは第三者でも偽造できるので、送信済みで未ACKのseqを含むものだけを受け入れる(RFC5927)
+    fn handle_too_big(&self, too_big: TooBig) {
+        if !self.owns_port(too_big.local_port) {
+            return;
+        }
+
+        let sock_id = SockID(
+            too_big.local_addr,
+            too_big.remote_addr,
+            too_big.local_port,
+            too_big.remote_port,
+        );
+        let mut table = self.sockets.write().unwrap();
+        let socket = match table.get_mut(&sock_id) {
+            Some(socket) if is_connected(&socket.status) => socket,
+            _ => return,
+        };
+        if seq::lt(too_big.seq, socket.send_param.unacked_seq)
+            || seq::geq(too_big.seq, socket.send_param.next)
+        {
+            socket.log(
+                Level::Trace,
+                format_args!("ignore icmp for unexpected seq: {}", too_big.seq),
+            );
+            return;
+        }
+
+        // MTUを通知してこない古いルータの場合は、今の半分を試す
+        let mss = too_big.mss().unwrap_or(socket.send_param.mss / 2);
+        if !socket.lower_path_mss(mss) {
+            return;
+        }
+        socket.log(
+            Level::Debug,
+            format_args!(
+                "path mtu updated: mtu={}, mss={}",
+                too_big.mtu, socket.send_param.mss
+            ),
+        );
+
+        let mut item = match socket.retransmission_queue.pop_front() {
+            Some(item) => item,
+            None => return,
+        };
+        if let Err(error) = socket.retransmit(&mut item) {
+            socket.log(
+                Level::Warn,
+                format_args!("failed to retransmit: {:?}", error),
+            );
+        }
+        socket.retransmission_queue.push_front(item);
+    }
+
+    // IPv6のセグメントの宛先のローカルアドレスを、対応するソケットから求める
+    // 対応する接続もlistenソケットも無ければ、RSTを返せるように経路表から求める
+    fn local_addr_v6(&self, remote_addr: IpAddr, packet: &TCPPacket) -> Option<IpAddr> {
+        if !self.owns_port(packet.get_dst()) {
+            return None;
+        }
+
+        let table = self.sockets.read().unwrap();
+        let connection = table.keys().find(|id| {
+            id.0.is_ipv6()
+                && id.1 == remote_addr
+                && id.2 == packet.get_dst()
+                && id.3 == packet.get_src()
+        });
+        let listening = || {
+            table
+                .keys()
+                .find(|id| id.0.is_ipv6() && id.2 == packet.get_dst() && id.3 == UNDETERMINED_PORT)
+        };
+        if let Some(id) = connection.or_else(listening) {
+            return Some(id.0);
+        }
+        drop(table);
+
+        get_source_addr_to(remote_addr).ok()
+    }
+
+    // 受信したセグメントを宛先のソケットの状態に応じて処理する
+    fn handle_segment(&self, local_addr: IpAddr, remote_addr: IpAddr, packet: TCPPacket) {
+        // 矛盾するフラグを持つパケットは、RSTも返さずに破棄する
+        if !tcpflags::is_valid_combination(packet.get_flag()) {
+            debug!(
+                "invalid flag combination: {}",
+                tcpflags::flag_to_string(packet.get_flag())
+            );
+            return;
+        }
+
+        // 同じプロセス内の別のインスタンスが使っているポート宛てのセグメントは、
+        // そのインスタンスが処理するので無視する
+        if !self.owns_port(packet.get_dst()) {
+            return;
+        }
+
+        let mut table = self.sockets.write().unwrap();
+        let connection_id = SockID(local_addr, remote_addr, packet.get_dst(), packet.get_src());
+        let listening_id = SockID(
+            local_addr,
+            undetermined_addr(local_addr),
+            packet.get_dst(),
+            UNDETERMINED_PORT,
+        );
+        // 接続は4-tupleで引くので、相手以外のアドレスやポートから届いたセグメントは接続に渡らない
+        let sock_id = if table.contains_key(&connection_id) {
+            connection_id
+        } else if table.contains_key(&listening_id) {
+            listening_id
+        } else {
+            // このインスタンスが使っているポート宛てで、対応する接続が無いセグメントにはRSTを返す
+            // raw socketでホスト宛ての全てのTCPパケットを受信しているので、
+            // 他のポート(カーネルのTCPなど)宛てのパケットにRSTを返してはいけない
+            if table.keys().any(|id| id.2 == packet.get_dst())
+                && packet.is_correct_checksum(local_addr, remote_addr)
+            {
+                if let Err(error) = self.send_reset(local_addr, remote_addr, &packet) {
+                    warn!("{:?}", error);
+                }
+            }
+            return;
+        };
+        let socket = table.get_mut(&sock_id).unwrap();
+
+        if !packet.is_correct_checksum(local_addr, remote_addr) {
+            socket.log(Level::Debug, format_args!("invalid checksum"));
+            return;
+        }
+
+        socket.trace_received(&packet);
+        self.counters.count_received(packet.payload().len());
+        socket.last_activity = SystemTime::now();
+        socket.keepalive_probes = 0;
+
+        // 以下のハンドラにはtableごと渡すものがあるので、エラーのログ用にログレベルを控えておく
+        let log_level = socket.log_level;
+        if packet.get_flag() & tcpflags::RST > 0 {
+            if let Err(error) = self.reset_handler(table, sock_id, &packet) {
+                log_connection(
+                    sock_id,
+                    log_level,
+                    Level::Warn,
+                    format_args!("failed to handle reset: {:?}", error),
+                );
+            }
+            return;
+        }
+
+        // 古いタイムスタンプのセグメントは破棄して、現在のACKを返す
+        if !socket.check_timestamp(&packet) {
+            socket.log(
+                Level::Debug,
+                format_args!("paws rejected: seq={}", packet.get_seq()),
+            );
+            if let Err(error) = socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            ) {
+                socket.log(Level::Warn, format_args!("failed to send ack: {:?}", error));
+            }
+            return;
+        }
+
+        if let Err(error) = match socket.status {
+            TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
+            TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
+            TcpStatus::SynSent => {
+                let result = self.synsent_handler(socket, &packet);
+                debug_assert_invariants(socket);
+                result
+            }
+            TcpStatus::Established => {
+                let result = self.established_handler(socket, &packet);
+                debug_assert_invariants(socket);
+                result
+            }
+            TcpStatus::CloseWait | TcpStatus::LastAck => {
+                let result = self.close_handler(socket, &packet);
+                debug_assert_invariants(socket);
+                result
+            }
+            TcpStatus::FinWait1 | TcpStatus::FinWait2 | TcpStatus::Closing => {
+                let result = self.finwait_handler(socket, &packet);
+                debug_assert_invariants(socket);
+                result
+            }
+            TcpStatus::TimeWait => self.timewait_handler(table, sock_id, &packet, remote_addr),
+        } {
+            log_connection(
+                sock_id,
+                log_level,
+                Level::Warn,
+                format_args!("failed to handle segment: {:?}", error),
+            );
+        }
+    }
+
+    fn listen_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        listening_socket_id: SockID,
+        packet: &TCPPacket,
+        remote_addr: IpAddr,
+    ) -> Result<()> {
+        let listening_socket = table.get_mut(&listening_socket_id).unwrap();
+        listening_socket.log(Level::Trace, format_args!("listen handler"));
+
+        if packet.get_flag() & tcpflags::ACK > 0 {
+            // 本来はSYNが来るはずなので、存在しない接続に対するACKとしてRSTを返す
+            let local_addr = listening_socket.local_addr;
+            return self.send_reset(local_addr, remote_addr, packet);
+        }
+
+        if listening_socket.draining {
+            listening_socket.log(Level::Debug, format_args!("listening socket is draining"));
+            return Ok(());
+        }
+
+        if packet.get_flag() & tcpflags::SYN > 0 {
+            let mut connection_socket = Socket::new(
                 listening_socket.local_addr,
                 remote_addr,
                 listening_socket.local_port,
                 packet.get_src(),
                 TcpStatus::SynRcvd,
+                self.buffer_sizes,
+            )?;
+            self.attach(&mut connection_socket);
+            // リスニングソケットに設定されたオプションを引き継ぐ
+            connection_socket.apply_options(listening_socket.options.clone());
+
+            connection_socket.recv_param.next = packet.get_seq().wrapping_add(1);
+            connection_socket.recv_param.initial_seq = packet.get_seq();
+            // 能動オープン側と折衝結果が食い違わないように、SYNのオプションを記録しておく
+            connection_socket.peer_options = PeerOptions::from_packet(packet);
+            connection_socket.negotiate_mss();
+            connection_socket.negotiate_window_scale();
+            connection_socket.negotiate_timestamp();
+            connection_socket.log(
+                Level::Debug,
+                format_args!("peer options: {:?}", connection_socket.peer_options),
+            );
+
+            connection_socket.send_param.initial_seq = self.initial_seq(&connection_socket);
+            connection_socket.send_param.window = u32::from(packet.get_window_size());
+            // SYNACKで自分のオプションを返す
+            let options = connection_socket.syn_options();
+            connection_socket.send_tcp_packet_with_options(
+                connection_socket.send_param.initial_seq,
+                connection_socket.recv_param.next,
+                tcpflags::SYN | tcpflags::ACK,
+                &options,
+                &[],
+            )?;
+
+            connection_socket.send_param.next =
+                connection_socket.send_param.initial_seq.wrapping_add(1);
+            connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
+            connection_socket.listening_socket = Some(listening_socket.get_sock_id());
+
+            connection_socket.log(
+                Level::Debug,
+                format_args!("status: listen -> {}", connection_socket.status),
+            );
+
+            table.insert(connection_socket.get_sock_id(), connection_socket);
+        }
+
+        Ok(())
+    }
+
+    // RSTを受信したら接続を破棄して、待機中のスレッドへ通知する
+    // 偽のRSTで接続を切られないように、受理するのは受信ウィンドウ内のseqを持つものだけにする
+    fn reset_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        let socket = table.get_mut(&sock_id).unwrap();
+        socket.log(Level::Trace, format_args!("reset handler"));
+
+        let acceptable = match socket.status {
+            TcpStatus::Listen => false,
+            // SYN_SENTでは送ったSYNに対するACKを持つ場合のみ受理する
+            TcpStatus::SynSent => {
+                packet.get_flag() & tcpflags::ACK > 0 && packet.get_ack() == socket.send_param.next
+            }
+            _ => {
+                let offset = packet.get_seq().wrapping_sub(socket.recv_param.next);
+                if socket.recv_param.window == 0 {
+                    offset == 0
+                } else {
+                    offset < socket.recv_param.window
+                }
+            }
+        };
+        if !acceptable {
+            socket.log(
+                Level::Debug,
+                format_args!("ignore unacceptable reset: seq={}", packet.get_seq()),
+            );
+            return Ok(());
+        }
+
+        socket.log(
+            Level::Debug,
+            format_args!("status: {} -> CLOSED (reset)", socket.status),
+        );
+        remove_socket(&mut table, sock_id);
+        self.publish_event(sock_id, TCPEventKind::ConnectionReset);
+
+        Ok(())
+    }
+
+    fn synrcvd_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+    ) -> Result<()> {
+        let socket = table.get_mut(&sock_id).unwrap();
+        socket.log(Level::Trace, format_args!("synrcvd handler"));
+
+        if packet.get_flag() & tcpflags::ACK > 0
+            && self.process_ack(socket, packet) == AckResult::Advanced
+        {
+            // packet.get_seq().wrapping_add(1)じゃなくても良い？
+            socket.recv_param.next = packet.get_seq();
+
+            socket.status = TcpStatus::Established;
+            socket.record_tcp_info();
+            socket.log(
+                Level::Debug,
+                format_args!("status: synrcvd -> {}", socket.status),
+            );
+
+            // listenソケットがハンドシェイク中にcloseされていれば、受け取り手はいない
+            if let Some(id) = socket.listening_socket {
+                let log_level = socket.log_level;
+                match table.get_mut(&id) {
+                    Some(ls) => {
+                        ls.connected_connection_queue.push_back(sock_id);
+                        self.publish_event(id, TCPEventKind::ConnectionCompleted);
+                    }
+                    None => log_connection(
+                        sock_id,
+                        log_level,
+                        Level::Debug,
+                        format_args!("listening socket already closed"),
+                    ),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn synsent_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        socket.log(Level::Trace, format_args!("synsent handler"));
+        if packet.get_flag() & tcpflags::ACK == 0 || packet.get_flag() & tcpflags::SYN == 0 {
+            return Ok(());
+        }
+
+        let ack = self.process_ack(socket, packet);
+        if matches!(ack, AckResult::Advanced | AckResult::Duplicate) {
+            socket.recv_param.next = packet.get_seq().wrapping_add(1);
+            socket.recv_param.initial_seq = packet.get_seq();
+            socket.send_param.window = u32::from(packet.get_window_size());
+            socket.peer_options = PeerOptions::from_packet(packet);
+            socket.negotiate_mss();
+            socket.negotiate_window_scale();
+            socket.negotiate_timestamp();
+            socket.log(
+                Level::Debug,
+                format_args!("peer options: {:?}", socket.peer_options),
+            );
+
+            if ack == AckResult::Advanced {
+                socket.status = TcpStatus::Established;
+                socket.record_tcp_info();
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+                socket.log(
+                    Level::Debug,
+                    format_args!("status: synsent -> {}", socket.status),
+                );
+                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
+            } else {
+                socket.status = TcpStatus::SynRcvd;
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+
+                socket.log(
+                    Level::Debug,
+                    format_args!("status: synsent -> {}", socket.status),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        socket.log(
+            Level::Trace,
+            format_args!(
+                "established handler: unacked_seq={}, ack={}",
+                socket.send_param.unacked_seq,
+                packet.get_ack()
+            ),
+        );
+        // 送信可能量が増えたかの判定に使う
+        let prev_remain = socket.send_capacity();
+
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            // ACKが立っていない受信パケットは破棄
+            return Ok(());
+        }
+
+        // 進展なしのうちRFC5681の条件を満たすものを重複ACKとして数え、fast retransmitの判定に使う
+        match self.process_ack(socket, packet) {
+            AckResult::Advanced => {
+                socket.dup_ack_count = 0;
+                if socket.recovery {
+                    socket.send_param.exit_fast_recovery();
+                    socket.recovery = false;
+                    socket.log(
+                        Level::Debug,
+                        format_args!("exit fast recovery: cwnd={}", socket.send_param.cwnd),
+                    );
+                } else {
+                    socket.send_param.grow_cwnd();
+                }
+                socket.congestion_state = if socket.send_param.in_slow_start() {
+                    CongestionState::SlowStart
+                } else {
+                    CongestionState::CongestionAvoidance
+                };
+            }
+            AckResult::Unsent => {
+                // 未送信セグメントに対するACKは破棄
+                socket.log(
+                    Level::Debug,
+                    format_args!(
+                        "discard ack for unsent data: next={}, ack={}",
+                        socket.send_param.next,
+                        packet.get_ack()
+                    ),
+                );
+                return Ok(());
+            }
+            // ウィンドウ更新の判定に使うので、send_param.windowを更新する前に判定する
+            AckResult::Duplicate if is_duplicate_ack(socket, packet) => {
+                socket.dup_ack_count = socket.dup_ack_count.saturating_add(1);
+                socket.log(
+                    Level::Debug,
+                    format_args!(
+                        "duplicate ack: ack={}, count={}",
+                        packet.get_ack(),
+                        socket.dup_ack_count
+                    ),
+                );
+                if socket.recovery {
+                    socket.send_param.inflate_cwnd();
+                } else if socket.dup_ack_count == DUP_ACK_THRESHOLD {
+                    socket.log(
+                        Level::Debug,
+                        format_args!("duplicate ack threshold reached: ack={}", packet.get_ack()),
+                    );
+                    socket.send_param.enter_fast_recovery();
+                    socket.recovery = true;
+                    socket.congestion_state = CongestionState::FastRecovery;
+                    socket.log(
+                        Level::Debug,
+                        format_args!("enter fast recovery: cwnd={}", socket.send_param.cwnd),
+                    );
+                    // 送信に失敗してもRTOによる再送に任せて、ACKの処理は続ける
+                    if let Err(error) = self.fast_retransmit(socket) {
+                        socket.log(
+                            Level::Warn,
+                            format_args!("failed to fast retransmit: {:?}", error),
+                        );
+                    }
+                }
+            }
+            AckResult::Duplicate | AckResult::Old => {}
+        }
+
+        // SACKは累積ACKが進まない重複ACKに載ってくることが多いので、ACKの進展に関わらず反映する
+        socket.mark_sacked(packet);
+
+        socket.last_time_ack_received = SystemTime::now();
+
+        let window = socket.send_param.scaled_window(packet.get_window_size());
+        if socket.send_param.window != window {
+            socket.log(Level::Debug, format_args!("resize window size: {}", window));
+        }
+
+        // timerスレッドが自発的にprobeを開始した場合はwindowサイズが変化しないこともあるので、
+        // windowの変化に関わらずprobeモードの解除を判定する
+        let mut window_reopened = false;
+        if window == 0 && socket.last_time_window_probe.is_none() {
+            socket.log(Level::Debug, format_args!("transit into window probe mode"));
+            socket.last_time_window_probe = Some(SystemTime::now());
+            socket.schedule_timer(SystemTime::now() + WINDOW_PROBE_DURATION);
+        } else if window > 0 && socket.last_time_window_probe.is_some() {
+            socket.log(Level::Debug, format_args!("transit into normal mode"));
+            socket.last_time_window_probe = None;
+            window_reopened = true;
+        }
+
+        socket.send_param.window = window;
+
+        // ACKでunacked_seqが進んだり相手のウィンドウが広がったりして送信可能量が増えたら、
+        // ポーリングの1msを待たずにsendを起こす
+        // send側はtableのロックを取り直してから送るので、ここでの状態更新は全て反映される
+        // probeモード中のsendは送信可能量に関わらず待機しているので、解除した時点でも起こす
+        if window_reopened || socket.send_capacity() > prev_remain {
+            self.notify_window_update();
+        }
+
+        if let Err(error) = self.send_pending(socket) {
+            socket.log(
+                Level::Warn,
+                format_args!("failed to send pending data: {:?}", error),
+            );
+        }
+
+        // 受信済みの範囲より前のseqを持つ空のセグメント(keep-aliveやwindow probe)には、
+        // 現在のACKを返して相手に生存とウィンドウを伝える(RFC793)
+        if packet.payload().is_empty() && seq::lt(packet.get_seq(), socket.recv_param.next) {
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+        }
+
+        if !packet.payload().is_empty() {
+            self.process_payload(socket, packet)?;
+        }
+
+        // FINと一緒に届いたデータはprocess_payloadで受信バッファに格納済みなので、
+        // CloseWaitに遷移した後もrecvで読み出せる
+        // 手前のデータを全て受理できていない(順序の入れ替わりやウィンドウ不足)場合は
+        // FINを受理せず、相手の再送を待つ
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            let fin_seq = packet.get_seq().wrapping_add(packet.payload().len() as u32);
+            if fin_seq != socket.recv_param.next {
+                socket.log(
+                    Level::Debug,
+                    format_args!(
+                        "fin is out of order: fin_seq={}, next={}",
+                        fin_seq, socket.recv_param.next
+                    ),
+                );
+                return Ok(());
+            }
+
+            socket.recv_param.next = fin_seq.wrapping_add(1);
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+            socket.status = TcpStatus::CloseWait;
+            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+        }
+
+        Ok(())
+    }
+
+    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        socket.log(Level::Trace, format_args!("finwait handler"));
+
+        if packet.get_flag() & tcpflags::ACK == 0
+            || self.process_ack(socket, packet) == AckResult::Unsent
+        {
+            return Ok(());
+        }
+
+        if !packet.payload().is_empty() {
+            self.process_payload(socket, packet)?;
+        }
+
+        if socket.status == TcpStatus::FinWait1
+            && socket.send_param.next == socket.send_param.unacked_seq
+        {
+            socket.status = TcpStatus::FinWait2;
+            socket.log(
+                Level::Debug,
+                format_args!("status: finwait1 -> {}", socket.status),
+            );
+        }
+
+        // 同時クローズで相手のFINを受信済みの場合、自分のFINがACKされたらTimeWaitへ遷移する
+        if socket.status == TcpStatus::Closing {
+            if socket.send_param.next == socket.send_param.unacked_seq {
+                self.enter_time_wait(socket);
+            } else if packet.get_flag() & tcpflags::FIN > 0 {
+                // 相手のFINが再送されてきたので、ACKを返し直す
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+            }
+            return Ok(());
+        }
+
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            // 手前のデータを全て受理できていない場合はFINを受理せず、相手の再送を待つ
+            let fin_seq = packet.get_seq().wrapping_add(packet.payload().len() as u32);
+            if fin_seq != socket.recv_param.next {
+                socket.log(
+                    Level::Debug,
+                    format_args!(
+                        "fin is out of order: fin_seq={}, next={}",
+                        fin_seq, socket.recv_param.next
+                    ),
+                );
+                return Ok(());
+            }
+
+            socket.recv_param.next = fin_seq.wrapping_add(1);
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+
+            if socket.status == TcpStatus::FinWait1 {
+                // 自分のFINがACKされる前に相手のFINが届いた(同時クローズ)
+                socket.status = TcpStatus::Closing;
+                socket.log(
+                    Level::Debug,
+                    format_args!("status: finwait1 -> {}", socket.status),
+                );
+            } else {
+                self.enter_time_wait(socket);
+            }
+        }
+
+        Ok(())
+    }
+
+    // 能動クローズの完了としてTimeWaitに遷移し、2MSL後に削除されるようにする
+    fn enter_time_wait(&self, socket: &mut Socket) {
+        let prev_status = socket.status.clone();
+        socket.status = TcpStatus::TimeWait;
+        socket.time_wait_since = Some(SystemTime::now());
+        socket.schedule_timer(SystemTime::now() + 2 * MSL);
+        socket.log(
+            Level::Debug,
+            format_args!("status: {} -> {}", prev_status, socket.status),
+        );
+        self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+    }
+
+    fn timewait_handler(
+        &self,
+        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        sock_id: SockID,
+        packet: &TCPPacket,
+        remote_addr: IpAddr,
+    ) -> Result<()> {
+        let socket = table.get_mut(&sock_id).unwrap();
+        socket.log(Level::Trace, format_args!("timewait handler"));
+
+        // RFC6191: TimeWait中の4-tupleに対して、前の接続の受信範囲より新しいseqのSYNが来たら
+        // TimeWaitを打ち切って新しい接続として受理する
+        if packet.get_flag() & tcpflags::SYN > 0
+            && packet.get_flag() & tcpflags::ACK == 0
+            && seq::gt(packet.get_seq(), socket.recv_param.next)
+        {
+            let listening_socket_id = SockID(
+                socket.local_addr,
+                undetermined_addr(socket.local_addr),
+                socket.local_port,
+                UNDETERMINED_PORT,
+            );
+
+            if table.contains_key(&listening_socket_id) {
+                if let Some(socket) = remove_socket(&mut table, sock_id) {
+                    socket.log(Level::Debug, format_args!("recycle timewait socket"));
+                }
+                return self.listen_handler(table, listening_socket_id, packet, remote_addr);
+            }
+        }
+
+        let socket = table.get_mut(&sock_id).unwrap();
+        // TimeWait中のセグメントはACKを返すだけで、それ以外は無視する
+        // ACKだけのセグメントにACKを返すと応答が往復し続けるので返さない
+        if !packet.payload().is_empty() || packet.get_flag() & (tcpflags::SYN | tcpflags::FIN) > 0 {
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+        }
+
+        // FINが再送されてきた場合は自分のACKが失われているので、2MSLを測り直す
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            socket.time_wait_since = Some(SystemTime::now());
+            socket.schedule_timer(SystemTime::now() + 2 * MSL);
+        }
+
+        Ok(())
+    }
+
+    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        socket.log(Level::Trace, format_args!("closewait | lastack handler"));
+        if packet.get_flag() & tcpflags::ACK == 0
+            || self.process_ack(socket, packet) == AckResult::Unsent
+        {
+            return Ok(());
+        }
+
+        if socket.status == TcpStatus::CloseWait {
+            self.send_pending(socket)?;
+        }
+
+        // FINのACKを受け取ったらtimerの処理を待たずにクローズを完了させる
+        if socket.status == TcpStatus::LastAck
+            && socket.send_param.unacked_seq == socket.send_param.next
+        {
+            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
+        }
+
+        Ok(())
+    }
+
+    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
+        if packet.get_flag() & tcpflags::SYN > 0 && packet.get_flag() & tcpflags::ACK > 0 {
+            socket.log(
+                Level::Trace,
+                format_args!(
+                    "payload on synack: offset={}, len={}, seq={}, next={}",
+                    packet.get_data_offset(),
+                    packet.payload().len(),
+                    packet.get_seq(),
+                    socket.recv_param.next
+                ),
+            );
+        }
+
+        // 再送などで届いた受信済みの古いセグメントはバッファに書き込まず、現在のACKだけ返す
+        if seq::leq(
+            packet.get_seq().wrapping_add(packet.payload().len() as u32),
+            socket.recv_param.next,
+        ) {
+            socket.log(
+                Level::Debug,
+                format_args!(
+                    "discard old segment: seq={}, next={}",
+                    packet.get_seq(),
+                    socket.recv_param.next
+                ),
+            );
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            )?;
+            return Ok(());
+        }
+
+        // 再送などで受信済みの範囲と先頭が重なっている場合は、重なった部分を読み飛ばして
+        // nextから始まるデータとして扱う
+        // 読み飛ばさないとoffsetの計算がアンダーフローして、未受信の部分まで破棄してしまう
+        let skip = cmp::max(
+            0,
+            socket.recv_param.next.wrapping_sub(packet.get_seq()) as i32,
+        ) as usize;
+        let seq = packet.get_seq().wrapping_add(skip as u32);
+        let payload = &packet.payload()[skip..];
+
+        // アプリが読めるin-orderのデータが増えたかの判定に使う
+        let prev_next = socket.recv_param.next;
+        // 歯抜けを埋めたセグメントかの判定に使う
+        let had_out_of_order = !socket.recv_param.out_of_order.is_empty();
+
+        // 順序が入れ替わっていたときのためにseq - socket.recv_param.nextでoffsetを調整する
+        // 受信済みの範囲は読み飛ばしているので、seqはnext以降にある
+        let distance = seq.wrapping_sub(socket.recv_param.next);
+        debug_assert!(
+            seq::geq(seq, socket.recv_param.next),
+            "segment starts before next: {} < {}",
+            seq,
+            socket.recv_param.next
+        );
+        // 相手がウィンドウのはるか先のseqを送ってきても溢れないように、バッファの範囲外はそのまま範囲外として扱う
+        let offset = socket.received_size().saturating_add(distance as usize);
+        // 広告したウィンドウ(nextからの受信バッファの空き)に入りきらない分は破棄する
+        // 受理した分だけACKを進めて、残りは相手に再送させる
+        // 順序が入れ替わって届いたデータはウィンドウを減らしていないので、nextからの距離を引いて判定する
+        let copy_size = cmp::min(
+            payload.len(),
+            (socket.recv_param.window as usize).saturating_sub(distance as usize),
+        );
+        if copy_size < payload.len() {
+            socket.log(
+                Level::Debug,
+                format_args!("partially accepted: {} of {}", copy_size, payload.len()),
+            );
+        }
+
+        // 自分が広告したウィンドウの外(受信バッファの外)に書き込まないこと
+        debug_assert!(copy_size == 0 || offset + copy_size <= socket.recv_buffer.capacity());
+        if copy_size > 0 {
+            socket.recv_buffer.write(offset, &payload[..copy_size]);
+        }
+        let end = seq.wrapping_add(copy_size as u32);
+
+        if seq == socket.recv_param.next {
+            // パケットの順序が入れ替わっていない場合
+            // 先に届いていた後続のデータと連続したら、そこまでアプリから読めるようになる
+            // 順序が入れ替わって届いたデータは受信時にウィンドウを減らしていないので、
+            // 新たに読めるようになったバイト数(nextの進み幅)だけウィンドウを減らす
+            socket.recv_param.next = end;
+            socket.recv_param.merge_out_of_order();
+            socket.recv_param.window -= socket.recv_param.next.wrapping_sub(prev_next);
+        } else if copy_size > 0 {
+            let max_blocks = socket.options.max_out_of_order_blocks;
+            socket.recv_param.insert_out_of_order(seq..end, max_blocks);
+        }
+
+        // 受信バッファにコピー成功
+        // 遅延ACK(RFC1122 4.2.3.2): 順序通りに届いたセグメントへのACKは、次のセグメントが届くか
+        // DELAYED_ACK_TIMEOUTが経過するまで遅らせて、2セグメントに1回にまとめる
+        // 以下の場合は遅らせずに返す
+        // - 順序が入れ替わったセグメントと、歯抜けを埋めたセグメント(相手のfast retransmitを遅らせない)
+        // - ウィンドウに入りきらなかったセグメント(受理した範囲を伝えて、残りを早く再送させる)
+        // - MSS未満のセグメント(相手がNagleで後続のデータをACK待ちで止めている可能性がある)
+        if copy_size > 0 {
+            let delayable = seq == prev_next
+                && !had_out_of_order
+                && copy_size == payload.len()
+                && !peer_may_wait_for_ack(payload.len(), socket.send_mss())
+                && socket.pending_ack_since.is_none();
+            if delayable {
+                socket.log(
+                    Level::Trace,
+                    format_args!("delay ack: {}", socket.recv_param.next),
+                );
+                let now = SystemTime::now();
+                socket.pending_ack_since = Some(now);
+                socket.schedule_timer(now + DELAYED_ACK_TIMEOUT);
+            } else {
+                socket.send_tcp_packet(
+                    socket.send_param.next,
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                )?;
+            }
+        } else {
+            // 1バイトも受理できなかったセグメントは破棄して、現在のACKとウィンドウを返す
+            // nextもtailも進めないので、アプリから読めるデータは増えない
+            socket.log(
+                Level::Debug,
+                format_args!(
+                    "recv buffer overflow: seq={}, window={}",
+                    seq, socket.recv_param.window
+                ),
+            );
+            socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
             )?;
+        }
+
+        // 読めるデータが増えていない(バッファあふれや順序逆転のデータのみ)ならrecvを起こさない
+        if socket.recv_param.next != prev_next {
+            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+        }
+
+        Ok(())
+    }
+
+    // RTO計算のために送信済みのパケットに対するACKパケットが返ってきたときに
+    // ターンアラウンドタイムを取得する
+    // 送信時刻は再送キューのエントリで一元管理し、
+    // 送信したパケットに対して予想されるACKの値が返ってきたもののみ計算対象にする
+    fn update_rto(&self, socket: &mut Socket, packet: &TCPPacket) {
+        let ack = packet.get_ack();
+        // タイムスタンプを折衝している場合は、エコーされた送信時刻からRTTを測る
+        // そうでない場合、再送したセグメントはどの送信に対するACKか区別できないので計算対象にしない(Karnのアルゴリズム)
+        let rtt = match socket.timestamp_rtt(packet) {
+            Some(rtt) => rtt,
+            None => match socket
+                .retransmission_queue
+                .iter()
+                .find(|item| item.expected_ack == ack && item.transmission_count == 1)
+            {
+                Some(item) => item.latest_transmission_time.elapsed().unwrap_or_default(),
+                None => return,
+            },
+        };
+
+        // スケジューリング遅延などによる極端なRTTでRTOが膨らまないように、
+        // 直近の中央値のRTO_MARGIN倍を超えるサンプルはRTOの計算から除外する
+        if is_rtt_outlier(&socket.turn_around_times, rtt) {
+            socket.log(Level::Debug, format_args!("discard rtt outlier: {:?}", rtt));
+        } else {
+            socket.rto.next(rtt);
+        }
+
+        // 経路の変化でRTTが恒常的に増えた場合に追従できるよう、外れ値も履歴には残す
+        socket.turn_around_times.push_back(rtt);
+        if socket.turn_around_times.len() > TURN_AROUND_TIMES_MAXLEN {
+            socket.turn_around_times.pop_front();
+        }
+
+        socket.log(
+            Level::Trace,
+            format_args!("rtt={:?}, rto={:?}", rtt, socket.rto.get()),
+        );
+    }
+
+    // Nagleで保留していたデータを、未ACKのデータが無くなったら送る
+    fn send_pending(&self, socket: &mut Socket) -> Result<()> {
+        if socket.pending.is_empty() || socket.send_param.used() > 0 {
+            return Ok(());
+        }
+
+        let send_size = cmp::min(socket.pending.len(), socket.send_capacity() as usize);
+        if send_size == 0 {
+            return Ok(());
+        }
+
+        socket.log(
+            Level::Trace,
+            format_args!("nagle: send pending data: {}", send_size),
+        );
+        let payload: Vec<u8> = socket.pending.drain(..send_size).collect();
+        socket.send_tcp_packet(
+            socket.send_param.next,
+            socket.recv_param.next,
+            tcpflags::ACK,
+            &payload,
+        )?;
+        socket.send_param.next = socket.send_param.next.wrapping_add(send_size as u32);
+
+        Ok(())
+    }
+
+    // 重複ACKが続いたら先頭のセグメントが失われたとみなし、RTOを待たずに再送する(RFC5681 3.2)
+    // 再送したセグメントはtransmission_countが増えるので、RTTの測定には使われない
+    fn fast_retransmit(&self, socket: &mut Socket) -> Result<()> {
+        let mut item = match socket.retransmission_queue.pop_front() {
+            Some(item) => item,
+            None => return Ok(()),
+        };
+
+        socket.log(
+            Level::Debug,
+            format_args!("fast retransmit: seq={}", item.packet.get_seq()),
+        );
+        let result = socket.retransmit(&mut item);
+        socket.retransmission_queue.push_front(item);
+
+        result
+    }
+
+    // ACK番号を受理範囲と照らし合わせて分類し、進んでいればunacked_seqを更新する
+    // RTTの測定と、ACK済みのセグメントの再送キューからの削除もここで行う
+    // 輻輳制御やウィンドウの更新は状態ごとに扱いが異なるので、呼び出し側で行う
+    fn process_ack(&self, socket: &mut Socket, packet: &TCPPacket) -> AckResult {
+        let ack = packet.get_ack();
+        if seq::lt(socket.send_param.next, ack) {
+            return AckResult::Unsent;
+        }
+        if ack == socket.send_param.unacked_seq {
+            return AckResult::Duplicate;
+        }
+        if seq::lt(ack, socket.send_param.unacked_seq) {
+            return AckResult::Old;
+        }
+
+        socket.stats.acked_bytes += ack.wrapping_sub(socket.send_param.unacked_seq) as u64;
+        // ACK済みのセグメントを再送キューから削除する前にRTTを測定する
+        self.update_rto(socket, packet);
+        socket.send_param.unacked_seq = ack;
+        self.delete_acked_segment_from_retransmission_queue(socket);
+
+        AckResult::Advanced
+    }
+
+    fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) {
+        socket.log(
+            Level::Trace,
+            format_args!("ack accept: {}", socket.send_param.unacked_seq),
+        );
+
+        while let Some(item) = socket.retransmission_queue.pop_front() {
+            // 一部だけACKされたセグメントは残りを再送する必要があるので削除しない
+            if seq::geq(socket.send_param.unacked_seq, item.expected_ack) {
+                socket.log(
+                    Level::Trace,
+                    format_args!("successfully acked: seq={}", item.packet.get_seq()),
+                );
+
+                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
+            } else {
+                socket.retransmission_queue.push_front(item);
+                break;
+            }
+        }
+    }
+
+    fn timer(weak: Weak<Self>) {
+        debug!("begin timer thread");
+
+        // 待機中はTCPへの参照を持たないので、TCPがdropされるとスレッドも終了する
+        while let Some(tcp) = Self::upgrade_running(&weak) {
+            tcp.expire_timers();
+            drop(tcp);
+            thread::sleep(TIMER_TICK);
+        }
+    }
+
+    // 期限が来たタイマを処理し、TimeWaitを終えたソケットを削除する
+    fn expire_timers(&self) {
+        let mut table = self.sockets.write().unwrap();
+        let expired = self.timer_wheel.lock().unwrap().expire(SystemTime::now());
+        let mut time_wait_expired = Vec::new();
+        for (sock_id, deadline) in expired {
+            let socket = match table.get_mut(&sock_id) {
+                Some(socket) => socket,
+                None => continue,
+            };
+
+            // より早い期限で登録し直された場合、古いエントリは無視する
+            if socket.next_timer != Some(deadline) {
+                continue;
+            }
+            socket.next_timer = None;
+
+            self.process_timer(sock_id, socket);
+            debug_assert_invariants(socket);
+
+            if is_time_wait_expired(socket) {
+                socket.log(Level::Debug, format_args!("time wait expired"));
+                time_wait_expired.push(sock_id);
+                continue;
+            }
+
+            if let Some(deadline) = next_timer_deadline(socket) {
+                socket.schedule_timer(deadline);
+            }
+        }
+
+        for sock_id in time_wait_expired {
+            remove_socket(&mut table, sock_id);
+        }
+
+        // 削除済みのソケットに残ったイベントを掃除する
+        // RSTなどで接続を破棄するときは、ソケットを削除してからエラーのイベントを発行するので、
+        // 待機中のスレッドが受け取るまでの間は残しておく
+        self.events.0.lock().unwrap().retain(|sock_id, queue| {
+            table.contains_key(sock_id)
+                || queue.published_at.elapsed().unwrap_or_default() < EVENT_RETENTION
+        });
+    }
+
+    // 期限が来たソケットの遅延ACK、probe、再送を処理する
+    fn process_timer(&self, sock_id: SockID, socket: &mut Socket) {
+        // 遅延させていたACKを返す
+        let delayed_ack_expired = socket
+            .pending_ack_since
+            .is_some_and(|since| since.elapsed().unwrap_or_default() >= DELAYED_ACK_TIMEOUT);
+        if delayed_ack_expired {
+            socket.log(
+                Level::Trace,
+                format_args!("send delayed ack: {}", socket.recv_param.next),
+            );
+            if let Err(error) = socket.send_tcp_packet(
+                socket.send_param.next,
+                socket.recv_param.next,
+                tcpflags::ACK,
+                &[],
+            ) {
+                socket.log(
+                    Level::Warn,
+                    format_args!("failed to send delayed ack: {:?}", error),
+                );
+            }
+        }
+
+        // 一定時間通信の無い接続にkeep-aliveのprobeを送り、応答が無いまま上限に達したら接続を諦める
+        if let (Some(deadline), Some(keepalive)) =
+            (socket.keepalive_deadline(), socket.options.keepalive)
+        {
+            if SystemTime::now() >= deadline {
+                if socket.keepalive_probes >= keepalive.count {
+                    socket.log(Level::Warn, format_args!("keepalive timeout"));
+                    socket.aborted = true;
+                    self.publish_event(sock_id, TCPEventKind::ConnectionAborted);
+                    self.notify_window_update();
+                } else {
+                    socket.log(
+                        Level::Debug,
+                        format_args!("send keepalive probe: {}", socket.keepalive_probes),
+                    );
+                    if let Err(error) = socket.send_tcp_packet(
+                        socket.send_param.next.wrapping_sub(1),
+                        socket.recv_param.next,
+                        tcpflags::ACK,
+                        &[],
+                    ) {
+                        socket.log(
+                            Level::Warn,
+                            format_args!("failed to send keepalive probe: {:?}", error),
+                        );
+                    }
+                    socket.keepalive_probes += 1;
+                }
+            }
+        }
+
+        // 送信ウィンドウを使い切ったまま一定時間ACKが来ない場合、
+        // 相手のウィンドウが0になっている可能性があるのでprobeを開始する
+        if socket.last_time_window_probe.is_none()
+            && socket.send_param.used() > 0
+            && socket.send_param.remain() == 0
+            && socket.last_time_ack_received.elapsed().unwrap_or_default() > WINDOW_PROBE_DURATION
+        {
+            socket.log(
+                Level::Debug,
+                format_args!("start window probe from sender side"),
+            );
+            socket.last_time_window_probe = Some(SystemTime::now());
+        }
+
+        if let Some(last_time) = socket.last_time_window_probe {
+            if last_time.elapsed().unwrap_or_default() > WINDOW_PROBE_DURATION {
+                // KeepAliveパケットを送信する
+                // 送れなかった場合も次の間隔で送り直す
+                if let Err(error) = socket.send_tcp_packet(
+                    socket.send_param.next.wrapping_sub(1),
+                    socket.recv_param.next,
+                    tcpflags::ACK,
+                    &[],
+                ) {
+                    socket.log(
+                        Level::Warn,
+                        format_args!("failed to send window probe: {:?}", error),
+                    );
+                }
+                socket.last_time_window_probe = Some(SystemTime::now());
+            }
+        }
+
+        // ICMPが届かない経路(MTUブラックホール)では、MTUを超えるセグメントは再送しても届かない
+        // 先頭の大きなセグメントのタイムアウトが続いたら、セグメントを小さくしてから再送する(RFC4821)
+        let blackhole_suspected = socket.retransmission_queue.front().is_some_and(|item| {
+            !item.sacked
+                && item.transmission_count >= BLACKHOLE_DETECTION_COUNT
+                && item.packet.payload().len() > socket.send_param.mss / 2
+                && item.latest_transmission_time.elapsed().unwrap_or_default() >= item.rto
+        });
+        if blackhole_suspected && socket.lower_path_mss(socket.send_param.mss / 2) {
+            socket.log(
+                Level::Debug,
+                format_args!("suspect mtu black hole: mss={}", socket.send_param.mss),
+            );
+        }
+
+        // 送信済みで未ACKのセグメントは、相手がウィンドウ0を広告していても再送する(RFC793)
+        // ウィンドウ0で止めるのは新規データの送信だけなので、ここではウィンドウを確認しない
+        let mut new_retransmission_queue = VecDeque::new();
+        // 1回のタイムアウトで複数のセグメントを再送しても、cwndを縮めるのは1回だけにする
+        let mut cwnd_collapsed = false;
+        while let Some(mut item) = socket.retransmission_queue.pop_front() {
+            if seq::geq(socket.send_param.unacked_seq, item.expected_ack) {
+                // ACKをすでに受信済み
+                socket.log(
+                    Level::Trace,
+                    format_args!("successfully acked: seq={}", item.packet.get_seq()),
+                );
+                self.publish_event(sock_id, TCPEventKind::Acked);
+
+                if item.packet.get_flag() & tcpflags::FIN > 0 && socket.status == TcpStatus::LastAck
+                {
+                    self.publish_event(sock_id, TCPEventKind::ConnectionClosed);
+                }
+
+                continue;
+            }
+
+            if item.latest_transmission_time.elapsed().unwrap_or_default() < item.rto {
+                new_retransmission_queue.push_back(item);
+                continue;
+            }
+
+            // 相手が受信済みのセグメントは再送せず、欠けているセグメントだけを再送する
+            // 累積ACKで削除されるまではキューに残しておく
+            if item.sacked {
+                socket.log(
+                    Level::Trace,
+                    format_args!("skip sacked segment: seq={}", item.packet.get_seq()),
+                );
+                item.latest_transmission_time = SystemTime::now();
+                new_retransmission_queue.push_back(item);
+                continue;
+            }
+
+            let give_up = match socket.options.retransmit_deadline {
+                Some(deadline) => {
+                    item.first_transmission_time.elapsed().unwrap_or_default() >= deadline
+                }
+                None => item.transmission_count >= MAX_TRANSMISSION,
+            };
+
+            if !give_up {
+                socket.log(
+                    Level::Debug,
+                    format_args!("retransmit: seq={}", item.packet.get_seq()),
+                );
+
+                // 送信に失敗した場合も1回の送信として数え、失敗が続けば上限で諦められるようにする
+                // 1つのソケットの送信エラーでtimerスレッドを止めない
+                if let Err(error) = socket.retransmit(&mut item) {
+                    socket.log(
+                        Level::Warn,
+                        format_args!("failed to retransmit: {:?}", error),
+                    );
+                    item.transmission_count = item.transmission_count.saturating_add(1);
+                    item.latest_transmission_time = SystemTime::now();
+                }
+                if item.packet.get_flag() == tcpflags::SYN {
+                    socket.rto.set(Duration::from_secs(3));
+                    item.rto = socket.rto.get();
+                } else {
+                    item.rto = socket.rto.backoff();
+                    if !cwnd_collapsed {
+                        socket.send_param.collapse_cwnd();
+                        socket.recovery = false;
+                        socket.congestion_state = CongestionState::SlowStart;
+                        cwnd_collapsed = true;
+                        socket.log(
+                            Level::Debug,
+                            format_args!("collapse cwnd: {}", socket.send_param.cwnd),
+                        );
+                    }
+                }
+                // 上の処理のように送信時間を見てRTTを超えていなければ再送するかの確認処理を
+                // 中断するという処理をできるようにするため
+                // 再送キューの一番後ろに配置するようにする
+                new_retransmission_queue.push_back(item);
+            } else {
+                // 再送の上限回数(または期限)に達したので再送を諦める
+                socket.log(
+                    Level::Warn,
+                    format_args!("reached MAX_TRANSMISSION: seq={}", item.packet.get_seq()),
+                );
+                if item.packet.get_flag() & tcpflags::FIN > 0
+                    && matches!(
+                        socket.status,
+                        TcpStatus::LastAck
+                            | TcpStatus::FinWait1
+                            | TcpStatus::FinWait2
+                            | TcpStatus::Closing
+                    )
+                {
+                    self.publish_event(sock_id, TCPEventKind::ConnectionClosed);
+                } else {
+                    // SYNやデータが届かない接続は使えないので、待機中のsend/recv/connectへエラーを通知する
+                    socket.aborted = true;
+                    self.publish_event(sock_id, TCPEventKind::ConnectionAborted);
+                    self.notify_window_update();
+                    break;
+                }
+            }
+        }
+
+        // 接続を諦めた場合は残りのセグメントも再送しない
+        if socket.aborted {
+            socket.retransmission_queue.clear();
+            return;
+        }
+        socket.retransmission_queue = new_retransmission_queue;
+    }
+
+    // 指定したソケットIDに対して指定したイベントが来るまで待機
+    // 待機中に接続がリセットされた場合や、スタックがshutdownされた場合はエラーを返す
+    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) -> Result<()> {
+        self.wait_event_until(sock_id, kind, None)?;
+        Ok(())
+    }
+
+    // 指定したソケットIDに対して指定したイベントが来るか、タイムアウトするまで待機
+    // イベントを受け取れた場合はtrue、タイムアウトした場合はfalseを返す
+    // 待機中に接続がリセットされた場合や、スタックがshutdownされた場合はエラーを返す
+    fn wait_event_timeout(
+        &self,
+        sock_id: SockID,
+        kind: TCPEventKind,
+        timeout: Duration,
+    ) -> Result<bool> {
+        self.wait_event_until(sock_id, kind, Some(SystemTime::now() + timeout))
+    }
+
+    fn wait_event_until(
+        &self,
+        sock_id: SockID,
+        kind: TCPEventKind,
+        deadline: Option<SystemTime>,
+    ) -> Result<bool> {
+        let (lock, cvar) = &self.events;
+        loop {
+            let mut events = lock.lock().unwrap();
+            // shutdown済みの場合はイベントが来ないので待機しない
+            if !self.running.load(Ordering::Acquire) {
+                return Err(TCPError::Shutdown.into());
+            }
+
+            if let Some(result) = take_event(&mut events, sock_id, &kind) {
+                return result;
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => match deadline.duration_since(SystemTime::now()) {
+                    Ok(remain) => cmp::min(remain, EVENT_RECHECK_INTERVAL),
+                    Err(_) => return Ok(false),
+                },
+                None => EVENT_RECHECK_INTERVAL,
+            };
+            let (mut events, _) = cvar.wait_timeout(events, timeout).unwrap();
+            if let Some(result) = take_event(&mut events, sock_id, &kind) {
+                return result;
+            }
+            drop(events);
+
+            // ソケットが削除された後のイベントはtimerが掃除するので、
+            // 取りこぼした場合でも待ち続けないようにソケットの存在を確認する
+            // tableのロックはイベントのロックより先に取る順序なので、イベントのロックを離してから確認する
+            // RSTなどで破棄する場合はtableのロックを握ったまま削除とイベントの発行を行うので、
+            // ソケットが無ければイベントを確認し直してから諦める
+            // shutdownがソケットを削除した場合も、停止したことが分かるようにShutdownを返す
+            let table = self.sockets.read().unwrap();
+            if !table.contains_key(&sock_id) {
+                if let Some(result) = take_event(&mut lock.lock().unwrap(), sock_id, &kind) {
+                    return result;
+                }
+                if !self.running.load(Ordering::Acquire) {
+                    return Err(TCPError::Shutdown.into());
+                }
+                return Err(TCPError::InvalidSocket(sock_id).into());
+            }
+        }
+    }
+
+    // 送信ウィンドウの現在の世代番号を取得
+    // tableのロックを握っている間に取得しておくことで、ロック解除後の通知を取りこぼさない
+    fn window_generation(&self) -> u64 {
+        *self.window_condvar.0.lock().unwrap()
+    }
+
+    // 送信ウィンドウが更新されるか、1ms経過するまで待機
+    fn wait_window_update(&self, generation: u64) {
+        let (lock, cvar) = &self.window_condvar;
+        let current = lock.lock().unwrap();
+        let _ = cvar
+            .wait_timeout_while(current, Duration::from_millis(1), |current| {
+                *current == generation
+            })
+            .unwrap();
+    }
+
+    // 送信ウィンドウの更新を送信待機中のスレッドへ通知
+    fn notify_window_update(&self) {
+        let (lock, cvar) = &self.window_condvar;
+        let mut generation = lock.lock().unwrap();
+        *generation = generation.wrapping_add(1);
+        cvar.notify_all();
+    }
+
+    // 指定のソケットIDに対してイベント発行
+    // イベントはソケットごとにキューに積まれるので、続けて発行しても上書きされない
+    // 誰も待っていない種類のイベント(Ackedなど)でキューが伸び続けないように、
+    // 同じ種類のイベントが未処理で残っている場合は1つにまとめる
+    fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
+        let (lock, cvar) = &self.events;
+        let mut events = lock.lock().unwrap();
+        let queue = events.entry(sock_id).or_insert_with(|| EventQueue {
+            kinds: VecDeque::new(),
+            published_at: SystemTime::now(),
+        });
+        queue.published_at = SystemTime::now();
+        if !queue.kinds.contains(&kind) {
+            queue.kinds.push_back(kind);
+        }
+        cvar.notify_all();
+    }
+}
+
+// 最後の参照が無くなったら受信スレッドとタイマスレッドを止め、raw socketを閉じる
+impl Drop for TCP {
+    fn drop(&mut self) {
+        if let Err(error) = self.shutdown() {
+            error!("failed to shutdown: {:?}", error);
+        }
+    }
+}
+
+impl Display for TCPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            TCPError::Drained => "listening socket is drained",
+            TCPError::ConnectionReset => "connection reset by peer",
+            TCPError::ConnectionAborted => "connection aborted: retransmission limit reached",
+            TCPError::NotConnected => "socket is not connected",
+            TCPError::Shutdown => "tcp stack is shut down",
+            TCPError::InvalidSocket(sock_id) => return write!(f, "no such socket: {:?}", sock_id),
+            TCPError::AddrInUse(port) => {
+                return write!(f, "port {} is used by another tcp instance", port)
+            }
+        };
+
+        write!(f, "{}", msg)
+    }
+}
+
+impl error::Error for TCPError {}
+
+impl TCPEventKind {
+    // 待機中の呼び出し元へエラーとして返すイベントか
+    fn error(&self) -> Option<TCPError> {
+        match self {
+            TCPEventKind::ConnectionReset => Some(TCPError::ConnectionReset),
+            TCPEventKind::ConnectionAborted => Some(TCPError::ConnectionAborted),
+            _ => None,
+        }
+    }
+}
+
+// キューから指定したソケットのイベントを取り出す
+// 接続のエラーを表すイベントは、待っているイベントより優先してエラーとして返す
+fn take_event(
+    events: &mut HashMap<SockID, EventQueue>,
+    sock_id: SockID,
+    kind: &TCPEventKind,
+) -> Option<Result<bool>> {
+    let queue = &mut events.get_mut(&sock_id)?.kinds;
+    let result = if let Some(index) = queue.iter().position(|e| e.error().is_some()) {
+        let error = queue.remove(index)?.error()?;
+        Err(error.into())
+    } else {
+        let index = queue.iter().position(|e| e == kind)?;
+        queue.remove(index);
+        Ok(true)
+    };
+    trace!("{:?}: take event: {:?}", sock_id, result);
+
+    if queue.is_empty() {
+        events.remove(&sock_id);
+    }
+    Some(result)
+}
+
+// listenソケットの相手のアドレスなど、未確定のアドレスをaddrと同じアドレスファミリで表す
+fn undetermined_addr(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+// ソケットをテーブルから削除する
+// 再送キューやバッファはソケットのdropを待たずに解放する
+// タイミングホイールに残ったエントリは、timerがソケットを見つけられず無視する
+fn remove_socket(table: &mut HashMap<SockID, Socket>, sock_id: SockID) -> Option<Socket> {
+    let mut socket = table.remove(&sock_id)?;
+    socket.release_buffers();
+    release_port_if_unused(table, sock_id.2);
+    Some(socket)
+}
+
+// テーブル内にポートを使うソケットが無ければ、PORT_OWNERSから外して他のインスタンスが使えるようにする
+// テーブルに載っているポートはこのインスタンスの持ち物なので、持ち主の確認はしない
+fn release_port_if_unused(table: &HashMap<SockID, Socket>, port: u16) {
+    if table.keys().all(|id| id.2 != port) {
+        PORT_OWNERS.lock().unwrap().remove(&port);
+    }
+}
 
-            connection_socket.recv_param.next = packet.get_seq() + 1;
-            connection_socket.recv_param.initial_seq = packet.get_seq();
+// 状態更新のたびに満たしているべきseq/ackの不変条件を検証する
+// debug_assert!のみで構成しているので、リリースビルドでは何もしない
+// 相手がウィンドウを縮めると正常でもused() > windowになりうるので、
+// ウィンドウを超えて送っていないことは送信時(send_segments)に検証する
+fn debug_assert_invariants(socket: &Socket) {
+    // unacked_seq <= next
+    debug_assert!(
+        socket
+            .send_param
+            .next
+            .wrapping_sub(socket.send_param.unacked_seq)
+            <= i32::MAX as u32,
+        "unacked_seq exceeds next: {:?}",
+        socket.send_param
+    );
+    // 広告しているウィンドウは受信バッファの空きを超えない
+    debug_assert!(
+        socket.recv_param.window as usize <= socket.recv_buffer.capacity(),
+        "recv window exceeds buffer: {:?}",
+        socket.recv_param
+    );
+    // nextより先のデータを保持している場合は、それが広告したウィンドウ(受信バッファの空き)に収まっている
+    if let Some(last) = socket.recv_param.out_of_order.last() {
+        debug_assert!(
+            last.end.wrapping_sub(socket.recv_param.next) <= socket.recv_param.window,
+            "out-of-order data exceeds buffer: {:?}",
+            socket.recv_param
+        );
+    }
+    // 順序逆転ブロックはnextとの間に必ず歯抜けがあり、昇順で重ならずに並んでいる
+    // 歯抜けが埋まったブロックはnextに取り込まれているので、アプリが読めるのは
+    // 先頭から連続したデータだけで、後から届いた前半のデータと順序が入れ替わらない
+    let mut prev_end = socket.recv_param.next;
+    for block in socket.recv_param.out_of_order.iter() {
+        debug_assert!(
+            seq::gt(block.start, prev_end) && seq::lt(block.start, block.end),
+            "out-of-order blocks are not separated by gaps: {:?}",
+            socket.recv_param
+        );
+        prev_end = block.end;
+    }
+    // 再送キューのセグメントはseqの昇順で並び、互いに範囲が重ならない
+    let mut prev_end = None;
+    for item in socket.retransmission_queue.iter() {
+        let range = item.seq_range();
+        debug_assert!(
+            prev_end.is_none_or(|end| seq::geq(range.start, end)),
+            "retransmission queue entries overlap: {:?}",
+            range
+        );
+        prev_end = Some(range.end);
+    }
+}
 
-            connection_socket.send_param.initial_seq = rand::thread_rng().gen_range(1..1 << 31);
-            connection_socket.send_param.window = packet.get_window_size();
-            connection_socket.send_tcp_packet(
-                connection_socket.send_param.initial_seq,
-                connection_socket.recv_param.next,
-                tcpflags::SYN | tcpflags::ACK,
+// メッセージの前に長さヘッダを付ける
+fn frame_message(message: &[u8]) -> Result<Vec<u8>> {
+    if message.len() > MAX_MESSAGE_SIZE {
+        anyhow::bail!("message too large: {}", message.len());
+    }
+
+    let mut framed = Vec::with_capacity(MESSAGE_HEADER_SIZE + message.len());
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+
+    Ok(framed)
+}
+
+// 対応する接続が無いセグメントに返すRST(RFC793 3.4)
+// RSTにRSTを返すと応答が往復し続けるので、RSTに対してはNoneを返す
+fn reset_segment(local_addr: IpAddr, remote_addr: IpAddr, packet: &TCPPacket) -> Option<TCPPacket> {
+    let flag = packet.get_flag();
+    if flag & tcpflags::RST > 0 {
+        return None;
+    }
+
+    let mut reset = TCPPacket::with_options(&[], 0).ok()?;
+    reset.set_src(packet.get_dst());
+    reset.set_dst(packet.get_src());
+    if flag & tcpflags::ACK > 0 {
+        reset.set_seq(packet.get_ack());
+        reset.set_flag(tcpflags::RST);
+    } else {
+        let mut len = packet.payload().len() as u32;
+        if flag & tcpflags::SYN > 0 {
+            len += 1;
+        }
+        if flag & tcpflags::FIN > 0 {
+            len += 1;
+        }
+        reset.set_ack(packet.get_seq().wrapping_add(len));
+        reset.set_flag(tcpflags::RST | tcpflags::ACK);
+    }
+    reset.set_checksum(reset.compute_checksum(local_addr, remote_addr));
+
+    Some(reset)
+}
+
+// TimeWaitに遷移してから2MSL経過したか
+fn is_time_wait_expired(socket: &Socket) -> bool {
+    socket
+        .time_wait_since
+        .is_some_and(|since| since.elapsed().unwrap_or_default() >= 2 * MSL)
+}
+
+// ソケットの再送、probe、TimeWaitの終了のうち、直近の期限を求める
+fn next_timer_deadline(socket: &Socket) -> Option<SystemTime> {
+    let retransmission = socket
+        .retransmission_queue
+        .iter()
+        .map(|item| item.latest_transmission_time + item.rto)
+        .min();
+
+    let probe = match socket.last_time_window_probe {
+        Some(last_time) => Some(last_time + WINDOW_PROBE_DURATION),
+        // 送信側からprobeを開始するかの判定
+        None if socket.send_param.used() > 0 => {
+            Some(socket.last_time_ack_received + WINDOW_PROBE_DURATION)
+        }
+        None => None,
+    };
+
+    let time_wait = socket.time_wait_since.map(|since| since + 2 * MSL);
+
+    let delayed_ack = socket
+        .pending_ack_since
+        .map(|since| since + DELAYED_ACK_TIMEOUT);
+
+    [
+        retransmission,
+        probe,
+        time_wait,
+        delayed_ack,
+        socket.keepalive_deadline(),
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+}
+
+// 3ウェイハンドシェイクを終えて、データを送受信できる状態か
+// FINの送受信後の状態も確立済みの接続として扱う
+fn is_connected(status: &TcpStatus) -> bool {
+    !matches!(
+        status,
+        TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcvd
+    )
+}
+
+// RFC5681の定義に従って重複ACKか判定する
+// ウィンドウ更新だけのACKやデータを運ぶセグメントは重複ACKとして数えない
+fn is_duplicate_ack(socket: &Socket, packet: &TCPPacket) -> bool {
+    packet.get_ack() == socket.send_param.unacked_seq
+        && packet.payload().is_empty()
+        && packet.get_flag() & (tcpflags::SYN | tcpflags::FIN) == 0
+        && socket.send_param.scaled_window(packet.get_window_size()) == socket.send_param.window
+        && socket.send_param.used() > 0
+}
+
+// 相手がこちらのACKを待って後続の送信を止めている可能性があるセグメントか
+// MSS未満のセグメントは、相手がNagleで残りのデータを保留しているか、
+// リクエスト/レスポンスの末尾で応答を待っているかのどちらかなので、
+// ACKを遅らせるとNagleと遅延ACKが互いを待ち合い、遅延ACKのタイムアウトまで止まってしまう
+fn peer_may_wait_for_ack(payload_len: usize, mss: usize) -> bool {
+    payload_len < mss
+}
+
+// 直近のRTTの中央値と比べて極端に大きいRTTか判定する
+// 履歴が少ないうちは中央値が安定しないので判定しない
+fn is_rtt_outlier(turn_around_times: &VecDeque<Duration>, rtt: Duration) -> bool {
+    if turn_around_times.len() < RTT_OUTLIER_MIN_SAMPLES {
+        return false;
+    }
+
+    let mut times: Vec<Duration> = turn_around_times.iter().copied().collect();
+    times.sort();
+    let median = times[times.len() / 2];
+
+    rtt > median.mul_f32(RTO_MARGIN)
+}
+
+// addrへ送信するときの送信元ipアドレスを取得する
+// UDPソケットをconnectすると、カーネルが経路表から送信元アドレスを選んでバインドするので、それを読み出す
+// UDPのconnectはパケットを送らないので、宛先に到達できるかどうかは関係ない
+fn get_source_addr_to(addr: IpAddr) -> Result<IpAddr> {
+    let socket = UdpSocket::bind((undetermined_addr(addr), 0))?;
+    // ポートは経路の選択に使われないので、0以外の任意の値でよい
+    socket
+        .connect((addr, 9))
+        .with_context(|| format!("no route to {}", addr))?;
+
+    let ip = socket.local_addr()?.ip();
+    debug!("source addr to {}: {}", addr, ip);
+
+    Ok(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::TcpOption;
+    use crate::test_util::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::sync::mpsc;
+
+    // テストの接続で相手が使う初期seq
+    // seqの比較が一周をまたいでも正しいことを確かめるため、2^32の手前から始める
+    const REMOTE_ISS_BASE: u32 = u32::MAX - 100;
+
+    // 確立済みのソケットをtcpのテーブルに載せる
+    fn insert_established(tcp: &TCP, mut socket: Socket) -> SockID {
+        tcp.attach(&mut socket);
+        tcp.claim_port(socket.local_port).unwrap();
+        let sock_id = socket.get_sock_id();
+        tcp.sockets.write().unwrap().insert(sock_id, socket);
+        sock_id
+    }
+
+    // 送ったセグメントを記録するソケットで確立した接続
+    // 相手はテストで、segmentで組み立てたセグメントをhandle_segmentへ渡して演じる
+    fn recording_connection(buffer_sizes: BufferSizes) -> (Arc<TCP>, SockID, RecordingSender) {
+        let tcp = TCP::spawn(buffer_sizes);
+        let sender = RecordingSender::default();
+        let sock_id = insert_established(
+            &tcp,
+            established_socket(
+                (LOCAL_ADDR, unique_port()),
+                (REMOTE_ADDR, unique_port()),
+                REMOTE_ISS_BASE,
+                REMOTE_ISS_BASE,
+                buffer_sizes,
+                Box::new(sender.clone()),
+            ),
+        );
+        (tcp, sock_id, sender)
+    }
+
+    // 相手から届いたセグメントとして処理させる
+    fn deliver(tcp: &TCP, sock_id: SockID, packet: TCPPacket) {
+        tcp.handle_segment(sock_id.0, sock_id.1, packet);
+    }
+
+    fn with_socket<T>(tcp: &TCP, sock_id: SockID, f: impl FnOnce(&mut Socket) -> T) -> T {
+        f(tcp.sockets.write().unwrap().get_mut(&sock_id).unwrap())
+    }
+
+    // predを満たすセグメントが送られるまで待ち、それまでに送られたセグメントと合わせて返す
+    fn wait_sent(sender: &RecordingSender, pred: impl Fn(&TCPPacket) -> bool) -> Vec<TCPPacket> {
+        let deadline = SystemTime::now() + Duration::from_secs(5);
+        let mut sent = Vec::new();
+        while !sent.iter().any(&pred) {
+            assert!(SystemTime::now() < deadline, "segment was not sent");
+            thread::sleep(Duration::from_millis(1));
+            sent.extend(sender.take());
+        }
+        sent
+    }
+
+    // 相手のインスタンスが送ったセグメントをtcpへ渡し続ける
+    // deliverがfalseを返したセグメントは、ネットワークで失われたものとして捨てる
+    // 送り手のソケットが無くなるか、tcpが止まったら終わる
+    fn pump(
+        tcp: Weak<TCP>,
+        rx: mpsc::Receiver<TCPPacket>,
+        local_addr: IpAddr,
+        remote_addr: IpAddr,
+        mut deliver: impl FnMut(&TCP, &TCPPacket) -> bool + Send + 'static,
+    ) {
+        thread::spawn(move || {
+            for packet in rx {
+                let Some(tcp) = TCP::upgrade_running(&tcp) else {
+                    break;
+                };
+                if deliver(&tcp, &packet) {
+                    tcp.handle_segment(local_addr, remote_addr, packet);
+                }
+            }
+        });
+    }
+
+    // メモリ上のチャネルでつないだ2つのTCPインスタンスと、その間の確立済みの接続
+    // aからbへのセグメントはdeliver_to_bを通してから渡す
+    fn connected_pair_with(
+        buffer_sizes: BufferSizes,
+        deliver_to_b: impl FnMut(&TCP, &TCPPacket) -> bool + Send + 'static,
+    ) -> (Arc<TCP>, SockID, Arc<TCP>, SockID) {
+        let tcp_a = TCP::spawn(buffer_sizes);
+        let tcp_b = TCP::spawn(buffer_sizes);
+        let (port_a, port_b) = (unique_port(), unique_port());
+        let (to_b, from_a) = mpsc::channel();
+        let (to_a, from_b) = mpsc::channel();
+
+        let sock_a = insert_established(
+            &tcp_a,
+            established_socket(
+                (LOCAL_ADDR, port_a),
+                (REMOTE_ADDR, port_b),
+                1000,
+                u32::MAX - 100,
+                buffer_sizes,
+                Box::new(ChannelSender(Mutex::new(to_b))),
+            ),
+        );
+        let sock_b = insert_established(
+            &tcp_b,
+            established_socket(
+                (REMOTE_ADDR, port_b),
+                (LOCAL_ADDR, port_a),
+                u32::MAX - 100,
+                1000,
+                buffer_sizes,
+                Box::new(ChannelSender(Mutex::new(to_a))),
+            ),
+        );
+        pump(
+            Arc::downgrade(&tcp_b),
+            from_a,
+            REMOTE_ADDR,
+            LOCAL_ADDR,
+            deliver_to_b,
+        );
+        pump(
+            Arc::downgrade(&tcp_a),
+            from_b,
+            LOCAL_ADDR,
+            REMOTE_ADDR,
+            |_, _| true,
+        );
+
+        (tcp_a, sock_a, tcp_b, sock_b)
+    }
+
+    // 受信側が広告した右端を超えるセグメントが届いていないか
+    fn within_advertised_window(tcp: &TCP, packet: &TCPPacket) -> bool {
+        let table = tcp.sockets.read().unwrap();
+        let Some(socket) = table.values().find(|s| s.local_port == packet.get_dst()) else {
+            return true;
+        };
+        let right_edge = socket
+            .recv_param
+            .next
+            .wrapping_add(socket.recv_param.window);
+        let end = packet.get_seq().wrapping_add(packet.payload().len() as u32);
+        seq::leq(end, right_edge)
+    }
+
+    #[test]
+    fn advertised_window_tracks_free_buffer_space() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes {
+            send: 4000,
+            recv: 4000,
+        });
+        let mut seq = REMOTE_ISS_BASE.wrapping_add(1);
+        let ack = REMOTE_ISS_BASE.wrapping_add(1);
+        let advertised = || {
+            let window = sender.take().last().unwrap().get_window_size() as usize;
+            let free = with_socket(&tcp, sock_id, |socket| {
+                socket.recv_buffer.capacity() - socket.received_size()
+            });
+            assert_eq!(window, free);
+            window
+        };
+
+        for len in [1000, 500] {
+            deliver(
+                &tcp,
+                sock_id,
+                segment(sock_id, seq, ack, tcpflags::ACK, &[], &vec![1; len]),
+            );
+            seq = seq.wrapping_add(len as u32);
+        }
+        assert_eq!(advertised(), 2500);
+
+        // 読み出した分は次に送るセグメントから広告に戻る
+        let mut buffer = [0; 400];
+        assert_eq!(tcp.recv(sock_id, &mut buffer).unwrap(), 400);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &[1; 900]),
+        );
+        seq = seq.wrapping_add(900);
+        assert_eq!(advertised(), 2000);
+
+        // 満杯になったら0を広告し、読み出したらすぐにウィンドウ更新を送る
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &[1; 1000]),
+        );
+        deliver(
+            &tcp,
+            sock_id,
+            segment(
+                sock_id,
+                seq.wrapping_add(1000),
+                ack,
+                tcpflags::ACK,
                 &[],
-            )?;
+                &[1; 1000],
+            ),
+        );
+        assert_eq!(advertised(), 0);
+        assert_eq!(tcp.recv(sock_id, &mut buffer).unwrap(), 400);
+        assert_eq!(advertised(), 400);
+    }
+
+    #[test]
+    fn unacked_data_is_retransmitted_into_a_zero_window() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        tcp.send(sock_id, &[7; 1000]).unwrap();
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+
+        // 相手はデータをACKせずにウィンドウ0を広告する
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.unacked_seq)
+        });
+        let mut zero_window = segment(sock_id, seq, ack, tcpflags::ACK, &[], &[]);
+        zero_window.set_window_size(0);
+        zero_window.set_checksum(zero_window.compute_checksum(sock_id.1, sock_id.0));
+        deliver(&tcp, sock_id, zero_window);
+        assert_eq!(with_socket(&tcp, sock_id, |s| s.send_param.window), 0);
+
+        // RTOが過ぎたら、ウィンドウ0のままでも未ACKのセグメントを再送する
+        with_socket(&tcp, sock_id, |socket| {
+            let item = socket.retransmission_queue.front_mut().unwrap();
+            item.latest_transmission_time -= item.rto + Duration::from_millis(1);
+            tcp.process_timer(sock_id, socket);
+        });
+        let retransmitted = sender.take();
+        assert_eq!(retransmitted.len(), 1);
+        assert_eq!(retransmitted[0].get_seq(), sent[0].get_seq());
+        assert_eq!(retransmitted[0].payload(), sent[0].payload());
+    }
+
+    #[test]
+    fn window_shrinks_only_by_newly_in_order_bytes() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes {
+            send: 4000,
+            recv: 4000,
+        });
+        let base = REMOTE_ISS_BASE.wrapping_add(1);
+        let ack = REMOTE_ISS_BASE.wrapping_add(1);
+        let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let send = |start: usize, end: usize| {
+            deliver(
+                &tcp,
+                sock_id,
+                segment(
+                    sock_id,
+                    base.wrapping_add(start as u32),
+                    ack,
+                    tcpflags::ACK,
+                    &[],
+                    &data[start..end],
+                ),
+            );
+            with_socket(&tcp, sock_id, |socket| {
+                (
+                    socket.recv_param.next.wrapping_sub(base),
+                    socket.recv_param.window,
+                )
+            })
+        };
+
+        // 先に届いた後半は読めるようになるまでウィンドウを減らさない
+        assert_eq!(send(1000, 2000), (0, 4000));
+        // 歯抜けが埋まると、先に届いていた分も含めて一度だけ減らす
+        assert_eq!(send(0, 1000), (2000, 2000));
+        // 受信済みの範囲の再送では減らさない
+        assert_eq!(send(500, 1500), (2000, 2000));
+        // 一部が受信済みのセグメントは、新しい部分だけ減らす
+        assert_eq!(send(1500, 3000), (3000, 1000));
+
+        let mut buffer = [0; 3000];
+        assert_eq!(tcp.recv(sock_id, &mut buffer).unwrap(), 3000);
+        assert_eq!(&buffer[..], &data[..]);
+    }
+
+    #[test]
+    fn peer_fin_in_fin_wait2_enters_time_wait_until_2msl() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            // 自分のFINはACK済み
+            socket.send_param.unacked_seq = socket.send_param.next;
+            socket.status = TcpStatus::FinWait2;
+            (socket.recv_param.next, socket.send_param.next)
+        });
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::FIN | tcpflags::ACK, &[], &[]),
+        );
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_ack(), seq.wrapping_add(1));
+
+        with_socket(&tcp, sock_id, |socket| {
+            assert_eq!(socket.status, TcpStatus::TimeWait);
+            assert!(!is_time_wait_expired(socket));
+            let since = socket.time_wait_since.unwrap();
+            assert_eq!(next_timer_deadline(socket), Some(since + 2 * MSL));
+        });
+
+        // FINの再送にはACKを返し直し、2MSLを測り直す
+        let restarted_after = with_socket(&tcp, sock_id, |socket| {
+            socket.time_wait_since = Some(SystemTime::now() - MSL);
+            SystemTime::now()
+        });
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::FIN | tcpflags::ACK, &[], &[]),
+        );
+        assert_eq!(sender.take().len(), 1);
+        with_socket(&tcp, sock_id, |socket| {
+            assert!(socket.time_wait_since.unwrap() >= restarted_after);
+        });
+
+        // 2MSLが経過したらtimerがソケットを削除する
+        with_socket(&tcp, sock_id, |socket| {
+            let expired = SystemTime::now() - 2 * MSL - Duration::from_millis(1);
+            socket.time_wait_since = Some(expired);
+            assert!(is_time_wait_expired(socket));
+            socket.schedule_timer(SystemTime::now() - Duration::from_millis(1));
+        });
+        // タイミングホイールは早くても次のtickで取り出すので、1tick待ってから処理する
+        thread::sleep(TIMER_TICK);
+        tcp.expire_timers();
+        assert!(!tcp.sockets.read().unwrap().contains_key(&sock_id));
+    }
+
+    #[test]
+    fn data_is_segmented_by_the_peer_mss() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        with_socket(&tcp, sock_id, |socket| {
+            socket.peer_options.mss = Some(536);
+            socket.negotiate_mss();
+        });
+
+        tcp.send_push(sock_id, &[1; 2000]).unwrap();
+        let lens: Vec<_> = sender
+            .take()
+            .iter()
+            .map(|packet| packet.payload().len())
+            .collect();
+        assert_eq!(lens, [536, 536, 536, 392]);
+    }
+
+    #[test]
+    fn simultaneous_close_goes_through_closing() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let closer = {
+            let tcp = tcp.clone();
+            thread::spawn(move || tcp.close(sock_id))
+        };
+        let sent = wait_sent(&sender, |packet| packet.get_flag() & tcpflags::FIN > 0);
+        let fin_seq = sent.last().unwrap().get_seq();
+        let seq = REMOTE_ISS_BASE.wrapping_add(1);
+
+        // 相手も自分のFINを受け取る前にFINを送ってきた
+        deliver(
+            &tcp,
+            sock_id,
+            segment(
+                sock_id,
+                seq,
+                fin_seq,
+                tcpflags::FIN | tcpflags::ACK,
+                &[],
+                &[],
+            ),
+        );
+        let status = with_socket(&tcp, sock_id, |socket| socket.status.clone());
+        assert_eq!(status, TcpStatus::Closing);
+        let ack = sender.take();
+        assert_eq!(ack.last().unwrap().get_ack(), seq.wrapping_add(1));
+
+        // 自分のFINがACKされたらTimeWaitへ進み、closeが戻る
+        deliver(
+            &tcp,
+            sock_id,
+            segment(
+                sock_id,
+                seq.wrapping_add(1),
+                fin_seq.wrapping_add(1),
+                tcpflags::ACK,
+                &[],
+                &[],
+            ),
+        );
+        closer.join().unwrap().unwrap();
+        let status = with_socket(&tcp, sock_id, |socket| socket.status.clone());
+        assert_eq!(status, TcpStatus::TimeWait);
+    }
+
+    // SYNを送った直後の、相手の応答を待っている接続
+    fn syn_sent_connection(buffer_sizes: BufferSizes) -> (Arc<TCP>, SockID, RecordingSender) {
+        let (tcp, sock_id, sender) = recording_connection(buffer_sizes);
+        with_socket(&tcp, sock_id, |socket| {
+            socket.status = TcpStatus::SynSent;
+            socket.send_param.unacked_seq = socket.send_param.initial_seq;
+        });
+        (tcp, sock_id, sender)
+    }
+
+    #[test]
+    fn unanswered_syn_times_out_and_removes_the_socket() {
+        let (tcp, sock_id, _sender) = syn_sent_connection(BufferSizes::default());
+
+        let timeout = Duration::from_millis(100);
+        let started = SystemTime::now();
+        let error = tcp
+            .wait_connected(sock_id, Some(started + timeout))
+            .unwrap_err();
+        let elapsed = started.elapsed().unwrap();
+
+        assert!(error.to_string().contains("timed out"), "{}", error);
+        assert!(
+            elapsed >= timeout && elapsed < timeout * 10,
+            "{:?}",
+            elapsed
+        );
+        assert!(!tcp.sockets.read().unwrap().contains_key(&sock_id));
+    }
+
+    #[test]
+    fn connection_established_before_the_timeout_is_returned() {
+        let (tcp, sock_id, _sender) = syn_sent_connection(BufferSizes::default());
+        let (seq, next) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.initial_seq, socket.send_param.next)
+        });
+
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, next, tcpflags::SYN | tcpflags::ACK, &[], &[]),
+        );
+
+        tcp.wait_connected(sock_id, Some(SystemTime::now() + Duration::from_secs(5)))
+            .unwrap();
+        let status = with_socket(&tcp, sock_id, |socket| socket.status.clone());
+        assert_eq!(status, TcpStatus::Established);
+    }
+
+    #[test]
+    fn reset_answering_the_syn_fails_the_connect() {
+        let (tcp, sock_id, _sender) = syn_sent_connection(BufferSizes::default());
+        let next = with_socket(&tcp, sock_id, |socket| socket.send_param.next);
+
+        // 送ったSYNをACKしていないRSTは偽物として無視する
+        let forged = segment(
+            sock_id,
+            0,
+            next.wrapping_add(1),
+            tcpflags::RST | tcpflags::ACK,
+            &[],
+            &[],
+        );
+        deliver(&tcp, sock_id, forged);
+        assert!(tcp.sockets.read().unwrap().contains_key(&sock_id));
+
+        // 閉じたポートへの接続に対するRSTで、待機中のconnectがすぐにエラーになる
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, 0, next, tcpflags::RST | tcpflags::ACK, &[], &[]),
+        );
+        let error = tcp
+            .wait_event(sock_id, TCPEventKind::ConnectionCompleted)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TCPError>(),
+            Some(TCPError::ConnectionReset)
+        ));
+        assert!(!tcp.sockets.read().unwrap().contains_key(&sock_id));
+    }
+
+    #[test]
+    fn reset_outside_the_receive_window_is_ignored() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes::default());
+        let (next, window) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.recv_param.window)
+        });
+
+        deliver(
+            &tcp,
+            sock_id,
+            segment(
+                sock_id,
+                next.wrapping_add(window),
+                0,
+                tcpflags::RST,
+                &[],
+                &[],
+            ),
+        );
+        assert!(tcp.sockets.read().unwrap().contains_key(&sock_id));
+
+        // ウィンドウ内であれば、次に期待するseqでなくても受理する
+        deliver(
+            &tcp,
+            sock_id,
+            segment(
+                sock_id,
+                next.wrapping_add(window - 1),
+                0,
+                tcpflags::RST,
+                &[],
+                &[],
+            ),
+        );
+        assert!(!tcp.sockets.read().unwrap().contains_key(&sock_id));
+        let result = tcp.wait_event_timeout(sock_id, TCPEventKind::DataArrived, Duration::ZERO);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn segment_with_contradicting_flags_is_dropped() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let next = with_socket(&tcp, sock_id, |socket| socket.recv_param.next);
+
+        // ウィンドウ内のRSTでも、SYNと同時に立っていれば接続を切らない
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, next, 0, tcpflags::SYN | tcpflags::RST, &[], &[]),
+        );
+        // FINだけのセグメントは受理もACKもしない
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, next, 0, tcpflags::FIN, &[], &[]),
+        );
+
+        let status = with_socket(&tcp, sock_id, |socket| {
+            (socket.status.clone(), socket.recv_param.next)
+        });
+        assert_eq!(status, (TcpStatus::Established, next));
+        assert!(sender.take().is_empty());
+    }
+
+    #[test]
+    fn unacked_data_is_limited_by_the_send_buffer() {
+        let buffer_sizes = BufferSizes {
+            send: 2000,
+            ..BufferSizes::default()
+        };
+        let (tcp, sock_id, sender) = recording_connection(buffer_sizes);
+        // 相手のウィンドウと輻輳ウィンドウには十分な空きがある
+        let (seq, unacked) = with_socket(&tcp, sock_id, |socket| {
+            socket.send_param.window = MAX_WINDOW as u32;
+            socket.send_param.cwnd = MAX_WINDOW as u32;
+            (socket.recv_param.next, socket.send_param.unacked_seq)
+        });
+
+        let sending = {
+            let tcp = tcp.clone();
+            thread::spawn(move || tcp.send_push(sock_id, &[1; 3000]))
+        };
+        thread::sleep(Duration::from_millis(50));
+        let sent: usize = sender.take().iter().map(|p| p.payload().len()).sum();
+        assert_eq!(sent, 2000);
+        assert!(!sending.is_finished());
+
+        // ACKで送信バッファが空いた分だけ残りを送る
+        let ack = unacked.wrapping_add(1000);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &[]),
+        );
+        sending.join().unwrap().unwrap();
+        let sent: usize = sender.take().iter().map(|p| p.payload().len()).sum();
+        assert_eq!(sent, 1000);
+    }
+
+    #[test]
+    fn send_fails_once_retransmissions_are_exhausted() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        // ACKは一切返さず、再送の間隔を縮めて上限まで早く再送させる
+        with_socket(&tcp, sock_id, |socket| {
+            socket.send_param.window = 1000;
+            socket.rto.set_max(Duration::from_millis(10));
+        });
+
+        let result = tcp.send(sock_id, &[1; 3000]);
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TCPError>(),
+            Some(TCPError::ConnectionAborted)
+        ));
+
+        // ウィンドウに収まる最初のデータだけを、諦めるまで再送し続けている
+        let sent = sender.take();
+        let head = sent[0].get_seq();
+        assert!(sent
+            .iter()
+            .all(|p| p.get_seq().wrapping_sub(head) as usize + p.payload().len() <= 1000));
+        assert!(sent.iter().filter(|p| p.get_seq() == head).count() > 1);
+
+        let error = tcp.recv(sock_id, &mut [0; 10]).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TCPError>(),
+            Some(TCPError::ConnectionAborted)
+        ));
+    }
+
+    #[test]
+    fn failing_retransmissions_count_towards_the_limit() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        with_socket(&tcp, sock_id, |socket| {
+            socket.rto.set_max(Duration::from_millis(10))
+        });
+        tcp.send_push(sock_id, &[1; 100]).unwrap();
+
+        // 再送が全て送信エラーになっても、timerスレッドは止まらずに接続を諦める
+        sender.fail.store(true, Ordering::Relaxed);
+        let error = tcp
+            .wait_event(sock_id, TCPEventKind::DataArrived)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TCPError>(),
+            Some(TCPError::ConnectionAborted)
+        ));
+    }
+
+    #[test]
+    fn send_errors_on_one_socket_do_not_stop_the_timer_for_others() {
+        let (tcp, failing_id, failing_sender) = recording_connection(BufferSizes::default());
+        let sender = RecordingSender::default();
+        let sock_id = insert_established(
+            &tcp,
+            established_socket(
+                (LOCAL_ADDR, unique_port()),
+                (REMOTE_ADDR, unique_port()),
+                REMOTE_ISS_BASE,
+                REMOTE_ISS_BASE,
+                BufferSizes::default(),
+                Box::new(sender.clone()),
+            ),
+        );
+        // もう一方の接続は、ACKを返すまでに諦められないよう再送の間隔を長めにする
+        for (id, max_rto) in [(failing_id, 10), (sock_id, 200)] {
+            with_socket(&tcp, id, |socket| {
+                socket.rto.set_max(Duration::from_millis(max_rto))
+            });
+        }
+
+        // 片方の接続の再送が全て送信エラーになり、その接続は諦められる
+        tcp.send_push(failing_id, &[1; 100]).unwrap();
+        failing_sender.fail.store(true, Ordering::Relaxed);
+        assert!(tcp
+            .wait_event(failing_id, TCPEventKind::DataArrived)
+            .is_err());
+
+        // その後も、timerスレッドはもう一方の接続の再送を続けている
+        tcp.send_push(sock_id, &[2; 100]).unwrap();
+        let sent = wait_sent(&sender, |p| p.payload().len() == 100);
+        let seq = sent
+            .iter()
+            .find(|p| p.payload().len() == 100)
+            .unwrap()
+            .get_seq();
+        wait_sent(&sender, |p| p.get_seq() == seq && p.payload().len() == 100);
+
+        // ACKを返せば、接続はそのまま使い続けられる
+        let ack = seq.wrapping_add(100);
+        let next = with_socket(&tcp, sock_id, |socket| socket.recv_param.next);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, next, ack, tcpflags::ACK, &[], b"pong"),
+        );
+        let mut buffer = [0; 4];
+        assert_eq!(tcp.recv(sock_id, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"pong");
+        assert!(with_socket(&tcp, sock_id, |socket| socket
+            .retransmission_queue
+            .is_empty()));
+    }
+
+    #[test]
+    fn events_published_back_to_back_are_not_lost() {
+        let (tcp, sock_a, _sender_a) = recording_connection(BufferSizes::default());
+        let sock_b = insert_established(
+            &tcp,
+            established_socket(
+                (LOCAL_ADDR, unique_port()),
+                (REMOTE_ADDR, unique_port()),
+                0,
+                0,
+                BufferSizes::default(),
+                Box::new(RecordingSender::default()),
+            ),
+        );
+
+        // 同じ種類のイベントは未処理の間まとめられるので、1回分を受け取り終えてから次を発行する
+        let (done, rounds) = mpsc::channel();
+        let publisher = {
+            let tcp = tcp.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    for sock_id in [sock_a, sock_b] {
+                        tcp.publish_event(sock_id, TCPEventKind::Acked);
+                        tcp.publish_event(sock_id, TCPEventKind::DataArrived);
+                    }
+                    rounds.recv().unwrap();
+                }
+            })
+        };
+
+        for _ in 0..1000 {
+            // 発行と逆の順序で待っても、先に発行されたイベントが上書きされていない
+            for sock_id in [sock_b, sock_a] {
+                tcp.wait_event(sock_id, TCPEventKind::DataArrived).unwrap();
+                tcp.wait_event(sock_id, TCPEventKind::Acked).unwrap();
+            }
+            done.send(()).unwrap();
+        }
+        publisher.join().unwrap();
+        assert!(tcp.events.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reordered_segments_are_received_in_sequence_order() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
+        let first = [1; 500];
+        let second = [2; 300];
+
+        // 後ろのセグメントが先に届いても、まだアプリへは渡さずに欠けている位置をACKする
+        let later = seq.wrapping_add(first.len() as u32);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, later, ack, tcpflags::ACK, &[], &second),
+        );
+        let sent = wait_sent(&sender, |p| p.get_flag() & tcpflags::ACK > 0);
+        assert_eq!(sent.last().unwrap().get_ack(), seq);
+        assert_eq!(
+            with_socket(&tcp, sock_id, |socket| socket.received_size()),
+            0
+        );
+
+        // 欠けていたセグメントでギャップが埋まると、両方を続けて読める
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &first),
+        );
+        let mut buffer = [0; 1000];
+        let size = tcp.recv(sock_id, &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], [&first[..], &second[..]].concat());
+    }
+
+    #[test]
+    fn negotiated_mss_is_the_smaller_of_local_and_peer() {
+        for (peer_mss, expected) in [(1000, 1000), (9000, MSS), (0, MSS)] {
+            let (tcp, sock_id, _sender) = syn_sent_connection(BufferSizes::default());
+            let (seq, next) = with_socket(&tcp, sock_id, |socket| {
+                (socket.recv_param.initial_seq, socket.send_param.next)
+            });
+
+            let options = [TcpOption::Mss(peer_mss)];
+            let flag = tcpflags::SYN | tcpflags::ACK;
+            deliver(
+                &tcp,
+                sock_id,
+                segment(sock_id, seq, next, flag, &options, &[]),
+            );
+
+            let mss = with_socket(&tcp, sock_id, |socket| socket.send_param.mss);
+            assert_eq!(mss, expected, "peer mss {}", peer_mss);
+        }
+    }
+
+    #[test]
+    fn syn_advertises_the_local_mss() {
+        let (tcp, sock_id, _sender) = syn_sent_connection(BufferSizes::default());
+        let options = with_socket(&tcp, sock_id, |socket| socket.syn_options());
+        assert!(options.contains(&TcpOption::Mss(MSS as u16)));
+    }
+
+    #[test]
+    fn segment_from_another_address_does_not_reach_the_connection() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes::default());
+        let next = with_socket(&tcp, sock_id, |socket| socket.recv_param.next);
+
+        // ポートとseqが正しくても、相手以外のアドレスからのRSTで接続を切られない
+        let spoofed_addr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 7));
+        let spoofed_id = SockID(sock_id.0, spoofed_addr, sock_id.2, sock_id.3);
+        let reset = segment(spoofed_id, next, 0, tcpflags::RST, &[], &[]);
+        tcp.handle_segment(sock_id.0, spoofed_addr, reset);
+
+        let status = with_socket(&tcp, sock_id, |socket| socket.status.clone());
+        assert_eq!(status, TcpStatus::Established);
+    }
+
+    #[test]
+    fn window_scale_lets_both_windows_exceed_64k() {
+        let buffer_sizes = BufferSizes {
+            send: MAX_WINDOW,
+            recv: 200_000,
+        };
+        let (tcp, sock_id, sender) = syn_sent_connection(buffer_sizes);
+        let (seq, next) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.initial_seq, socket.send_param.next)
+        });
+
+        // SYNのウィンドウはスケールしない
+        let options = [TcpOption::WindowScale(7)];
+        let mut syn_ack = segment(
+            sock_id,
+            seq,
+            next,
+            tcpflags::SYN | tcpflags::ACK,
+            &options,
+            &[],
+        );
+        syn_ack.set_window_size(1024);
+        syn_ack.set_checksum(syn_ack.compute_checksum(sock_id.1, sock_id.0));
+        deliver(&tcp, sock_id, syn_ack);
+        let scales = with_socket(&tcp, sock_id, |socket| {
+            (
+                socket.send_param.window,
+                socket.send_param.scale,
+                socket.recv_param.scale,
+            )
+        });
+        // 受信バッファ200000バイトを広告するには、2ビットのシフトが要る
+        assert_eq!(scales, (1024, 7, 2));
+
+        // 以降のセグメントのウィンドウは相手のシフト量でスケールする
+        let mut update = segment(sock_id, seq.wrapping_add(1), next, tcpflags::ACK, &[], &[]);
+        update.set_window_size(1024);
+        update.set_checksum(update.compute_checksum(sock_id.1, sock_id.0));
+        deliver(&tcp, sock_id, update);
+        let (window, capacity) = with_socket(&tcp, sock_id, |socket| {
+            socket.send_param.cwnd = MAX_WINDOW as u32;
+            (socket.send_param.window, socket.send_capacity())
+        });
+        assert_eq!(window, 1024 << 7);
+        assert!(capacity > u16::MAX as u32);
+
+        // 自分のウィンドウは自分のシフト量で縮めて広告する
+        let ack = sender.take().pop().unwrap();
+        assert_eq!(ack.get_flag(), tcpflags::ACK);
+        assert_eq!(ack.get_window_size() as u32, 200_000 >> 2);
+    }
+
+    #[test]
+    fn only_the_segment_missing_from_sack_is_retransmitted() {
+        let buffer_sizes = BufferSizes {
+            send: MAX_WINDOW,
+            ..BufferSizes::default()
+        };
+        let (tcp, sock_id, sender) = recording_connection(buffer_sizes);
+        let (seq, unacked) = with_socket(&tcp, sock_id, |socket| {
+            socket.peer_options.sack_permitted = true;
+            socket.send_param.window = MAX_WINDOW as u32;
+            socket.send_param.cwnd = MAX_WINDOW as u32;
+            socket.rto.set_max(Duration::from_millis(10));
+            (socket.recv_param.next, socket.send_param.unacked_seq)
+        });
+        tcp.send_push(sock_id, &[1; 5 * MSS]).unwrap();
+        assert_eq!(sender.take().len(), 5);
+
+        // 5つのうち3つ目だけが失われた
+        let lost = unacked.wrapping_add(2 * MSS as u32);
+        let sack = [TcpOption::Sack(vec![(
+            lost.wrapping_add(MSS as u32),
+            unacked.wrapping_add(5 * MSS as u32),
+        )])];
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, lost, tcpflags::ACK, &sack, &[]),
+        );
+
+        let retransmitted = wait_sent(&sender, |p| !p.payload().is_empty());
+        let retransmitted: Vec<_> = retransmitted
+            .iter()
+            .filter(|p| !p.payload().is_empty())
+            .collect();
+        assert!(retransmitted.iter().all(|p| p.get_seq() == lost));
+    }
+
+    #[test]
+    fn sacked_segments_free_room_in_the_congestion_window() {
+        let buffer_sizes = BufferSizes {
+            send: MAX_WINDOW,
+            ..BufferSizes::default()
+        };
+        let (tcp, sock_id, sender) = recording_connection(buffer_sizes);
+        let (seq, unacked) = with_socket(&tcp, sock_id, |socket| {
+            socket.peer_options.sack_permitted = true;
+            socket.send_param.window = MAX_WINDOW as u32;
+            socket.send_param.cwnd = 3 * MSS as u32;
+            (socket.recv_param.next, socket.send_param.unacked_seq)
+        });
+        tcp.send_push(sock_id, &[1; 3 * MSS]).unwrap();
+        assert_eq!(sender.take().len(), 3);
+        assert_eq!(
+            with_socket(&tcp, sock_id, |socket| socket.send_capacity()),
+            0
+        );
+
+        // 先頭が失われ、2つ目と3つ目は相手に届いている
+        let left = unacked.wrapping_add(MSS as u32);
+        let right = unacked.wrapping_add(3 * MSS as u32);
+        let sack = [TcpOption::Sack(vec![(left, right)])];
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, unacked, tcpflags::ACK, &sack, &[]),
+        );
 
-            connection_socket.send_param.next = connection_socket.send_param.initial_seq + 1;
-            connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
-            connection_socket.listening_socket = Some(listening_socket.get_sock_id());
+        let (inflight, capacity) = with_socket(&tcp, sock_id, |socket| {
+            (socket.inflight(), socket.send_capacity())
+        });
+        assert_eq!(inflight, MSS as u32);
+        assert_eq!(capacity, 2 * MSS as u32);
 
-            dbg!("status: listen ->", &connection_socket.status);
+        // 抜けた分だけ、累積ACKを待たずに新しいデータを送れる
+        tcp.send_push(sock_id, &[2; 2 * MSS]).unwrap();
+        let sent = sender.take();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].get_seq(), right);
+    }
 
-            table.insert(connection_socket.get_sock_id(), connection_socket);
-        }
+    // タイムスタンプを折衝した接続
+    fn timestamp_connection(ts_recent: u32) -> (Arc<TCP>, SockID, RecordingSender) {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        with_socket(&tcp, sock_id, |socket| {
+            socket.peer_options.timestamp = Some(ts_recent);
+            socket.negotiate_timestamp();
+        });
+        (tcp, sock_id, sender)
+    }
 
-        Ok(())
+    #[test]
+    fn rtt_is_sampled_from_the_timestamp_echo() {
+        let (tcp, sock_id, sender) = timestamp_connection(1000);
+        tcp.send_push(sock_id, &[1; 100]).unwrap();
+        let sent = sender.take();
+        let (tsval, tsecr) = sent[0].get_timestamp().unwrap();
+        assert_eq!(tsecr, 1000);
+
+        // 再送したセグメントでもエコーから測れることを、再送済みの印を付けて確かめる
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            socket.retransmission_queue[0].transmission_count = 2;
+            (socket.recv_param.next, socket.send_param.next)
+        });
+        thread::sleep(Duration::from_millis(30));
+        let options = [TcpOption::Timestamp {
+            tsval: 1001,
+            tsecr: tsval,
+        }];
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &options, &[]),
+        );
+
+        let srtt = with_socket(&tcp, sock_id, |socket| socket.rto.srtt()).unwrap();
+        assert!(srtt >= Duration::from_millis(30), "{:?}", srtt);
+        assert!(srtt < Duration::from_secs(1), "{:?}", srtt);
     }
 
-    fn synrcvd_handler(
-        &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
-        sock_id: SockID,
-        packet: &TCPPacket,
-    ) -> Result<()> {
-        dbg!("synrcvd handler");
-        let socket = table.get_mut(&sock_id).unwrap();
+    #[test]
+    fn paws_rejects_a_segment_with_an_old_timestamp() {
+        let (tcp, sock_id, sender) = timestamp_connection(1000);
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
 
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            // packet.get_seq() + 1じゃなくても良い？
-            socket.recv_param.next = packet.get_seq();
+        // seqは受理できる位置でも、タイムスタンプが古ければ破棄して現在のACKを返す
+        let old = [TcpOption::Timestamp {
+            tsval: 500,
+            tsecr: 0,
+        }];
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &old, b"old"),
+        );
+        let reply = wait_sent(&sender, |p| p.get_flag() & tcpflags::ACK > 0);
+        assert_eq!(reply.last().unwrap().get_ack(), seq);
+        assert_eq!(reply.last().unwrap().get_timestamp().unwrap().1, 1000);
+        let state = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.ts_recent)
+        });
+        assert_eq!(state, (seq, 1000));
 
-            socket.send_param.unacked_seq = packet.get_ack();
-            socket.status = TcpStatus::Established;
-            dbg!("status: synrcvd ->", &socket.status);
+        let fresh = [TcpOption::Timestamp {
+            tsval: 1500,
+            tsecr: 0,
+        }];
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &fresh, b"new"),
+        );
+        let state = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.ts_recent)
+        });
+        assert_eq!(state, (seq.wrapping_add(3), 1500));
+    }
 
-            if let Some(id) = socket.listening_socket {
-                let ls = table.get_mut(&id).unwrap();
-                ls.connected_connection_queue.push_back(sock_id);
-                self.publish_event(ls.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            }
+    #[test]
+    fn retransmission_timeout_collapses_cwnd() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let cwnd = with_socket(&tcp, sock_id, |socket| {
+            // 次の再送で再びcwndを減らす前に確かめられるよう、間隔には余裕を持たせる
+            socket.rto.set_max(Duration::from_millis(100));
+            socket.send_param.cwnd = 8 * socket.send_param.mss as u32;
+            socket.send_param.cwnd
+        });
+        tcp.send_push(sock_id, &[1; 100]).unwrap();
+
+        // 最初の送信と再送の2つが送られるまで待つ
+        let mut sent = Vec::new();
+        while sent.len() < 2 {
+            sent.extend(wait_sent(&sender, |_| true));
         }
 
-        Ok(())
+        let (param, congestion_state) = with_socket(&tcp, sock_id, |socket| {
+            (socket.send_param.clone(), socket.congestion_state)
+        });
+        assert_eq!(param.cwnd, param.mss as u32);
+        assert_eq!(param.ssthresh, cwnd / 2);
+        assert_eq!(congestion_state, CongestionState::SlowStart);
     }
 
-    fn synsent_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("synsent handler");
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-            && packet.get_flag() & tcpflags::SYN > 0
-        {
-            socket.recv_param.next = packet.get_seq() + 1;
-            socket.recv_param.initial_seq = packet.get_seq();
-            socket.send_param.unacked_seq = packet.get_ack();
-            socket.send_param.window = packet.get_window_size();
+    // 3セグメントを送った、重複ACKを数えられる接続
+    // 重複ACKとして数えるため、相手のウィンドウはsegmentが載せる値に合わせておく
+    fn connection_with_three_segments_in_flight() -> (Arc<TCP>, SockID, RecordingSender) {
+        let buffer_sizes = BufferSizes {
+            send: MAX_WINDOW,
+            ..BufferSizes::default()
+        };
+        let (tcp, sock_id, sender) = recording_connection(buffer_sizes);
+        with_socket(&tcp, sock_id, |socket| {
+            socket.send_param.window = u16::MAX as u32;
+            socket.send_param.cwnd = 10 * MSS as u32;
+        });
+        tcp.send_push(sock_id, &[1; 3 * MSS]).unwrap();
+        assert_eq!(sender.take().len(), 3);
+        (tcp, sock_id, sender)
+    }
 
-            if socket.send_param.unacked_seq > socket.send_param.initial_seq {
-                socket.status = TcpStatus::Established;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: syssent ->", &socket.status);
-                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            } else {
-                socket.status = TcpStatus::SynRcvd;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
+    #[test]
+    fn third_duplicate_ack_retransmits_the_head_immediately() {
+        let (tcp, sock_id, sender) = connection_with_three_segments_in_flight();
+        let (seq, unacked) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.unacked_seq)
+        });
 
-                dbg!("status: synsent ->", &socket.status);
-            }
+        for _ in 0..2 {
+            deliver(
+                &tcp,
+                sock_id,
+                segment(sock_id, seq, unacked, tcpflags::ACK, &[], &[]),
+            );
         }
+        assert!(sender.take().is_empty());
 
-        Ok(())
+        // RTOを待たずに、先頭のセグメントだけを再送する
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, unacked, tcpflags::ACK, &[], &[]),
+        );
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_seq(), unacked);
+        assert_eq!(sent[0].payload().len(), MSS);
     }
 
-    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("established handler");
-        dbg!(
-            "received seq",
-            socket.send_param.unacked_seq,
-            packet.get_ack()
-        );
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            // 未送信セグメントに対するACKは破棄
-            dbg!("discard packet", socket.send_param.next, packet.get_ack());
-            return Ok(());
-        }
+    #[test]
+    fn timestamps_round_trip_and_stale_echoes_are_not_sampled() {
+        let (tcp, sock_id, sender) = timestamp_connection(1000);
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
 
-        if packet.get_flag() & tcpflags::ACK == 0 {
-            // ACKが立っていない受信パケットは破棄
-            return Ok(());
-        }
+        // 相手のTSvalを次のACKでエコーする
+        let options = [TcpOption::Timestamp {
+            tsval: 2000,
+            tsecr: 0,
+        }];
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &options, b"ping"),
+        );
+        let reply = wait_sent(&sender, |p| p.get_flag() & tcpflags::ACK > 0);
+        assert_eq!(reply.last().unwrap().get_timestamp().unwrap().1, 2000);
 
-        if socket.send_param.window != packet.get_window_size() {
-            dbg!("resize window size", packet.get_window_size());
+        tcp.send_push(sock_id, &[1; 100]).unwrap();
+        let (tsval, _) = sender.take().last().unwrap().get_timestamp().unwrap();
+        let ack = ack.wrapping_add(100);
+        let echo = |tsecr| {
+            let options = [TcpOption::Timestamp { tsval: 2001, tsecr }];
+            segment(
+                sock_id,
+                seq.wrapping_add(4),
+                ack,
+                tcpflags::ACK,
+                &options,
+                &[],
+            )
+        };
 
-            if packet.get_window_size() == 0 && socket.last_time_window_probe.is_none() {
-                dbg!("transit into window probe mode");
-                socket.last_time_window_probe = Some(SystemTime::now());
-            } else if packet.get_window_size() > 0 && socket.last_time_window_probe.is_some() {
-                dbg!("transit into normal mode");
-                socket.last_time_window_probe = None;
-            }
+        // 送った時刻より前や未来の時刻のエコーは、RTTの測定に使わない
+        with_socket(&tcp, sock_id, |socket| {
+            assert_eq!(socket.timestamp_rtt(&echo(tsval.wrapping_sub(1))), None);
+            assert_eq!(
+                socket.timestamp_rtt(&echo(tsval.wrapping_add(60_000))),
+                None
+            );
+            assert!(socket.timestamp_rtt(&echo(tsval)).is_some());
+        });
+    }
+
+    #[test]
+    fn fast_recovery_inflates_cwnd_and_deflates_on_the_recovering_ack() {
+        let (tcp, sock_id, _sender) = connection_with_three_segments_in_flight();
+        let (seq, unacked) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.unacked_seq)
+        });
+        let mss = MSS as u32;
+        let dup_ack = || segment(sock_id, seq, unacked, tcpflags::ACK, &[], &[]);
+        let congestion = |tcp: &TCP| {
+            with_socket(tcp, sock_id, |socket| {
+                (
+                    socket.send_param.cwnd,
+                    socket.send_param.ssthresh,
+                    socket.recovery,
+                )
+            })
+        };
+
+        // 3つ目の重複ACKでssthreshを半分にし、届いた3セグメントの分だけcwndを膨らませる
+        for _ in 0..3 {
+            deliver(&tcp, sock_id, dup_ack());
         }
+        assert_eq!(congestion(&tcp), (8 * mss, 5 * mss, true));
 
-        socket.send_param.window = packet.get_window_size();
+        // 以降の重複ACKごとに1MSSずつ膨らませる
+        deliver(&tcp, sock_id, dup_ack());
+        assert_eq!(congestion(&tcp), (9 * mss, 5 * mss, true));
 
-        // RTO計算のために送信済みのパケットに対するACKパケットが返ってきたときに
-        // ターンアラウンドタイムを取得する
-        // 送信したパケットに対して予想されるACKの値が返ってきたもののみ計算対象にする
+        // 欠けていたセグメントがACKされたら、膨らませた分を戻してfast recoveryを抜ける
+        let recovered = unacked.wrapping_add(3 * mss);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, recovered, tcpflags::ACK, &[], &[]),
+        );
+        assert_eq!(congestion(&tcp), (5 * mss, 5 * mss, false));
+        let state = with_socket(&tcp, sock_id, |socket| socket.congestion_state);
+        assert_eq!(state, CongestionState::CongestionAvoidance);
+    }
 
-        if let Some((idx, _)) = socket
-            .sent_times
-            .iter()
-            .enumerate()
-            .find(|(_, times)| times.expected_ack == packet.get_ack())
-        {
-            let sent_time = socket.sent_times.remove(idx).unwrap();
-            let rtt = sent_time.sent_time.elapsed().unwrap();
-            socket.rto.next(rtt);
+    #[test]
+    fn nagle_coalesces_small_sends_until_the_ack() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, unacked) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.unacked_seq)
+        });
 
-            dbg!(sent_time.sent_time.elapsed().unwrap());
-            dbg!(socket.rto.get());
+        // 未ACKのデータがある間は、小さな書き込みを保留してまとめる
+        for _ in 0..3 {
+            tcp.send(sock_id, &[1; 10]).unwrap();
         }
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].payload().len(), 10);
 
-        if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
-        }
+        // 最初のセグメントがACKされたら、保留していた分を1つのセグメントで送る
+        let ack = unacked.wrapping_add(10);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &[]),
+        );
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_seq(), ack);
+        assert_eq!(sent[0].payload().len(), 20);
+    }
 
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            socket.recv_param.next = packet.get_seq() + 1;
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &[],
-            )?;
-            socket.status = TcpStatus::CloseWait;
-            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+    #[test]
+    fn nodelay_sends_small_writes_immediately() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        tcp.set_nodelay(sock_id, true).unwrap();
+
+        for _ in 0..3 {
+            tcp.send(sock_id, &[1; 10]).unwrap();
         }
+        let sent = sender.take();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|p| p.payload().len() == 10));
+    }
 
-        Ok(())
+    #[test]
+    fn initial_seq_depends_on_the_connection_and_advances_with_time() {
+        let tcp = TCP::spawn(BufferSizes::default());
+        let socket = |remote_port| {
+            established_socket(
+                (LOCAL_ADDR, 5000),
+                (REMOTE_ADDR, remote_port),
+                0,
+                0,
+                BufferSizes::default(),
+                Box::new(RecordingSender::default()),
+            )
+        };
+        let (a, b) = (socket(6000), socket(6001));
+
+        // 4-tupleが異なる接続のISNは、時刻の差よりずっと大きく離れる
+        let (isn_a, isn_b) = (tcp.initial_seq(&a), tcp.initial_seq(&b));
+        let distance = cmp::min(isn_a.wrapping_sub(isn_b), isn_b.wrapping_sub(isn_a));
+        assert!(distance > 100_000, "{} {}", isn_a, isn_b);
+
+        // 同じ4-tupleでは、4マイクロ秒ごとに1ずつ進む
+        thread::sleep(Duration::from_millis(20));
+        let advanced = tcp.initial_seq(&a).wrapping_sub(isn_a);
+        assert!((5_000..50_000).contains(&advanced), "{}", advanced);
+
+        // 秘密鍵が異なる別のインスタンスでは、同じ4-tupleでも推測できない値になる
+        let other = TCP::spawn(BufferSizes::default());
+        let distance = other.initial_seq(&a).wrapping_sub(tcp.initial_seq(&a));
+        assert!(distance > 100_000 && distance < u32::MAX - 100_000);
     }
 
-    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("finwait handler");
+    #[test]
+    fn two_full_sized_segments_are_acked_once() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
 
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            return Ok(());
-        }
+        // 1つ目はACKを遅らせ、2つ目でまとめてACKする
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &[1; MSS]),
+        );
+        assert!(sender.take().is_empty());
+        let second = seq.wrapping_add(MSS as u32);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, second, ack, tcpflags::ACK, &[], &[2; MSS]),
+        );
 
-        if packet.get_flag() & tcpflags::ACK == 0 {
-            return Ok(());
-        }
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_ack(), second.wrapping_add(MSS as u32));
+    }
 
-        if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
-        }
+    #[test]
+    fn lone_segment_is_acked_after_the_delay() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
 
-        if socket.status == TcpStatus::FinWait1
-            && socket.send_param.next == socket.send_param.unacked_seq
-        {
-            socket.status = TcpStatus::FinWait2;
-            dbg!("status: finwait1 ->", &socket.status);
-        }
+        let started = SystemTime::now();
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &[1; MSS]),
+        );
+        let sent = wait_sent(&sender, |p| p.get_flag() & tcpflags::ACK > 0);
 
-        // 本来はFinWait1状態のときにFINが来たら
-        // CLOSING状態に移行するが今回は簡略化のためなし。
-        // FinWait2のときにのみFINが来ることとしている
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            socket.recv_param.next += 1;
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &[],
-            )?;
-            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
-        }
+        // 後続のセグメントが来なければ、timerスレッドが遅延ACKのタイムアウトで返す
+        assert!(started.elapsed().unwrap() >= DELAYED_ACK_TIMEOUT);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_ack(), seq.wrapping_add(MSS as u32));
+    }
 
-        Ok(())
+    #[test]
+    fn out_of_order_segment_is_acked_immediately() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
+
+        let later = seq.wrapping_add(MSS as u32);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, later, ack, tcpflags::ACK, &[], &[2; MSS]),
+        );
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_ack(), seq);
     }
 
-    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("closewait | lastack handler");
-        socket.send_param.unacked_seq = packet.get_ack();
-        Ok(())
+    #[test]
+    fn silent_peer_is_aborted_after_the_keepalive_probes() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let next = with_socket(&tcp, sock_id, |socket| socket.send_param.next);
+        let interval = Duration::from_millis(20);
+        tcp.set_keepalive(sock_id, interval, interval, 3).unwrap();
+
+        let error = tcp
+            .wait_event(sock_id, TCPEventKind::DataArrived)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TCPError>(),
+            Some(TCPError::ConnectionAborted)
+        ));
+
+        // probeは送信済みの最後のバイトを送り直す形のACKで、応答を促す
+        let probes = sender.take();
+        assert_eq!(probes.len(), 3);
+        assert!(probes
+            .iter()
+            .all(|p| p.get_seq() == next.wrapping_sub(1) && p.payload().is_empty()));
     }
 
-    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        if packet.get_flag() & tcpflags::SYN > 0 && packet.get_flag() & tcpflags::ACK > 0 {
-            dbg!(packet.get_data_offset());
-            dbg!(packet.payload().len());
-            dbg!(packet.get_seq());
-            dbg!(socket.recv_param.next);
-        }
-        // 順序が入れ替わっていたときのためにpacket.get_seq() - socket.recv_param.nextでoffsetを調整する
-        let offset = socket.recv_buffer.len() - socket.recv_param.window as usize
-            + (packet.get_seq() - socket.recv_param.next) as usize;
-        let copy_size = cmp::min(packet.payload().len(), socket.recv_buffer.len() - offset);
-
-        socket.recv_buffer[offset..offset + copy_size]
-            .copy_from_slice(&packet.payload()[..copy_size]);
-        // すでに順序が入れ替わっている可能性があるため、socket.recv_param.tailのほうが大きいか確認する
-        socket.recv_param.tail =
-            cmp::max(socket.recv_param.tail, packet.get_seq() + copy_size as u32);
-
-        // パケットの順序が入れ替わっていない場合
-        if packet.get_seq() == socket.recv_param.next {
-            // 順序が入れ替わっていないので、tailがそのままnextになる
-            socket.recv_param.next = socket.recv_param.tail;
-            // TODO: socket.recv_param.tail - packet.get_seq()ではなくcopy_sizeでも良いか確認する
-            // 上のcmp::maxでtailを求めている部分は順序が入れ替わっていなければ必ずpacket.get_seq() + copy_sizeの値が選択される?
-            socket.recv_param.window -= (socket.recv_param.tail - packet.get_seq()) as u16;
+    #[test]
+    fn answered_keepalive_probe_keeps_the_connection() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
+        let interval = Duration::from_millis(20);
+        tcp.set_keepalive(sock_id, interval, interval, 2).unwrap();
+
+        // probeに応答があれば数え直すので、上限の回数を超えても接続は切れない
+        for _ in 0..4 {
+            wait_sent(&sender, |p| p.get_seq() == ack.wrapping_sub(1));
+            deliver(
+                &tcp,
+                sock_id,
+                segment(sock_id, seq, ack, tcpflags::ACK, &[], &[]),
+            );
         }
+        let state = with_socket(&tcp, sock_id, |socket| {
+            (socket.aborted, socket.keepalive_probes)
+        });
+        assert_eq!(state, (false, 0));
+    }
 
-        // 受信バッファにコピー成功
-        if copy_size > 0 {
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
+    #[test]
+    fn replayed_segment_is_acked_without_buffering_it_again() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], b"hello"),
+        );
+        wait_sent(&sender, |p| p.get_flag() & tcpflags::ACK > 0);
+        let next = seq.wrapping_add(5);
+
+        // 受理済みの範囲の再送や、一部だけ新しいデータを含む再送が届いても、受信済みのデータは変わらない
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], b"hello"),
+        );
+        let sent = sender.take();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].get_ack(), next);
+        assert_eq!(
+            with_socket(&tcp, sock_id, |socket| socket.recv_param.next),
+            next
+        );
+
+        deliver(
+            &tcp,
+            sock_id,
+            segment(
+                sock_id,
+                seq.wrapping_add(3),
+                ack,
                 tcpflags::ACK,
                 &[],
-            )?;
-        } else {
-            dbg!("recv buffer overflow");
-        }
+                b"lo world",
+            ),
+        );
+        wait_sent(&sender, |p| p.get_ack() == seq.wrapping_add(11));
+        let mut buffer = [0; 32];
+        let size = tcp.recv(sock_id, &mut buffer).unwrap();
+        assert_eq!(&buffer[..size], b"hello world");
+    }
 
-        self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
+    #[test]
+    fn retransmission_carries_current_ack_timestamp_and_sack() {
+        let (tcp, sock_id, sender) = timestamp_connection(1000);
+        let (seq, ack) = with_socket(&tcp, sock_id, |socket| {
+            socket.peer_options.sack_permitted = true;
+            socket.rto.set_max(Duration::from_millis(50));
+            (socket.recv_param.next, socket.send_param.next)
+        });
+        tcp.send_push(sock_id, &[1; 100]).unwrap();
+        let original = sender.take().pop().unwrap();
+        assert_eq!(original.get_ack(), seq);
 
-        Ok(())
+        // 再送までの間に、相手から順序どおりのデータと順序の入れ替わったデータが届く
+        let ts = |tsval| [TcpOption::Timestamp { tsval, tsecr: 0 }];
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &ts(1001), b"abc"),
+        );
+        let later = seq.wrapping_add(10);
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, later, ack, tcpflags::ACK, &ts(1002), b"xyz"),
+        );
+
+        let sent = wait_sent(&sender, |p| p.get_seq() == ack && !p.payload().is_empty());
+        let retransmitted = sent.last().unwrap();
+        assert_eq!(retransmitted.payload(), original.payload());
+        assert_eq!(retransmitted.get_ack(), seq.wrapping_add(3));
+        assert!(retransmitted.is_correct_checksum(sock_id.0, sock_id.1));
+
+        let (tsval, tsecr) = retransmitted.get_timestamp().unwrap();
+        assert!(seq::gt(tsval, original.get_timestamp().unwrap().0));
+        assert_eq!(tsecr, 1001);
+        let sack = retransmitted
+            .options()
+            .find(|option| matches!(option, TcpOption::Sack(_)));
+        assert_eq!(
+            sack,
+            Some(TcpOption::Sack(vec![(later, later.wrapping_add(3))]))
+        );
     }
 
-    fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) {
-        dbg!("ack accept", socket.send_param.unacked_seq);
+    #[test]
+    fn data_beyond_a_full_buffer_is_dropped_without_an_event() {
+        let (tcp, sock_id, sender) = recording_connection(BufferSizes::default());
+        let (seq, ack, capacity) = with_socket(&tcp, sock_id, |socket| {
+            (
+                socket.recv_param.next,
+                socket.send_param.next,
+                socket.recv_buffer.capacity(),
+            )
+        });
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, seq, ack, tcpflags::ACK, &[], &vec![1; capacity]),
+        );
+        let full = seq.wrapping_add(capacity as u32);
+        let sent = wait_sent(&sender, |p| p.get_ack() == full);
+        assert_eq!(sent.last().unwrap().get_window_size(), 0);
+        tcp.events.0.lock().unwrap().remove(&sock_id);
 
-        while let Some(item) = socket.retransmission_queue.pop_front() {
-            if socket.send_param.unacked_seq > item.packet.get_seq() {
-                dbg!("successfully acked", item.packet.get_seq());
+        // ゼロウィンドウを無視して送られたデータは保持せず、nextも進めずにACKを返す
+        deliver(
+            &tcp,
+            sock_id,
+            segment(sock_id, full, ack, tcpflags::ACK, &[], &[2; 100]),
+        );
+        let sent = wait_sent(&sender, |_| true);
+        assert_eq!(sent.last().unwrap().get_ack(), full);
+        assert_eq!(sent.last().unwrap().get_window_size(), 0);
+        assert_eq!(
+            with_socket(&tcp, sock_id, |socket| socket.recv_param.next),
+            full
+        );
+        assert!(!tcp.events.0.lock().unwrap().contains_key(&sock_id));
 
-                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
-            } else {
-                socket.retransmission_queue.push_front(item);
-                break;
-            }
-        }
+        let mut buffer = vec![0; capacity * 2];
+        assert_eq!(tcp.recv(sock_id, &mut buffer).unwrap(), capacity);
+        assert!(buffer[..capacity].iter().all(|&b| b == 1));
     }
 
-    fn timer(&self) {
-        dbg!("begin timer thread");
+    #[test]
+    fn leaving_probe_mode_wakes_senders_even_if_window_is_unchanged() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes::default());
+        // timerが自発的にprobeを始めた状態、相手のウィンドウは変わっていない
+        let (seq, ack, window) = with_socket(&tcp, sock_id, |socket| {
+            socket.last_time_window_probe = Some(SystemTime::now());
+            (
+                socket.recv_param.next,
+                socket.send_param.unacked_seq,
+                socket.send_param.window as u16,
+            )
+        });
 
-        loop {
-            let mut table = self.sockets.write().unwrap();
-            for (sock_id, socket) in table.iter_mut() {
-                if let Some(last_time) = socket.last_time_window_probe {
-                    if last_time.elapsed().unwrap() > WINDOW_PROBE_DURATION {
-                        // KeepAliveパケットを送信する
-                        socket
-                            .send_tcp_packet(
-                                socket.send_param.next - 1,
-                                socket.recv_param.next,
-                                tcpflags::ACK,
-                                &[],
-                            )
-                            .expect("failed to send window probe");
-                        socket.last_time_window_probe = Some(SystemTime::now());
-                    }
-                }
+        let generation = tcp.window_generation();
+        let mut update = segment(sock_id, seq, ack, tcpflags::ACK, &[], &[]);
+        update.set_window_size(window);
+        update.set_checksum(update.compute_checksum(sock_id.1, sock_id.0));
+        deliver(&tcp, sock_id, update);
 
-                let mut new_retransmission_queue = VecDeque::new();
-                while let Some(mut item) = socket.retransmission_queue.pop_front() {
-                    if socket.send_param.unacked_seq > item.packet.get_seq() {
-                        // ACKをすでに受信済み
-                        dbg!("successfully acked", item.packet.get_seq());
-                        self.publish_event(*sock_id, TCPEventKind::Acked);
-
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && socket.status == TcpStatus::LastAck
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
+        assert!(with_socket(&tcp, sock_id, |s| s
+            .last_time_window_probe
+            .is_none()));
+        assert_ne!(tcp.window_generation(), generation);
+    }
 
-                        continue;
+    #[test]
+    fn slow_reader_receives_everything_within_the_window() {
+        // ランダムなサイズで送り、受信側はランダムなサイズとタイミングで読む
+        // 送信側が広告ウィンドウを超えて送ったり、受信バッファが壊れたりすると、
+        // パイプ上の検査か受信したバイト列の比較で失敗する
+        let mut rng = StdRng::seed_from_u64(486);
+        for _ in 0..4 {
+            let buffer_sizes = BufferSizes {
+                send: 4380,
+                recv: rng.gen_range(512..4096),
+            };
+            let overrun = Arc::new(AtomicBool::new(false));
+            let flag = overrun.clone();
+            let (tcp_a, sock_a, tcp_b, sock_b) =
+                connected_pair_with(buffer_sizes, move |tcp, packet| {
+                    if !within_advertised_window(tcp, packet) {
+                        flag.store(true, Ordering::Relaxed);
                     }
+                    true
+                });
 
-                    if item.latest_transmission_time.elapsed().unwrap() < item.rto {
-                        new_retransmission_queue.push_back(item);
-                        continue;
-                    }
+            let data: Vec<u8> = (0..rng.gen_range(10_000..40_000))
+                .map(|_| rng.gen())
+                .collect();
+            let chunks: Vec<usize> = (0..64).map(|_| rng.gen_range(1..3000)).collect();
+            let reads: Vec<(usize, u64)> = (0..4096)
+                .map(|_| (rng.gen_range(1..2000), rng.gen_range(0..3)))
+                .collect();
 
-                    if item.transmission_count < MAX_TRANSMISSION {
-                        dbg!("retransmit");
-
-                        socket
-                            .sender
-                            .send_to(item.packet.clone(), IpAddr::V4(socket.remote_addr))
-                            .context("failed to retransmit")
-                            .unwrap();
-                        item.transmission_count += 1;
-                        if item.packet.get_flag() == tcpflags::SYN {
-                            socket.rto.set(Duration::from_secs(3));
-                            item.rto = socket.rto.get();
-                        } else {
-                            item.rto = socket.rto.backoff();
-                        }
-                        item.latest_transmission_time = SystemTime::now();
-                        // 上の処理のように送信時間を見てRTTを超えていなければ再送するかの確認処理を
-                        // 中断するという処理をできるようにするため
-                        // 再送キューの一番後ろに配置するようにする
-                        new_retransmission_queue.push_back(item);
-                    } else {
-                        // 再送の上限回数に達したので再送を諦める
-                        // 本来はメインスレッドへエラーの通知が必要
-                        dbg!("reached MAX_TRANSMISSION");
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && matches!(
-                                socket.status,
-                                TcpStatus::LastAck | TcpStatus::FinWait1 | TcpStatus::FinWait2
-                            )
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
+            let sender = {
+                let data = data.clone();
+                let tcp_a = tcp_a.clone();
+                thread::spawn(move || {
+                    let mut cursor = 0;
+                    for chunk in chunks.iter().cycle() {
+                        if cursor == data.len() {
+                            break;
                         }
+                        let end = cmp::min(cursor + chunk, data.len());
+                        tcp_a.send(sock_a, &data[cursor..end]).unwrap();
+                        cursor = end;
                     }
-                }
+                    tcp_a.send_push(sock_a, &[]).unwrap();
+                })
+            };
 
-                socket.retransmission_queue = new_retransmission_queue;
+            let mut received = Vec::new();
+            for (size, sleep) in reads.iter().cycle() {
+                if received.len() >= data.len() {
+                    break;
+                }
+                let mut buffer = vec![0; *size];
+                let n = tcp_b.recv(sock_b, &mut buffer).unwrap();
+                received.extend_from_slice(&buffer[..n]);
+                thread::sleep(Duration::from_millis(*sleep));
             }
+            sender.join().unwrap();
 
-            drop(table);
-            thread::sleep(Duration::from_millis(100));
+            assert!(!overrun.load(Ordering::Relaxed));
+            assert_eq!(received, data);
         }
     }
 
-    // 指定したソケットIDに対して指定したイベントが来るまで待機
-    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) {
-        let (lock, cvar) = &self.event_condvar;
-        let mut event = lock.lock().unwrap();
-        loop {
-            if let Some(ref e) = *event {
-                if e.sock_id == sock_id && e.kind == kind {
-                    break;
+    #[test]
+    fn small_request_is_acked_immediately() {
+        // RPCの小さいリクエスト/レスポンスはACKを待たせない
+        assert!(peer_may_wait_for_ack(1, MSS));
+        assert!(peer_may_wait_for_ack(100, MSS));
+        assert!(peer_may_wait_for_ack(MSS - 1, MSS));
+    }
+
+    #[test]
+    fn full_sized_segment_does_not_wait_for_ack() {
+        assert!(!peer_may_wait_for_ack(MSS, MSS));
+        assert!(!peer_may_wait_for_ack(536, 536));
+    }
+
+    #[test]
+    fn request_response_with_nagle_does_not_wait_for_delayed_ack() {
+        let (tcp_a, sock_a, tcp_b, sock_b) =
+            connected_pair_with(BufferSizes::default(), |_, _| true);
+
+        // ヘッダと本体を分けて書くリクエストは、2回目の書き込みがNagleで1回目のACKを待つ
+        // 1回目のセグメントへのACKを遅らせると、遅延ACKのタイムアウトまでリクエストが揃わない
+        let responder = thread::spawn(move || {
+            for _ in 0..10 {
+                let mut request = Vec::new();
+                let mut buffer = [0; 100];
+                while request.len() < 100 {
+                    let n = tcp_b.recv(sock_b, &mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..n]);
                 }
+                tcp_b.send(sock_b, &request).unwrap();
             }
+        });
 
-            event = cvar.wait(event).unwrap();
+        for _ in 0..10 {
+            let started = SystemTime::now();
+            tcp_a.send(sock_a, &[1; 10]).unwrap();
+            tcp_a.send(sock_a, &[2; 90]).unwrap();
+            let mut response = Vec::new();
+            let mut buffer = [0; 100];
+            while response.len() < 100 {
+                let n = tcp_a.recv(sock_a, &mut buffer).unwrap();
+                response.extend_from_slice(&buffer[..n]);
+            }
+            let elapsed = started.elapsed().unwrap();
+            assert!(elapsed < DELAYED_ACK_TIMEOUT / 2, "{:?}", elapsed);
         }
+        responder.join().unwrap();
+    }
+
+    // 同じポートを使う接続を2つ持つインスタンスを作り、もう1つのインスタンスから
+    // 最後の接続が削除されるまでそのポートを取れないことを確かめる
+    #[test]
+    fn port_is_released_to_other_instances_with_its_last_socket() {
+        let (first, sock_id, sender) = recording_connection(BufferSizes::default());
+        let second_id = insert_established(
+            &first,
+            established_socket(
+                (LOCAL_ADDR, sock_id.2),
+                (REMOTE_ADDR, unique_port()),
+                REMOTE_ISS_BASE,
+                REMOTE_ISS_BASE,
+                BufferSizes::default(),
+                Box::new(sender),
+            ),
+        );
+        let other = TCP::spawn(BufferSizes::default());
 
-        dbg!(&event);
-        *event = None;
+        let error = other.claim_port(sock_id.2).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TCPError>(),
+            Some(TCPError::AddrInUse(port)) if *port == sock_id.2
+        ));
+        assert!(!other.owns_port(sock_id.2));
+
+        remove_socket(&mut first.sockets.write().unwrap(), sock_id);
+        assert!(first.owns_port(sock_id.2));
+        assert!(other.claim_port(sock_id.2).is_err());
+
+        remove_socket(&mut first.sockets.write().unwrap(), second_id);
+        assert!(!first.owns_port(sock_id.2));
+        other.claim_port(sock_id.2).unwrap();
+        assert!(other.owns_port(sock_id.2));
     }
 
-    // 指定のソケットIDに対してイベント発行
-    fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
-        let (lock, cvar) = &self.event_condvar;
-        let mut e = lock.lock().unwrap();
-        *e = Some(TCPEvent::new(sock_id, kind));
-        cvar.notify_all();
+    // 経路表の引き方はプロセスを起動せずに済むUDPソケットのconnectで行う
+    // ループバック宛てなら、送信元もループバックのアドレスになる
+    #[test]
+    fn source_addr_to_loopback_is_loopback() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert_eq!(get_source_addr_to(localhost).unwrap(), localhost);
+    }
+
+    // 別スレッドで待機中の呼び出しが、shutdownでエラーになることを確かめる
+    fn assert_fails_on_shutdown(
+        tcp: Arc<TCP>,
+        wait: impl FnOnce(&TCP) -> Result<()> + Send + 'static,
+    ) {
+        let waiter = {
+            let tcp = tcp.clone();
+            thread::spawn(move || wait(&tcp))
+        };
+        // 待機に入るまで少し待ってから止める
+        thread::sleep(Duration::from_millis(100));
+        tcp.shutdown().unwrap();
+
+        let error = waiter.join().unwrap().unwrap_err();
+        assert!(
+            matches!(error.downcast_ref::<TCPError>(), Some(TCPError::Shutdown)),
+            "{}",
+            error
+        );
     }
-}
 
-impl TCPEvent {
-    fn new(sock_id: SockID, kind: TCPEventKind) -> Self {
-        Self { sock_id, kind }
+    #[test]
+    fn shutdown_fails_a_pending_connect() {
+        let (tcp, sock_id, _sender) = syn_sent_connection(BufferSizes::default());
+        assert_fails_on_shutdown(tcp, move |tcp| tcp.wait_connected(sock_id, None));
     }
-}
 
-// ipコマンドを使用して自身のipアドレスを取得する。
-// そのため、ipコマンドのバージョンによってはうまく動かない？
-// TODO:std::netに自身のipアドレスを取得する関数などはない？
-fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(format!("ip route get {} | grep src", addr))
-        .output()?;
-    let mut output = str::from_utf8(&output.stdout)?
-        .trim()
-        .split_ascii_whitespace();
+    #[test]
+    fn shutdown_fails_a_pending_recv() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes::default());
+        assert_fails_on_shutdown(tcp, move |tcp| tcp.recv(sock_id, &mut [0; 10]).map(|_| ()));
+    }
+
+    #[test]
+    fn shutdown_fails_a_pending_close() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes::default());
+        assert_fails_on_shutdown(tcp, move |tcp| tcp.close(sock_id));
+    }
+
+    #[test]
+    fn segments_without_a_connection_are_reset_through_one_sender() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes::default());
+        let reset_sender = RecordingSender::default();
+        tcp.reset_senders.lock().unwrap().insert(
+            undetermined_addr(LOCAL_ADDR),
+            Box::new(reset_sender.clone()),
+        );
+        // 接続と同じローカルポート宛てだが、相手のポートが違うので対応する接続が無い
+        let stray = SockID(sock_id.0, sock_id.1, sock_id.2, unique_port());
+
+        // ACKを持つセグメントには、そのACK番号をseqにしたRSTを返す
+        deliver(
+            &tcp,
+            stray,
+            segment(stray, 100, 2000, tcpflags::ACK, &[], b"abc"),
+        );
+        // ACKを持たないセグメントには、SYNとペイロードの分だけ進めたACKを載せる
+        deliver(
+            &tcp,
+            stray,
+            segment(stray, 100, 0, tcpflags::SYN, &[], b"abc"),
+        );
+        // RSTにはRSTを返さない
+        deliver(&tcp, stray, segment(stray, 100, 0, tcpflags::RST, &[], &[]));
 
-    while let Some(s) = output.next() {
-        if s == "src" {
-            break;
+        let sent = reset_sender.take();
+        let summary: Vec<_> = sent
+            .iter()
+            .map(|p| (p.get_flag(), p.get_seq(), p.get_ack()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (tcpflags::RST, 2000, 0),
+                (tcpflags::RST | tcpflags::ACK, 0, 104)
+            ]
+        );
+        for reset in sent {
+            assert_eq!((reset.get_src(), reset.get_dst()), (stray.2, stray.3));
+            assert!(reset.is_correct_checksum(REMOTE_ADDR, LOCAL_ADDR));
         }
+        assert_eq!(tcp.sockets.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reset_event_survives_the_timer_after_the_socket_is_removed() {
+        let (tcp, sock_id, _sender) = recording_connection(BufferSizes::default());
+        // RSTを受けたときと同じく、ソケットを削除してからイベントを発行する
+        remove_socket(&mut tcp.sockets.write().unwrap(), sock_id);
+        tcp.publish_event(sock_id, TCPEventKind::ConnectionReset);
+
+        // 待機中のスレッドが受け取る前にtimerが走っても、リセットのエラーが届く
+        tcp.expire_timers();
+        let error = tcp
+            .wait_event(sock_id, TCPEventKind::DataArrived)
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<TCPError>(),
+            Some(TCPError::ConnectionReset)
+        ));
+
+        // 誰も受け取らなかったイベントは、時間が経てば掃除する
+        tcp.publish_event(sock_id, TCPEventKind::ConnectionReset);
+        tcp.expire_timers();
+        assert!(tcp.events.0.lock().unwrap().contains_key(&sock_id));
+        tcp.events
+            .0
+            .lock()
+            .unwrap()
+            .get_mut(&sock_id)
+            .unwrap()
+            .published_at -= EVENT_RETENTION;
+        tcp.expire_timers();
+        assert!(!tcp.events.0.lock().unwrap().contains_key(&sock_id));
     }
 
-    let ip = output.next().context("failed to get src ip")?;
-    dbg!("source addr", ip);
+    #[test]
+    fn soft_window_holds_back_the_right_edge_while_the_reader_is_slow() {
+        let buffer_sizes = BufferSizes {
+            recv: 40000,
+            ..BufferSizes::default()
+        };
+        let (tcp, sock_id, sender) = recording_connection(buffer_sizes);
+        tcp.set_soft_window(sock_id, true).unwrap();
+        let (first, ack) = with_socket(&tcp, sock_id, |socket| {
+            (socket.recv_param.next, socket.send_param.next)
+        });
+        let edge = first.wrapping_add(40000);
+
+        // アプリが読み出さない間は、右端は最初に広告した位置のまま動かない
+        for i in 0..36 {
+            let seq = first.wrapping_add(i * 1000);
+            deliver(
+                &tcp,
+                sock_id,
+                segment(sock_id, seq, ack, tcpflags::ACK, &[], &[1; 1000]),
+            );
+        }
+        let next = first.wrapping_add(36000);
+        let sent = wait_sent(&sender, |p| p.get_ack() == next);
+        assert!(sent
+            .iter()
+            .all(|p| p.get_ack().wrapping_add(p.get_window_size() as u32) == edge));
+        assert_eq!(sent.last().unwrap().get_window_size(), 4000);
+
+        // 受信済みの範囲を送り直させて、その時点で広告している右端をACKから読み取る
+        let advertised_edge = || {
+            deliver(
+                &tcp,
+                sock_id,
+                segment(sock_id, first, ack, tcpflags::ACK, &[], &[1; 1000]),
+            );
+            let sent = wait_sent(&sender, |p| p.get_ack() == next);
+            let last = sent.last().unwrap();
+            last.get_ack().wrapping_add(last.get_window_size() as u32)
+        };
+        let mut buffer = vec![0; 3000];
+
+        // 使用量34000はhigh-water(30000)を4000超えるので、空き6000から4000を引いた2000しか広告しない
+        // 前回の右端より手前にはしないので、読み出した2000バイト分も右端は進まない
+        assert_eq!(tcp.recv(sock_id, &mut buffer[..2000]).unwrap(), 2000);
+        assert_eq!(advertised_edge(), edge);
 
-    ip.parse().context("failed to parse source ip")
+        // 使用量31000では、空き9000から超過分の1000を引いた8000まで右端を進める
+        assert_eq!(tcp.recv(sock_id, &mut buffer).unwrap(), 3000);
+        assert_eq!(advertised_edge(), next.wrapping_add(8000));
+
+        // high-waterを下回れば、空きの全てを広告する
+        assert_eq!(tcp.recv(sock_id, &mut buffer[..2000]).unwrap(), 2000);
+        assert_eq!(advertised_edge(), next.wrapping_add(11000));
+    }
 }
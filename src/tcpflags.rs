@@ -7,6 +7,17 @@ pub const RST: u8 = 1 << 2;
 pub const SYN: u8 = 1 << 1;
 pub const FIN: u8 = 1;
 
+// RFC793的に矛盾しないフラグの組み合わせか
+// SYNとFIN/RSTの同時指定や、SYN・RST・ACKのどれも立っていないもの(NULLスキャンやFINのみなど)は
+// 正常なTCPが送ることはないので無効とする
+pub fn is_valid_combination(flag: u8) -> bool {
+    if flag & SYN > 0 && flag & (FIN | RST) > 0 {
+        return false;
+    }
+
+    flag & (SYN | RST | ACK) > 0
+}
+
 pub fn flag_to_string(flag: u8) -> String {
     let mut flag_str = String::new();
 
@@ -43,4 +54,42 @@ pub fn flag_to_string(flag: u8) -> String {
     }
 
     flag_str
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_flags_a_normal_tcp_sends() {
+        for flag in [
+            SYN,
+            SYN | ACK,
+            ACK,
+            ACK | PSH,
+            FIN | ACK,
+            FIN | PSH | ACK,
+            RST,
+            RST | ACK,
+            SYN | ECE | CWR,
+        ] {
+            assert!(is_valid_combination(flag), "{}", flag_to_string(flag));
+        }
+    }
+
+    #[test]
+    fn rejects_contradicting_flags() {
+        for flag in [
+            SYN | FIN,
+            SYN | RST,
+            SYN | FIN | ACK,
+            SYN | RST | ACK,
+            0,
+            FIN,
+            PSH,
+            FIN | PSH | URG,
+        ] {
+            assert!(!is_valid_combination(flag), "{}", flag_to_string(flag));
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// テストで使う、ネットワークを使わない送信先とセグメントの組み立て
+use crate::packet::{TCPPacket, TcpOption};
+use crate::socket::{BufferSizes, SegmentSender, SockID, Socket, TcpStatus};
+use pnet::packet::Packet;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+
+// 文書用のアドレス(RFC5737)なので、実際のネットワークのパケットと混ざらない
+pub const LOCAL_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+pub const REMOTE_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+// ポートの持ち主はプロセス全体で管理しているので、並列に走るテスト同士で重ならないポートを配る
+// エフェメラルポートの範囲(40000..60000)とも重ならないようにする
+static NEXT_PORT: AtomicU16 = AtomicU16::new(61000);
+
+pub fn unique_port() -> u16 {
+    NEXT_PORT.fetch_add(1, Ordering::Relaxed)
+}
+
+// 送ったセグメントを記録する送信先
+// failを立てている間は送信エラーを返す
+#[derive(Clone, Default)]
+pub struct RecordingSender {
+    pub sent: Arc<Mutex<Vec<TCPPacket>>>,
+    pub fail: Arc<AtomicBool>,
+}
+
+impl RecordingSender {
+    // 記録したセグメントを取り出す
+    pub fn take(&self) -> Vec<TCPPacket> {
+        mem::take(&mut *self.sent.lock().unwrap())
+    }
+}
+
+impl SegmentSender for RecordingSender {
+    fn send_to(&mut self, packet: &TCPPacket, _addr: IpAddr) -> io::Result<usize> {
+        if self.fail.load(Ordering::Relaxed) {
+            return Err(io::Error::other("injected send error"));
+        }
+        self.sent.lock().unwrap().push(packet.clone());
+        Ok(packet.packet().len())
+    }
+}
+
+// 送ったセグメントをチャネルへ流す送信先
+// 受け取った側で相手のTCPインスタンスへ渡すと、2つのインスタンスをメモリ上でつなげる
+pub struct ChannelSender(pub Mutex<mpsc::Sender<TCPPacket>>);
+
+impl SegmentSender for ChannelSender {
+    fn send_to(&mut self, packet: &TCPPacket, _addr: IpAddr) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap()
+            .send(packet.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "peer is gone"))?;
+        Ok(packet.packet().len())
+    }
+}
+
+// 3ウェイハンドシェイクを終えた状態のソケット
+// 自分のISNをlocal_iss、相手のISNをremote_issとして、どちらもSYNの分だけ進めておく
+// 相手の広告ウィンドウは自分と同じ受信バッファのサイズとする
+pub fn established_socket(
+    local: (IpAddr, u16),
+    remote: (IpAddr, u16),
+    local_iss: u32,
+    remote_iss: u32,
+    buffer_sizes: BufferSizes,
+    sender: Box<dyn SegmentSender>,
+) -> Socket {
+    let mut socket = Socket::with_sender(
+        local.0,
+        remote.0,
+        local.1,
+        remote.1,
+        TcpStatus::Established,
+        buffer_sizes,
+        sender,
+    )
+    .unwrap();
+    socket.send_param.initial_seq = local_iss;
+    socket.send_param.unacked_seq = local_iss.wrapping_add(1);
+    socket.send_param.next = local_iss.wrapping_add(1);
+    socket.send_param.window = buffer_sizes.recv as u32;
+    socket.recv_param.initial_seq = remote_iss;
+    socket.recv_param.next = remote_iss.wrapping_add(1);
+    socket.negotiate_mss();
+    socket.record_tcp_info();
+    socket
+}
+
+// 相手から届くセグメントを、正しいchecksumを付けて組み立てる
+pub fn segment(
+    sock_id: SockID,
+    seq: u32,
+    ack: u32,
+    flag: u8,
+    options: &[TcpOption],
+    payload: &[u8],
+) -> TCPPacket {
+    let SockID(local_addr, remote_addr, local_port, remote_port) = sock_id;
+    let mut packet = TCPPacket::with_options(options, payload.len()).unwrap();
+    packet.set_src(remote_port);
+    packet.set_dst(local_port);
+    packet.set_seq(seq);
+    packet.set_ack(ack);
+    packet.set_flag(flag);
+    packet.set_window_size(u16::MAX);
+    packet.set_payload(payload).unwrap();
+    packet.set_checksum(packet.compute_checksum(remote_addr, local_addr));
+    packet
+}
@@ -0,0 +1,140 @@
+use crate::socket::SockID;
+use std::time::{Duration, SystemTime};
+
+// 再送やprobeの期限をソケット単位で管理するタイミングホイール
+// 期限が来たソケットだけを取り出せるので、timerスレッドが全ソケットを走査しなくて済む
+pub struct TimerWheel {
+    slots: Vec<Vec<(SockID, SystemTime)>>,
+    tick: Duration,
+    origin: SystemTime,
+    // 全てのエントリを処理し終えた最新のtick番号
+    // nowを含むtickには期限前のエントリが残りうるので、次のexpireでもう一度調べる
+    current_tick: u64,
+}
+
+impl TimerWheel {
+    pub fn new(tick: Duration, slot_count: usize) -> Self {
+        Self {
+            slots: vec![Vec::new(); slot_count],
+            tick,
+            origin: SystemTime::now(),
+            current_tick: 0,
+        }
+    }
+
+    pub fn schedule(&mut self, sock_id: SockID, deadline: SystemTime) {
+        // 処理済みのtickに入れると一周するまで取り出されないので、次に処理するtickに入れる
+        let tick = u64::max(self.tick_of(deadline), self.current_tick + 1);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((sock_id, deadline));
+    }
+
+    // nowまでに期限が来たエントリを取り出す
+    pub fn expire(&mut self, now: SystemTime) -> Vec<(SockID, SystemTime)> {
+        let now_tick = self.tick_of(now);
+        let mut expired = Vec::new();
+        if now_tick <= self.current_tick {
+            return expired;
+        }
+
+        // 長時間処理されていなくても、全スロットを一周すれば十分
+        let first_tick = u64::max(
+            self.current_tick + 1,
+            now_tick.saturating_sub(self.slots.len() as u64 - 1),
+        );
+        for tick in first_tick..=now_tick {
+            let slot = (tick % self.slots.len() as u64) as usize;
+            let (due, pending): (Vec<_>, Vec<_>) = self.slots[slot]
+                .drain(..)
+                .partition(|(_, deadline)| *deadline <= now);
+            expired.extend(due);
+            self.slots[slot] = pending;
+        }
+        self.current_tick = now_tick - 1;
+
+        expired
+    }
+
+    fn tick_of(&self, time: SystemTime) -> u64 {
+        let elapsed = time.duration_since(self.origin).unwrap_or_default();
+        (elapsed.as_nanos() / self.tick.as_nanos()) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const TICK: Duration = Duration::from_millis(100);
+
+    fn sock_id(port: u16) -> SockID {
+        let addr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        SockID(addr, addr, port, port)
+    }
+
+    fn ids(expired: Vec<(SockID, SystemTime)>) -> Vec<SockID> {
+        expired.into_iter().map(|(sock_id, _)| sock_id).collect()
+    }
+
+    #[test]
+    fn expires_only_due_entries() {
+        let mut wheel = TimerWheel::new(TICK, 8);
+        let origin = wheel.origin;
+        wheel.schedule(sock_id(1), origin + Duration::from_millis(250));
+        wheel.schedule(sock_id(2), origin + Duration::from_millis(450));
+
+        assert!(wheel.expire(origin + Duration::from_millis(200)).is_empty());
+        assert_eq!(
+            ids(wheel.expire(origin + Duration::from_millis(300))),
+            [sock_id(1)]
+        );
+        assert_eq!(
+            ids(wheel.expire(origin + Duration::from_millis(500))),
+            [sock_id(2)]
+        );
+    }
+
+    #[test]
+    fn past_deadline_fires_on_the_next_tick() {
+        let mut wheel = TimerWheel::new(TICK, 8);
+        let origin = wheel.origin;
+        wheel.expire(origin + Duration::from_millis(500));
+
+        // 処理済みのtickに入れると一周するまで取り出されない
+        wheel.schedule(sock_id(1), origin + Duration::from_millis(100));
+        assert_eq!(
+            ids(wheel.expire(origin + Duration::from_millis(600))),
+            [sock_id(1)]
+        );
+    }
+
+    #[test]
+    fn deadline_beyond_one_rotation_waits_for_its_time() {
+        let mut wheel = TimerWheel::new(TICK, 8);
+        let origin = wheel.origin;
+        // tick 8と同じスロット0に入るが、一周目では期限が来ていない
+        let deadline = origin + Duration::from_millis(850);
+        wheel.schedule(sock_id(1), deadline);
+
+        assert!(wheel.expire(origin + Duration::from_millis(150)).is_empty());
+        assert!(wheel.expire(origin + Duration::from_millis(800)).is_empty());
+        assert_eq!(
+            wheel.expire(origin + Duration::from_millis(900)),
+            [(sock_id(1), deadline)]
+        );
+    }
+
+    #[test]
+    fn long_pause_still_expires_everything() {
+        let mut wheel = TimerWheel::new(TICK, 8);
+        let origin = wheel.origin;
+        wheel.schedule(sock_id(1), origin + Duration::from_millis(150));
+        wheel.schedule(sock_id(2), origin + Duration::from_millis(750));
+
+        let mut expired = ids(wheel.expire(origin + Duration::from_secs(10)));
+        expired.sort_by_key(|sock_id| sock_id.2);
+        assert_eq!(expired, [sock_id(1), sock_id(2)]);
+        assert!(wheel.expire(origin + Duration::from_secs(11)).is_empty());
+    }
+}